@@ -0,0 +1,3 @@
+mod terminal;
+
+pub mod machine;
@@ -1,90 +1,822 @@
-use std::io::Read;
-use std::fs::File;
-use std::path::Path;
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
 
+use super::config::{Config, ExpansionBanks};
+use super::device::{BusDevice, NvRam, Ram};
+use super::flash::Flash;
+use super::fault::Fault;
 use super::io::IO;
+use super::io::pic::PIC;
+use super::io::via::Pins;
+use super::rom;
 
+/// The rest of the machine, as seen by the CPU
+pub trait SystemBus {
+    fn read(&mut self, bank: u8, addr: u16) -> Result<u8, Fault>;
+    fn write(&mut self, bank: u8, addr: u16, value: u8) -> Result<(), Fault>;
+
+    /// Lets the devices run for `cycles`. The CPU calls this after every instruction with the
+    /// number of cycles it took.
+    fn idle(&mut self, cycles: u64);
+
+    /// Number of cycles the bus can be idle before any device needs attention, `u64::MAX` if no
+    /// device has anything scheduled
+    fn next_event(&self) -> u64;
+
+    /// Level of the IRQ line, as driven by the devices on the bus
+    fn irq(&self) -> bool;
+
+    /// Called when the CPU is reset
+    fn reset(&mut self);
+
+    /// Wait states added by the accesses since the last call, in cycles. The CPU adds them to the
+    /// instruction that made the accesses.
+    fn take_wait_states(&mut self) -> u64 {
+        0
+    }
+
+    /// Address of the vector to take an IRQ through instead of the usual one, for interrupt
+    /// controllers that hand the CPU a vector per source
+    fn irq_vector(&mut self, _emulation: bool) -> Option<u16> {
+        None
+    }
+
+    /// Saves the non-volatile memory on the bus to the host
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Where a device appears in the address space: the same range of addresses in every bank of a
+/// range of banks.
+///
+/// The offset passed to the device is `(bank - first bank) * bank_stride + (addr - first addr)`,
+/// masked with `mask`. Every access through the mapping stalls the CPU for `wait_states` cycles.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    banks: RangeInclusive<u8>,
+    addrs: RangeInclusive<u16>,
+    bank_stride: u32,
+    mask: u32,
+    wait_states: u8,
+}
+
+impl Mapping {
+    /// Maps consecutive parts of the device into consecutive banks
+    pub fn new(banks: RangeInclusive<u8>, addrs: RangeInclusive<u16>) -> Self {
+        let bank_stride = *addrs.end() as u32 - *addrs.start() as u32 + 1;
+        Mapping { banks, addrs, bank_stride, mask: 0xffffff, wait_states: 0 }
+    }
+
+    /// Maps the same part of the device into every bank
+    pub fn mirrored(banks: RangeInclusive<u8>, addrs: RangeInclusive<u16>) -> Self {
+        Mapping { bank_stride: 0, ..Mapping::new(banks, addrs) }
+    }
+
+    /// Sets how far apart the parts of the device in consecutive banks are
+    pub fn with_stride(self, bank_stride: u32) -> Self {
+        Mapping { bank_stride, ..self }
+    }
+
+    /// Masks the offset, which mirrors a device that is smaller than its window
+    pub fn with_mask(self, mask: u32) -> Self {
+        Mapping { mask, ..self }
+    }
+
+    /// Makes every access stall the CPU, for devices slower than the SRAM
+    pub fn with_wait_states(self, wait_states: u8) -> Self {
+        Mapping { wait_states, ..self }
+    }
+
+    fn offset(&self, bank: u8, addr: u16) -> u32 {
+        let bank_offset = (bank - self.banks.start()) as u32 * self.bank_stride;
+        (bank_offset + (addr - self.addrs.start()) as u32) & self.mask
+    }
+
+    fn overlaps(&self, other: &Mapping) -> bool {
+        self.banks.start() <= other.banks.end() && other.banks.start() <= self.banks.end() &&
+        self.addrs.start() <= other.addrs.end() && other.addrs.start() <= self.addrs.end()
+    }
+}
+
+/// What happens on an access where no device is mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unmapped {
+    /// Reads see whatever was last on the data bus, writes are ignored
+    OpenBus,
+    /// The access is a `Fault::UnmappedAddress`
+    Fault,
+}
+
+/// The 1 MiB window for larger memory mapped devices
+pub const LARGE_MMIO_BANKS: RangeInclusive<u8> = 0x10..=0x1F;
+
+/// Identifies a device added to a `Bus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+/// Address decoder, sends every access to the device mapped at its address
 pub struct Bus {
-    low_ram:   Box<[u8; 512 * 1024]>,
-    flash_rom: Box<[u8; 512 * 1024]>,
-    high_ram:  Box<[u8;2048 * 1024]>,
-    mmio: IO,
+    devices: Vec<Box<dyn BusDevice>>,
+    /// Input of the interrupt controller each device's IRQ output goes to, `None` if it goes
+    /// straight to the CPU
+    irq_inputs: Vec<Option<u8>>,
+    interrupt_controller: Option<PIC>,
+    mappings: Vec<(Mapping, DeviceId)>,
+    /// Indices into `mappings` of the mappings that cover each bank
+    banks: Vec<Vec<usize>>,
+    unmapped: [Unmapped; 256],
+    /// Last value read or written, this is what open bus reads see
+    data_bus: u8,
+    /// Parts of the large MMIO window that have been claimed
+    large_mmio: Vec<Mapping>,
+    /// Wait states since the last `take_wait_states`
+    wait_states: u64,
+    /// Registers and names of the devices in the IO page
+    io_map: Vec<(RangeInclusive<u8>, String)>,
+    via_pins: Option<Rc<RefCell<Pins>>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Bus {
-    pub fn new(file_path: String, mmio: IO) -> Bus {
-        let mut flash_rom = [0u8; 512 * 1024];
+    /// A bus with nothing on it
+    pub fn new() -> Bus {
+        Bus {
+            devices: Vec::new(),
+            irq_inputs: Vec::new(),
+            interrupt_controller: None,
+            mappings: Vec::new(),
+            banks: vec![Vec::new(); 256],
+            unmapped: [Unmapped::Fault; 256],
+            data_bus: 0,
+            large_mmio: Vec::new(),
+            wait_states: 0,
+            io_map: Vec::new(),
+            via_pins: None,
+        }
+    }
 
-        let path = Path::new(&file_path);
-        let mut file = File::open(&path).unwrap();
+    /// The CATE-16 memory map, see the README
+    pub fn cate16(config: &Config, io: IO) -> Result<Bus, String> {
+        let mut flash = Flash::new(rom::load(&config.roms)?);
+        if config.write_back_flash {
+            let image = match config.roms.as_slice() {
+                [image] if image.is_raw_at_start() => image,
+                _ => return Err("writing back the flash needs a single raw image from bank 0"
+                    .to_string()),
+            };
+            let file = OpenOptions::new().write(true).open(&image.path)
+                .map_err(|e| format!("{}: {}", image.path.display(), e))?;
+            flash = flash.with_write_back(file);
+        }
 
-        file.read_exact(&mut flash_rom).unwrap();
+        let mut bus = Bus::new();
 
-        Bus {
-            low_ram:   Box::new([0u8; 512 * 1024]),
-            flash_rom: Box::new(flash_rom),
-            high_ram:  Box::new([0u8;2048 * 1024]),
-            mmio
-        }
-    }
-
-    fn cycle(&mut self) {
-        self.mmio.cycle();
-    }
-
-    pub fn read(&mut self, bank: u8, addr: u16) -> u8 {
-        self.cycle();
-        match bank {
-            0..=0x0F => {
-                match addr {
-                    0..=0x7EFF => {
-                        self.low_ram[bank as usize * 0x8000 + addr as usize]
-                    }
-                    0x7F00..=0x7FFF => {
-                        self.mmio.read(addr as u8)
-                    }
-                    0x8000..=0xFFFF => {
-                        self.flash_rom[bank as usize * 0x8000 + (addr as usize & 0x7FFF)]
-                    }
-                }
-            }
-            0x10..=0x1f => {
-                panic!("Large MMIO not implemented yet!!!! {:02X}{:04X}", bank, addr);
-            }
-            0x20..=0x3f => {
-                self.high_ram[(bank - 0x20) as usize * 0x8000 + (addr as usize)]
+        let low_ram = bus.add_device(Box::new(Ram::new(512 * 1024)));
+        let waits = config.wait_states;
+        bus.map(low_ram, Mapping::new(0x00..=0x0F, 0x0000..=0x7EFF)
+            .with_stride(0x8000)
+            .with_wait_states(waits.low_ram));
+
+        bus.interrupt_controller = io.interrupt_controller();
+        bus.via_pins = io.via_pins();
+        for device in io.into_devices() {
+            let addrs = 0x7F00 + *device.registers.start() as u16..=
+                0x7F00 + *device.registers.end() as u16;
+            let id = bus.add_device(device.device);
+            if let Some(input) = device.irq {
+                bus.connect_irq(id, input);
             }
-            _ => {
-                panic!("Final 12 MiB not implemented yet!!!! {:02X}{:04X}", bank, addr);
+            bus.map(id, Mapping::mirrored(0x00..=0x0F, addrs).with_wait_states(waits.io));
+            bus.io_map.push((device.registers, device.name));
+        }
+        // the rest of these banks is RAM and flash, so this only covers the free IO registers
+        bus.set_unmapped(0x00..=0x0F, Unmapped::OpenBus);
+
+        let flash = bus.add_device(Box::new(flash));
+        bus.map(flash, Mapping::new(0x00..=0x0F, 0x8000..=0xFFFF).with_wait_states(waits.flash));
+
+        bus.set_unmapped(LARGE_MMIO_BANKS, Unmapped::OpenBus);
+
+        let high_ram = bus.add_device(Box::new(Ram::new(2048 * 1024)));
+        bus.map(high_ram, Mapping::new(0x20..=0x3F, 0x0000..=0xFFFF)
+            .with_wait_states(waits.high_ram));
+
+        match config.expansion {
+            ExpansionBanks::OpenBus => bus.set_unmapped(0x40..=0xFF, Unmapped::OpenBus),
+            ExpansionBanks::Ram(size) => {
+                let banks = size.div_ceil(0x10000);
+                let ram = bus.add_device(Box::new(Ram::new(banks * 0x10000)));
+                bus.map(ram, Mapping::new(0x40..=(0x40 + banks - 1) as u8, 0x0000..=0xFFFF)
+                    .with_wait_states(waits.expansion));
+                bus.set_unmapped(0x40..=0xFF, Unmapped::OpenBus);
             }
+            ExpansionBanks::Trap => {}
         }
+
+        // mapped over the RAM they are part of, which they hide
+        for region in &config.nvram {
+            let (banks, addrs) = (region.banks(), region.addrs());
+            let wait_states = if *banks.end() <= 0x0F && *addrs.end() <= 0x7EFF {
+                waits.low_ram
+            } else if *banks.start() >= 0x20 && *banks.end() <= 0x3F {
+                waits.high_ram
+            } else {
+                return Err(format!("{}: battery backed RAM has to be inside low or high RAM",
+                    region.path.display()));
+            };
+            let mapping = Mapping::new(banks, addrs).with_wait_states(wait_states);
+            let nvram = NvRam::open(region.path.clone(), region.size())?;
+            let nvram = bus.add_device(Box::new(nvram));
+            bus.map(nvram, mapping);
+        }
+
+        Ok(bus)
     }
 
-    pub fn write(&mut self, bank: u8, addr: u16, value: u8) {
-        self.cycle();
-        match bank {
-            0..=0x0F => {
-                match addr {
-                    0..=0x7EFF => {
-                        self.low_ram[bank as usize * 0x8000 + addr as usize] = value;
-                    }
-                    0x7F00..=0x7FFF => {
-                        self.mmio.write(addr as u8, value)
-                    }
-                    0x8000..=0xFFFF => {
-                        panic!("Write to Flash ROM!!!! {:02X}{:04X} = {:02X}", bank, addr, value);
-                    }
-                }
+    /// The devices in the IO page, by register range
+    pub fn io_map(&self) -> impl Iterator<Item = (&RangeInclusive<u8>, &str)> {
+        self.io_map.iter().map(|(registers, name)| (registers, name.as_str()))
+    }
+
+    /// The pins of the VIA in the IO page, for attaching peripherals to them
+    pub fn via_pins(&self) -> Option<Rc<RefCell<Pins>>> {
+        self.via_pins.clone()
+    }
+
+    pub fn add_device(&mut self, device: Box<dyn BusDevice>) -> DeviceId {
+        self.devices.push(device);
+        self.irq_inputs.push(None);
+        DeviceId(self.devices.len() - 1)
+    }
+
+    /// Sets the interrupt controller devices can be connected to
+    pub fn set_interrupt_controller(&mut self, pic: PIC) {
+        self.interrupt_controller = Some(pic);
+    }
+
+    /// Connects the IRQ output of a device to an input of the interrupt controller, instead of
+    /// straight to the CPU
+    pub fn connect_irq(&mut self, device: DeviceId, input: u8) {
+        self.irq_inputs[device.0] = Some(input);
+    }
+
+    /// Hands the IRQ outputs of the devices connected to it to the interrupt controller
+    fn update_interrupt_controller(&self) {
+        if let Some(pic) = &self.interrupt_controller {
+            let levels = self.devices.iter()
+                .zip(&self.irq_inputs)
+                .filter_map(|(device, input)| input.filter(|_| device.irq()))
+                .fold(0, |levels, input| levels | 1 << input);
+            pic.set_inputs(levels);
+        }
+    }
+
+    /// Maps a device into the address space. A device can be mapped more than once, where
+    /// mappings overlap the one added last wins.
+    pub fn map(&mut self, device: DeviceId, mapping: Mapping) {
+        for bank in mapping.banks.clone() {
+            self.banks[bank as usize].push(self.mappings.len());
+        }
+        self.mappings.push((mapping, device));
+    }
+
+    /// Maps a device into the large MMIO window (banks $10-$1F). Unlike `map`, the device has to
+    /// stay inside the window and can't overlap a device that claimed part of it before.
+    pub fn claim_large_mmio(&mut self, device: DeviceId, mapping: Mapping) -> Result<(), String> {
+        if !LARGE_MMIO_BANKS.contains(mapping.banks.start()) ||
+            !LARGE_MMIO_BANKS.contains(mapping.banks.end()) {
+            return Err(format!("banks {:02X}-{:02X} are outside the large MMIO window",
+                mapping.banks.start(), mapping.banks.end()));
+        }
+        if let Some(other) = self.large_mmio.iter().find(|other| other.overlaps(&mapping)) {
+            return Err(format!("{:02X}{:04X}-{:02X}{:04X} is already claimed",
+                other.banks.start(), other.addrs.start(), other.banks.end(), other.addrs.end()));
+        }
+        self.large_mmio.push(mapping.clone());
+        self.map(device, mapping);
+        Ok(())
+    }
+
+    /// Sets what happens on accesses to the banks where no device is mapped
+    pub fn set_unmapped(&mut self, banks: RangeInclusive<u8>, unmapped: Unmapped) {
+        for bank in banks {
+            self.unmapped[bank as usize] = unmapped;
+        }
+    }
+
+    /// Finds the device at an address and the offset into it, and counts the wait states of the
+    /// access
+    fn decode(&mut self, bank: u8, addr: u16) -> Option<(DeviceId, u32)> {
+        let (mapping, device) = self.banks[bank as usize].iter()
+            .rev()
+            .map(|&i| &self.mappings[i])
+            .find(|(mapping, _)| mapping.addrs.contains(&addr))?;
+        self.wait_states += mapping.wait_states as u64;
+        Some((*device, mapping.offset(bank, addr)))
+    }
+}
+
+impl SystemBus for Bus {
+    fn idle(&mut self, mut cycles: u64) {
+        while cycles > 0 {
+            let step = cycles.min(self.next_event());
+            for device in self.devices.iter_mut() {
+                device.tick(step);
             }
-            0x10..=0x1f => {
-                panic!("Large MMIO not implemented yet!!!! {:02X}{:04X} = {:02X}", bank, addr, value);
+            cycles -= step;
+        }
+        self.update_interrupt_controller();
+    }
+
+    fn next_event(&self) -> u64 {
+        self.devices.iter().map(|d| d.next_event()).min().unwrap_or(u64::MAX).max(1)
+    }
+
+    fn irq(&self) -> bool {
+        self.update_interrupt_controller();
+        self.devices.iter()
+            .zip(&self.irq_inputs)
+            .any(|(device, input)| input.is_none() && device.irq())
+    }
+
+    fn irq_vector(&mut self, emulation: bool) -> Option<u16> {
+        self.update_interrupt_controller();
+        self.interrupt_controller.as_ref()?.vector(emulation)
+    }
+
+    fn reset(&mut self) {
+        for device in self.devices.iter_mut() {
+            device.reset();
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        for device in self.devices.iter_mut() {
+            device.flush()?;
+        }
+        Ok(())
+    }
+
+    fn take_wait_states(&mut self) -> u64 {
+        std::mem::take(&mut self.wait_states)
+    }
+
+    fn read(&mut self, bank: u8, addr: u16) -> Result<u8, Fault> {
+        let value = match self.decode(bank, addr) {
+            Some((DeviceId(device), offset)) => self.devices[device].read(offset)?,
+            None => match self.unmapped[bank as usize] {
+                Unmapped::OpenBus => self.data_bus,
+                Unmapped::Fault => return Err(Fault::UnmappedAddress(bank, addr)),
+            },
+        };
+        self.data_bus = value;
+        Ok(value)
+    }
+
+    fn write(&mut self, bank: u8, addr: u16, value: u8) -> Result<(), Fault> {
+        self.data_bus = value;
+        match self.decode(bank, addr) {
+            Some((DeviceId(device), offset)) => {
+                self.devices[device].write(offset, value).map_err(|fault| match fault {
+                    Fault::FlashWrite(_, _, value) => Fault::FlashWrite(bank, addr, value),
+                    fault => fault,
+                })
             }
-            0x20..=0x3f => {
-                self.high_ram[(bank - 0x20) as usize * 0x8000 + (addr as usize)] = value;
+            None => match self.unmapped[bank as usize] {
+                Unmapped::OpenBus => Ok(()),
+                Unmapped::Fault => Err(Fault::UnmappedAddress(bank, addr)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use super::super::config::WaitStates;
+    use super::super::cpu::{RunStatus, W65C816};
+    use super::super::cpu::observer::{Access, AccessKind};
+
+    /// A bus with 64K of RAM in bank 0 and nothing else, holding `program` at $8000 and the reset
+    /// vector pointing at it
+    fn ram_bus(program: &[u8]) -> Bus {
+        let mut bus = Bus::new();
+        let ram = bus.add_device(Box::new(Ram::new(0x10000)));
+        bus.map(ram, Mapping::new(0x00..=0x00, 0x0000..=0xFFFF));
+        for (i, &byte) in program.iter().enumerate() {
+            bus.write(0x00, 0x8000 + i as u16, byte).unwrap();
+        }
+        bus.write(0x00, 0xFFFC, 0x00).unwrap();
+        bus.write(0x00, 0xFFFD, 0x80).unwrap();
+        bus
+    }
+
+    #[test]
+    fn wai_without_events() {
+        // WAI, NOP
+        let mut cpu = W65C816::new(ram_bus(&[0xCB, 0xEA]));
+        assert_eq!(cpu.bus_mut().next_event(), u64::MAX);
+        assert_eq!(cpu.instruction(), Ok(RunStatus::Waiting));
+        let cycles = cpu.cycles();
+        assert_eq!(cpu.instruction(), Ok(RunStatus::Waiting));
+        assert_eq!(cpu.cycles(), cycles);
+
+        // a masked IRQ ends the wait without being taken
+        cpu.set_irq(true);
+        assert_eq!(cpu.instruction(), Ok(RunStatus::Running));
+        assert_eq!(cpu.registers().pc(), 0x8002);
+    }
+
+    #[test]
+    fn access_cycles() {
+        // NOP, LDA $10, STA $11, LDA $10,X, INC $10
+        let program = [0xEA, 0xA5, 0x10, 0x85, 0x11, 0xB5, 0x10, 0xE6, 0x10];
+        let mut cpu = W65C816::new(ram_bus(&program));
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let log = accesses.clone();
+        cpu.add_observer(Box::new(move |access: &Access| {
+            log.borrow_mut().push((access.kind, access.addr, access.cycle));
+        }));
+        let mut starts = Vec::new();
+        for _ in 0..5 {
+            starts.push(cpu.cycles());
+            cpu.instruction().unwrap();
+        }
+        assert_eq!(starts, [0, 2, 5, 8, 12]);
+        assert_eq!(cpu.cycles(), 17);
+
+        // every access is stamped with its own cycle, internal operations leave gaps
+        assert_eq!(*accesses.borrow(), [
+            (AccessKind::OpcodeFetch, 0x008000, 0),
+            (AccessKind::OpcodeFetch, 0x008001, 2),
+            (AccessKind::Read, 0x008002, 3),
+            (AccessKind::Read, 0x000010, 4),
+            (AccessKind::OpcodeFetch, 0x008003, 5),
+            (AccessKind::Read, 0x008004, 6),
+            (AccessKind::Write, 0x000011, 7),
+            // the index is added in cycle 10
+            (AccessKind::OpcodeFetch, 0x008005, 8),
+            (AccessKind::Read, 0x008006, 9),
+            (AccessKind::Read, 0x000010, 11),
+            // the value is modified in cycle 15
+            (AccessKind::OpcodeFetch, 0x008007, 12),
+            (AccessKind::Read, 0x008008, 13),
+            (AccessKind::Read, 0x000010, 14),
+            (AccessKind::Write, 0x000010, 16),
+        ]);
+    }
+
+    #[test]
+    fn masked_mapping() {
+        // 16 registers repeated over a page, in two banks
+        let mut bus = Bus::new();
+        let registers = bus.add_device(Box::new(Ram::new(0x10)));
+        bus.map(registers, Mapping::mirrored(0x00..=0x01, 0x7F00..=0x7FFF).with_mask(0x0F));
+        bus.write(0x00, 0x7F03, 0x42).unwrap();
+        assert_eq!(bus.read(0x00, 0x7F13), Ok(0x42));
+        assert_eq!(bus.read(0x00, 0x7FF3), Ok(0x42));
+        assert_eq!(bus.read(0x01, 0x7F83), Ok(0x42));
+        bus.write(0x01, 0x7FEF, 0x99).unwrap();
+        assert_eq!(bus.read(0x00, 0x7F0F), Ok(0x99));
+        assert_eq!(bus.read(0x00, 0x7F03), Ok(0x42));
+    }
+
+    #[test]
+    fn large_mmio_claim() {
+        let mut bus = Bus::new();
+        bus.set_unmapped(LARGE_MMIO_BANKS, Unmapped::OpenBus);
+        let buffer = bus.add_device(Box::new(Ram::new(0x100)));
+        bus.claim_large_mmio(buffer, Mapping::new(0x12..=0x12, 0x1000..=0x10FF)).unwrap();
+
+        bus.write(0x12, 0x1005, 0x42).unwrap();
+        bus.write(0x12, 0x10FF, 0x43).unwrap();
+        assert_eq!(bus.read(0x12, 0x1005), Ok(0x42));
+        // outside the claim is open bus, which still has the last value read
+        assert_eq!(bus.read(0x12, 0x1100), Ok(0x42));
+        assert_eq!(bus.read(0x1F, 0xFFFF), Ok(0x42));
+        // writes there go nowhere, but are seen on the data bus
+        bus.write(0x13, 0x1005, 0x99).unwrap();
+        assert_eq!(bus.read(0x12, 0x10FF), Ok(0x43));
+        assert_eq!(bus.read(0x10, 0x0000), Ok(0x43));
+    }
+
+    #[test]
+    fn large_mmio_rejects_bad_claims() {
+        let mut bus = Bus::new();
+        let buffer = bus.add_device(Box::new(Ram::new(0x100)));
+        bus.claim_large_mmio(buffer, Mapping::new(0x12..=0x12, 0x1000..=0x10FF)).unwrap();
+
+        let other = bus.add_device(Box::new(Ram::new(0x100)));
+        assert_eq!(bus.claim_large_mmio(other, Mapping::new(0x11..=0x12, 0x10F0..=0x11EF)),
+            Err("121000-1210FF is already claimed".to_string()));
+        assert_eq!(bus.claim_large_mmio(other, Mapping::new(0x1F..=0x20, 0x0000..=0x00FF)),
+            Err("banks 1F-20 are outside the large MMIO window".to_string()));
+        // the rejected claims didn't map anything
+        assert_eq!(bus.read(0x11, 0x10F0), Err(Fault::UnmappedAddress(0x11, 0x10F0)));
+        // right next to the first claim is fine
+        bus.claim_large_mmio(other, Mapping::new(0x12..=0x12, 0x1100..=0x11FF)).unwrap();
+    }
+
+    /// Pulls its IRQ output while the shared level is set
+    struct IrqSource(Rc<Cell<bool>>);
+
+    impl BusDevice for IrqSource {
+        fn read(&mut self, _offset: u32) -> Result<u8, Fault> {
+            Ok(0)
+        }
+
+        fn write(&mut self, _offset: u32, _value: u8) -> Result<(), Fault> {
+            Ok(())
+        }
+
+        fn irq(&self) -> bool {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn interrupt_controller() {
+        let mut bus = Bus::new();
+        let pic = PIC::new();
+        let controller = bus.add_device(Box::new(pic.clone()));
+        bus.map(controller, Mapping::new(0x00..=0x00, 0x0000..=0x0003));
+        bus.set_interrupt_controller(pic);
+
+        let levels: Vec<_> = (0..3).map(|_| Rc::new(Cell::new(false))).collect();
+        for (input, level) in [5, 2].iter().zip(&levels) {
+            let source = bus.add_device(Box::new(IrqSource(level.clone())));
+            bus.connect_irq(source, *input);
+        }
+        // not connected to the controller, straight to the CPU
+        bus.add_device(Box::new(IrqSource(levels[2].clone())));
+
+        levels[0].set(true);
+        levels[1].set(true);
+        assert!(!bus.irq());
+        assert_eq!(bus.irq_vector(false), None);
+
+        // enable input 5 only
+        bus.write(0x00, 0x0001, 0x20).unwrap();
+        assert!(bus.irq());
+        assert_eq!(bus.read(0x00, 0x0000), Ok(0x20));
+        assert_eq!(bus.read(0x00, 0x0002), Ok(0x24));
+        assert_eq!(bus.irq_vector(false), Some(0xFFCA));
+        assert_eq!(bus.irq_vector(true), Some(0xFFDA));
+
+        // input 2 has the higher priority
+        bus.write(0x00, 0x0001, 0xFF).unwrap();
+        assert_eq!(bus.read(0x00, 0x0003), Ok(2));
+        assert_eq!(bus.irq_vector(false), Some(0xFFC4));
+
+        levels[0].set(false);
+        levels[1].set(false);
+        assert!(!bus.irq());
+        assert_eq!(bus.read(0x00, 0x0003), Ok(0xFF));
+        levels[2].set(true);
+        assert!(bus.irq());
+        assert_eq!(bus.irq_vector(false), None);
+    }
+
+    /// A CPU running `program` from `ram_bus`, with the stack at $01FF and every interrupt vector
+    /// pointing at its own RTI, at $90xx for vector $FFxx
+    fn interrupt_cpu(program: &[u8]) -> W65C816 {
+        let mut bus = ram_bus(program);
+        for vector in [0xFFE4, 0xFFE6, 0xFFE8, 0xFFEA, 0xFFEE, 0xFFF4, 0xFFF8, 0xFFFA, 0xFFFE] {
+            bus.write(0x00, vector, vector as u8).unwrap();
+            bus.write(0x00, vector + 1, 0x90).unwrap();
+            bus.write(0x00, 0x9000 | (vector & 0xFF), 0x40).unwrap();
+        }
+        let mut cpu = W65C816::new(bus);
+        let mut registers = cpu.registers();
+        registers.set_s(0x01FF);
+        cpu.set_registers(&registers);
+        cpu
+    }
+
+    /// The `count` bytes the last interrupt pushed, from the top of the stack
+    fn stacked(cpu: &mut W65C816, count: u16) -> Vec<u8> {
+        let s = cpu.registers().s();
+        (1..=count).map(|i| cpu.bus_mut().read(0x00, s.wrapping_add(i)).unwrap()).collect()
+    }
+
+    #[test]
+    fn irq_masking() {
+        // NOP, CLI, NOP, BRK $00, NOP
+        let mut cpu = interrupt_cpu(&[0xEA, 0x58, 0xEA, 0x00, 0x00, 0xEA]);
+
+        // I is set by a reset
+        cpu.set_irq(true);
+        cpu.instruction().unwrap();
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8002);
+
+        // taken right after CLI, with B cleared on the stack and I set in the handler
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90FE);
+        let pushed = stacked(&mut cpu, 3);
+        assert_eq!(pushed[1..], [0x02, 0x80]);
+        assert_eq!(pushed[0] & 0x14, 0x00);
+        assert!(cpu.registers().p().interrupt());
+
+        // returning with the line still asserted takes it again
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8002);
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90FE);
+        cpu.set_irq(false);
+        cpu.instruction().unwrap();
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8003);
+
+        // BRK shares the vector, with B set on the stack, and returns past its signature
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90FE);
+        assert_eq!(stacked(&mut cpu, 3)[0] & 0x10, 0x10);
+        assert_eq!(stacked(&mut cpu, 3)[1..], [0x05, 0x80]);
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8005);
+    }
+
+    #[test]
+    fn interrupt_vectors() {
+        // a NOP after each interrupt in emulation mode, then CLC, XCE and the same in native mode
+        let mut program = vec![0xEA; 3];
+        program.extend([0x18, 0xFB]);
+        program.extend([0xEA; 3]);
+        let mut cpu = interrupt_cpu(&program);
+
+        // NMI and ABORT aren't masked by I, IRQ is the only one that is
+        let modes = [(false, [0x90FA, 0x90F8, 0x90FE]), (true, [0x90EA, 0x90E8, 0x90EE])];
+        for (native, handlers) in modes {
+            if native {
+                cpu.instruction().unwrap();
+                cpu.instruction().unwrap();
+                assert!(!cpu.registers().emulation());
             }
-            _ => {
-                panic!("Final 12 MiB not implemented yet!!!!");
+            let mut p = cpu.registers().p();
+            p.set_interrupt(false);
+            let mut registers = cpu.registers();
+            registers.set_p(p);
+            cpu.set_registers(&registers);
+
+            for (n, handler) in handlers.into_iter().enumerate() {
+                let s = cpu.registers().s();
+                let pc = cpu.registers().pc();
+                match n {
+                    0 => cpu.set_nmi(true),
+                    1 => cpu.abort(),
+                    _ => cpu.set_irq(true),
+                }
+                cpu.instruction().unwrap();
+                assert_eq!(cpu.registers().pc(), handler);
+                assert_eq!(cpu.registers().pbr(), 0x00);
+                // native mode pushes the program bank as well
+                let pushed = if native { 4 } else { 3 };
+                assert_eq!(cpu.registers().s(), s.wrapping_sub(pushed));
+                assert_eq!(stacked(&mut cpu, pushed)[1..3], pc.to_le_bytes());
+                cpu.set_nmi(false);
+                cpu.set_irq(false);
+                cpu.instruction().unwrap();
+                assert_eq!(cpu.registers().pc(), pc);
+                assert_eq!(cpu.registers().s(), s);
+                cpu.instruction().unwrap();
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nmi_edge() {
+        // NOPs
+        let mut cpu = interrupt_cpu(&[0xEA; 8]);
+        cpu.set_nmi(true);
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90FA);
+        cpu.instruction().unwrap();
+
+        // held, so not taken again
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8001);
+
+        // a pulse between instructions is enough
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        cpu.set_nmi(false);
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90FA);
+    }
+
+    #[test]
+    fn abort_returns_to_the_aborted_instruction() {
+        // NOP, LDA #$42, NOP
+        let mut cpu = interrupt_cpu(&[0xEA, 0xA9, 0x42, 0xEA]);
+        cpu.instruction().unwrap();
+        let a = cpu.registers().a();
+
+        // LDA doesn't happen, its address is pushed
+        cpu.abort();
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x90F8);
+        assert_eq!(cpu.registers().a(), a);
+        assert_eq!(stacked(&mut cpu, 3)[1..], [0x01, 0x80]);
+        assert_eq!(stacked(&mut cpu, 3)[0] & 0x10, 0);
+
+        // RTI runs it after all
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().pc(), 0x8001);
+        cpu.instruction().unwrap();
+        assert_eq!(cpu.registers().a() & 0xFF, 0x42);
+        assert_eq!(cpu.registers().pc(), 0x8003);
+    }
+
+    /// The CATE-16 memory map with an empty IO page and erased flash
+    fn cate16_bus(config: Config) -> Result<Bus, String> {
+        Bus::cate16(&Config { roms: Vec::new(), ..config }, IO::empty())
+    }
+
+    #[test]
+    fn cate16_wait_states() {
+        let mut io = IO::empty();
+        io.register("registers", 0x10..=0x1F, Box::new(Ram::new(0x10))).unwrap();
+        let wait_states = WaitStates { low_ram: 0, io: 3, flash: 2, high_ram: 1, expansion: 0 };
+        let config = Config { roms: Vec::new(), wait_states, ..Config::default() };
+        let mut bus = Bus::cate16(&config, io).unwrap();
+
+        // LDA $8000, LDA $7F10, STA $7F11, LDA $1000, LDA $200000, in low RAM
+        let program = [0xAD, 0x00, 0x80, 0xAD, 0x10, 0x7F, 0x8D, 0x11, 0x7F, 0xAD, 0x00, 0x10,
+            0xAF, 0x00, 0x00, 0x20];
+        for (i, &byte) in program.iter().enumerate() {
+            bus.write(0x00, 0x1000 + i as u16, byte).unwrap();
+        }
+        let mut cpu = W65C816::new(bus);
+        let mut registers = cpu.registers();
+        registers.set_pc(0x1000);
+        cpu.set_registers(&registers);
+
+        // every access to flash, an IO register or high RAM stalls the CPU, fetching the program
+        // from low RAM doesn't
+        let mut cycles = Vec::new();
+        for _ in 0..5 {
+            let start = cpu.cycles();
+            cpu.instruction().unwrap();
+            cycles.push(cpu.cycles() - start);
+        }
+        assert_eq!(cycles, [4 + 2, 4 + 3, 4 + 3, 4, 5 + 1]);
+    }
+
+    #[test]
+    fn cate16_nvram() {
+        let dir = std::env::temp_dir().join(format!("cate16-bus-nvram-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (low, high) = (dir.join("low.bin"), dir.join("high.bin"));
+        std::fs::write(&high, [0xAA]).unwrap();
+        let region = |path: &std::path::Path, range: &str| {
+            format!("{}@{}", path.display(), range).parse().unwrap()
+        };
+
+        let mut bus = cate16_bus(Config {
+            nvram: vec![region(&low, "011000-0110FF"), region(&high, "200000-2100FF")],
+            ..Config::default()
+        }).unwrap();
+        assert_eq!(bus.read(0x20, 0x0000), Ok(0xAA));
+        bus.write(0x01, 0x1000, 0x01).unwrap();
+        bus.write(0x01, 0x10FF, 0x02).unwrap();
+        bus.write(0x21, 0x0000, 0x03).unwrap();
+        // the RAM around the regions isn't kept
+        bus.write(0x00, 0x1000, 0x04).unwrap();
+        bus.write(0x01, 0x1100, 0x05).unwrap();
+        bus.write(0x20, 0x0100, 0x06).unwrap();
+        assert_eq!(bus.read(0x01, 0x1000), Ok(0x01));
+        bus.flush().unwrap();
+
+        let low = std::fs::read(&low).unwrap();
+        assert_eq!(low.len(), 0x100);
+        assert_eq!((low[0x00], low[0xFF]), (0x01, 0x02));
+        assert_eq!(low.iter().filter(|&&byte| byte != 0).count(), 2);
+        let high = std::fs::read(&high).unwrap();
+        assert_eq!(high.len(), 0x200);
+        assert_eq!((high[0x000], high[0x100]), (0xAA, 0x03));
+        assert_eq!(high.iter().filter(|&&byte| byte != 0).count(), 2);
+
+        // the flash and the large MMIO window can't be battery backed
+        for range in ["008000-0080FF", "100000-1000FF", "0F7000-207000"] {
+            let path = dir.join("bad.bin");
+            let error = cate16_bus(Config { nvram: vec![region(&path, range)],
+                ..Config::default() }).err().unwrap();
+            assert_eq!(error, format!("{}: battery backed RAM has to be inside low or high RAM",
+                path.display()));
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use super::io::rtc::{days_from_civil, days_in_month};
+use super::rom::RomImage;
+
+/// How to build the machine
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Images to load into the flash, in order
+    pub roms: Vec<RomImage>,
+    /// Write programs and erases of the flash back to the ROM image, which has to be a single raw
+    /// image from bank 0
+    pub write_back_flash: bool,
+    /// What happens in banks $40-$FF
+    pub expansion: ExpansionBanks,
+    pub wait_states: WaitStates,
+    /// Parts of the RAM that keep their contents in host files
+    pub nvram: Vec<NvRamRegion>,
+    /// What time the RTC starts at
+    pub rtc: RtcStart,
+    /// Host file to keep the NVRAM of the RTC in, it's lost on exit without one
+    pub rtc_nvram: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            roms: vec![RomImage::new("../rom/boot_rom")],
+            write_back_flash: false,
+            expansion: ExpansionBanks::Trap,
+            wait_states: WaitStates::default(),
+            nvram: Vec::new(),
+            rtc: RtcStart::Host,
+            rtc_nvram: None,
+        }
+    }
+}
+
+/// Cycles every access to a region stalls the CPU for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStates {
+    pub low_ram: u8,
+    pub io: u8,
+    pub flash: u8,
+    pub high_ram: u8,
+    /// Expansion RAM in banks $40-$FF
+    pub expansion: u8,
+}
+
+impl Default for WaitStates {
+    /// The SRAM keeps up with the CPU, the flash and the IO devices need a wait state
+    fn default() -> Self {
+        WaitStates { low_ram: 0, io: 1, flash: 1, high_ram: 0, expansion: 0 }
+    }
+}
+
+/// Parses a comma separated list of REGION:N, where REGION is one of "low-ram", "io", "flash",
+/// "high-ram" or "expansion". Regions that aren't listed keep their default.
+impl FromStr for WaitStates {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut wait_states = WaitStates::default();
+        for setting in s.split(',') {
+            let (region, n) = setting.split_once(':')
+                .ok_or_else(|| format!("\"{}\" isn't REGION:N", setting))?;
+            let n = n.parse::<u8>().map_err(|_| format!("bad number of wait states \"{}\"", n))?;
+            let field = match region {
+                "low-ram" => &mut wait_states.low_ram,
+                "io" => &mut wait_states.io,
+                "flash" => &mut wait_states.flash,
+                "high-ram" => &mut wait_states.high_ram,
+                "expansion" => &mut wait_states.expansion,
+                _ => return Err(format!("unknown region \"{}\"", region)),
+            };
+            *field = n;
+        }
+        Ok(wait_states)
+    }
+}
+
+/// Nothing is allocated to banks $40-$FF yet, this decides what they do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionBanks {
+    /// Reads see open bus, writes are ignored
+    OpenBus,
+    /// This many bytes of RAM from bank $40 up, rounded up to whole banks. The banks above it are
+    /// open bus.
+    Ram(usize),
+    /// Every access is a fault, naming the address and the instruction that made it
+    Trap,
+}
+
+/// Size of banks $40-$FF
+pub const MAX_EXPANSION_RAM: usize = 0xC0 * 0x10000;
+
+/// Parses "open-bus", "trap" or "ram:SIZE", where SIZE is in bytes or has a K or M suffix
+impl FromStr for ExpansionBanks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open-bus" => Ok(ExpansionBanks::OpenBus),
+            "trap" => Ok(ExpansionBanks::Trap),
+            _ => {
+                let size = s.strip_prefix("ram:")
+                    .ok_or_else(|| format!("unknown expansion bank policy \"{}\"", s))?;
+                let (digits, unit) = match size.chars().last() {
+                    Some('K') | Some('k') => (&size[..size.len() - 1], 1024),
+                    Some('M') | Some('m') => (&size[..size.len() - 1], 1024 * 1024),
+                    _ => (size, 1),
+                };
+                let size = digits.parse::<usize>()
+                    .ok()
+                    .and_then(|n| n.checked_mul(unit))
+                    .ok_or_else(|| format!("bad expansion RAM size \"{}\"", size))?;
+                if size == 0 || size > MAX_EXPANSION_RAM {
+                    return Err(format!("expansion RAM has to be between 1 byte and {} MiB",
+                        MAX_EXPANSION_RAM / (1024 * 1024)));
+                }
+                Ok(ExpansionBanks::Ram(size))
+            }
+        }
+    }
+}
+
+/// Battery backed RAM, the same range of addresses in each of a range of banks, loaded from
+/// `path` at startup and saved back to it on exit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvRamRegion {
+    pub path: PathBuf,
+    /// First address, the bank and address are both the lowest of the region
+    pub start: u32,
+    /// Last address, the bank and address are both the highest of the region
+    pub end: u32,
+}
+
+impl NvRamRegion {
+    pub fn banks(&self) -> RangeInclusive<u8> {
+        (self.start >> 16) as u8..=(self.end >> 16) as u8
+    }
+
+    pub fn addrs(&self) -> RangeInclusive<u16> {
+        self.start as u16..=self.end as u16
+    }
+
+    /// Size of the region in bytes
+    pub fn size(&self) -> usize {
+        self.banks().len() * self.addrs().len()
+    }
+}
+
+/// Parses "PATH@START-END", with 24-bit START and END in hex
+impl FromStr for NvRamRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, range) = s.rsplit_once('@')
+            .ok_or_else(|| format!("\"{}\" isn't PATH@START-END", s))?;
+        let address = |a: &str| u32::from_str_radix(a, 16)
+            .ok()
+            .filter(|&a| a <= 0xFFFFFF)
+            .ok_or_else(|| format!("bad address \"{}\"", a));
+        let (start, end) = range.split_once('-')
+            .ok_or_else(|| format!("\"{}\" isn't START-END", range))?;
+        let (start, end) = (address(start)?, address(end)?);
+        if start >> 16 > end >> 16 || start & 0xFFFF > end & 0xFFFF {
+            return Err(format!("{:06X}-{:06X} is empty", start, end));
+        }
+        Ok(NvRamRegion { path: PathBuf::from(path), start, end })
+    }
+}
+
+/// Where the time of the RTC comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcStart {
+    /// It follows host time
+    Host,
+    /// It starts at this time, in seconds since 1970 UTC, and advances with emulated cycles. Runs
+    /// see the same time at the same cycle, which makes them reproducible.
+    Pinned(i64),
+}
+
+/// Parses "host", or "YYYY-MM-DD" with an optional "THH:MM:SS" for a pinned time (UTC)
+impl FromStr for RtcStart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "host" {
+            return Ok(RtcStart::Host);
+        }
+        let bad = || format!("bad time \"{}\", it has to be YYYY-MM-DD[THH:MM:SS]", s);
+        let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+        let fields = |s: &str, sep: char| s.split(sep)
+            .map(|field| field.parse::<u32>().ok())
+            .collect::<Option<Vec<u32>>>();
+        let (year, month, day) = match fields(date, '-').as_deref() {
+            Some(&[year, month, day]) if (1970..=2099).contains(&year) &&
+                (1..=12).contains(&month) &&
+                (1..=days_in_month(year as i64, month)).contains(&day) => (year, month, day),
+            _ => return Err(bad()),
+        };
+        let (hours, minutes, seconds) = match fields(time, ':').as_deref() {
+            Some(&[hours, minutes, seconds]) if hours < 24 && minutes < 60 && seconds < 60 => {
+                (hours, minutes, seconds)
+            }
+            _ => return Err(bad()),
+        };
+        let days = days_from_civil(year as i64, month, day);
+        Ok(RtcStart::Pinned(days * 86400 + (hours * 3600 + minutes * 60 + seconds) as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_states() {
+        assert_eq!("io:3".parse(), Ok(WaitStates { io: 3, ..WaitStates::default() }));
+        assert_eq!("low-ram:1,io:0,flash:2,high-ram:3,expansion:255".parse(),
+            Ok(WaitStates { low_ram: 1, io: 0, flash: 2, high_ram: 3, expansion: 255 }));
+        // the last setting of a region wins
+        assert_eq!("flash:4,flash:0".parse(), Ok(WaitStates { flash: 0, ..WaitStates::default() }));
+
+        for (bad, error) in [
+            ("", "\"\" isn't REGION:N"),
+            ("io", "\"io\" isn't REGION:N"),
+            ("io:1,", "\"\" isn't REGION:N"),
+            ("io=1", "\"io=1\" isn't REGION:N"),
+            ("io:", "bad number of wait states \"\""),
+            ("io:256", "bad number of wait states \"256\""),
+            ("io:-1", "bad number of wait states \"-1\""),
+            ("io:1:2", "bad number of wait states \"1:2\""),
+            ("rom:1", "unknown region \"rom\""),
+            ("IO:1", "unknown region \"IO\""),
+        ] {
+            assert_eq!(bad.parse::<WaitStates>(), Err(error.to_string()));
+        }
+    }
+
+    #[test]
+    fn rtc_start() {
+        assert_eq!("host".parse(), Ok(RtcStart::Host));
+        assert_eq!("1970-01-01".parse(), Ok(RtcStart::Pinned(0)));
+        assert_eq!("1970-01-02T01:02:03".parse(), Ok(RtcStart::Pinned(86400 + 3723)));
+        assert_eq!("2024-02-29".parse::<RtcStart>().map(|_| ()), Ok(()));
+        assert_eq!("2000-02-29".parse::<RtcStart>().map(|_| ()), Ok(()));
+        for bad in ["2023-02-29", "2100-02-29", "2024-04-31", "2024-13-01", "2024-01-00",
+            "1969-12-31", "2024-01-01T24:00:00", "2024-01-01T12:00", "tomorrow"] {
+            assert_eq!(bad.parse::<RtcStart>(),
+                Err(format!("bad time \"{}\", it has to be YYYY-MM-DD[THH:MM:SS]", bad)));
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use super::W65C816;
+use super::super::bus::SystemBus;
+use super::opcodes::Mode;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AddressingMode {
     Immediate(u16),
     Immediate8(u8),
@@ -26,48 +28,217 @@ pub enum AddressingMode {
     AbsoluteIndirect(u16),
     AbsoluteIndirectLong(u16),
 
-    StackRel(u8)
+    StackRel(u8),
+    StackRelIndirectIdx(u8),
+
+    /// Destination bank, source bank
+    BlockMove(u8, u8),
 }
 
 impl AddressingMode {
-    pub fn loadb(self, cpu: &mut W65C816) -> u8 {
+    /// Decodes the operand bytes of an instruction (in the order they follow the opcode, starting
+    /// at the lowest byte). `wide` is only used for immediates. Returns `None` for instructions
+    /// without an operand.
+    pub fn decode(mode: Mode, wide: bool, operand: u32) -> Option<AddressingMode> {
+        let byte = operand as u8;
+        let word = operand as u16;
+        let bank = (operand >> 16) as u8;
+
+        Some(match mode {
+            Mode::Implied | Mode::Accumulator => return None,
+            Mode::Immediate if wide => AddressingMode::Immediate(word),
+            Mode::Immediate => AddressingMode::Immediate8(byte),
+
+            Mode::Rel => AddressingMode::Rel(byte as i8),
+            Mode::RelLong => AddressingMode::RelLong(word as i16),
+
+            Mode::Direct => AddressingMode::Direct(byte),
+            Mode::DirectIndexedX => AddressingMode::DirectIndexedX(byte),
+            Mode::DirectIndexedY => AddressingMode::DirectIndexedY(byte),
+            Mode::DirectIndexedIndirect => AddressingMode::DirectIndexedIndirect(byte),
+            Mode::DirectIndirect => AddressingMode::DirectIndirect(byte),
+            Mode::DirectIndirectIndexed => AddressingMode::DirectIndirectIndexed(byte),
+            Mode::DirectIndirectLong => AddressingMode::DirectIndirectLong(byte),
+            Mode::DirectIndirectLongIdx => AddressingMode::DirectIndirectLongIdx(byte),
+
+            Mode::Absolute => AddressingMode::Absolute(word),
+            Mode::AbsIndexedX => AddressingMode::AbsIndexedX(word),
+            Mode::AbsIndexedY => AddressingMode::AbsIndexedY(word),
+            Mode::AbsIndexedIndirect => AddressingMode::AbsIndexedIndirect(word),
+            Mode::AbsLongIndexedX => AddressingMode::AbsLongIndexedX(bank, word),
+            Mode::AbsoluteLong => AddressingMode::AbsoluteLong(bank, word),
+            Mode::AbsoluteIndirect => AddressingMode::AbsoluteIndirect(word),
+            Mode::AbsoluteIndirectLong => AddressingMode::AbsoluteIndirectLong(word),
+
+            Mode::StackRel => AddressingMode::StackRel(byte),
+            Mode::StackRelIndirectIdx => AddressingMode::StackRelIndirectIdx(byte),
+
+            Mode::BlockMove => AddressingMode::BlockMove(byte, (operand >> 8) as u8),
+        })
+    }
+
+    pub fn loadb<B: SystemBus>(self, cpu: &mut W65C816<B>) -> u8 {
         match self {
             AddressingMode::Immediate(_) => panic!("loadb on 16-bit immediate"),
             AddressingMode::Immediate8(val) => val,
             _ => {
                 let (bank, addr) = self.address(cpu);
+                self.index_cycles(cpu, bank, addr);
                 cpu.loadb(bank, addr)
             }
         }
     }
-    pub fn loadw(self, cpu: &mut W65C816) -> u16 {
+    pub fn loadw<B: SystemBus>(self, cpu: &mut W65C816<B>) -> u16 {
         match self {
-            AddressingMode::Immediate(val) => val,
+            AddressingMode::Immediate(val) => {
+                cpu.cycles += 1;
+                val
+            }
             AddressingMode::Immediate8(_) => panic!("loadw on 8-bit immediate"),
             _ => {
                 let (bank, addr) = self.address(cpu);
-                cpu.loadw(bank, addr)
+                self.index_cycles(cpu, bank, addr);
+                self.loadw_at(cpu, bank, addr)
             }
         }
     }
 
-    pub fn storeb(self, cpu: &mut W65C816, value: u8) {
-        let (bank, addr) = self.address(cpu);
+    pub fn storeb<B: SystemBus>(self, cpu: &mut W65C816<B>, value: u8) {
+        let (bank, addr) = self.write_address(cpu);
         cpu.storeb(bank, addr, value)
     }
 
-    pub fn storew(self, cpu: &mut W65C816, value: u16) {
-        let (bank, addr) = self.address(cpu);
-        cpu.storew(bank, addr, value)
+    pub fn storew<B: SystemBus>(self, cpu: &mut W65C816<B>, value: u16) {
+        let (bank, addr) = self.write_address(cpu);
+        self.storew_at(cpu, bank, addr, value)
+    }
+
+    /// Resolves the address of a write or read-modify-write operand. Unlike reads, these always
+    /// spend a cycle on adding the index.
+    pub fn write_address<B: SystemBus>(self, cpu: &mut W65C816<B>) -> (u8, u16) {
+        use self::AddressingMode::*;
+
+        let address = self.address(cpu);
+        if let AbsIndexedX(_) | AbsIndexedY(_) | DirectIndirectIndexed(_) = self {
+            cpu.io();
+        }
+        address
+    }
+
+    /// Loads a 16-bit operand from an address previously resolved by `address`.
+    ///
+    /// Direct page and stack relative operands stay in bank 0 (and in the direct page, when it
+    /// wraps), every other operand carries into the next bank.
+    pub fn loadw_at<B: SystemBus>(&self, cpu: &mut W65C816<B>, bank: u8, addr: u16) -> u16 {
+        // the high byte takes one more cycle
+        cpu.cycles += 1;
+        match self.next_in_bank0(cpu, addr) {
+            Some(next) => {
+                let lo = cpu.loadb(0, addr) as u16;
+                let hi = cpu.loadb(0, next) as u16;
+                (hi << 8) | lo
+            }
+            None => cpu.loadw(bank, addr),
+        }
+    }
+
+    /// Stores a 16-bit operand, see `loadw_at`.
+    pub fn storew_at<B: SystemBus>(&self, cpu: &mut W65C816<B>, bank: u8, addr: u16, value: u16) {
+        cpu.cycles += 1;
+        match self.next_in_bank0(cpu, addr) {
+            Some(next) => {
+                cpu.storeb(0, addr, value as u8);
+                cpu.storeb(0, next, (value >> 8) as u8);
+            }
+            None => cpu.storew(bank, addr, value),
+        }
+    }
+
+    /// Stores the result of an 8-bit read-modify-write instruction, after the internal cycle
+    /// between reading and writing
+    pub fn modifyb_at<B: SystemBus>(&self, cpu: &mut W65C816<B>, bank: u8, addr: u16, value: u8) {
+        cpu.io();
+        cpu.storeb(bank, addr, value);
+    }
+
+    /// Stores the result of a 16-bit read-modify-write instruction, see `loadw_at`. These write the
+    /// high byte first.
+    pub fn modifyw_at<B: SystemBus>(&self, cpu: &mut W65C816<B>, bank: u8, addr: u16, value: u16) {
+        cpu.cycles += 1;
+        cpu.io();
+        let (bank, hi_bank, hi_addr) = match self.next_in_bank0(cpu, addr) {
+            Some(next) => (0, 0, next),
+            None if addr == 0xffff => (bank, bank.wrapping_add(1), 0),
+            None => (bank, bank, addr + 1),
+        };
+        cpu.storeb(hi_bank, hi_addr, (value >> 8) as u8);
+        cpu.storeb(bank, addr, value as u8);
+    }
+
+    fn is_direct(&self) -> bool {
+        use self::AddressingMode::*;
+
+        matches!(*self,
+            Direct(_) | DirectIndexedX(_) | DirectIndexedY(_) | DirectIndexedIndirect(_) |
+            DirectIndirect(_) | DirectIndirectIndexed(_) | DirectIndirectLong(_) |
+            DirectIndirectLongIdx(_))
+    }
+
+    /// Indexed reads take an extra cycle when the index is 16 bits wide or when adding it crosses
+    /// a page boundary. Indexed writes and read-modify-write instructions always take it, which is
+    /// already part of their base cycle count.
+    fn index_cycles<B: SystemBus>(&self, cpu: &mut W65C816<B>, bank: u8, addr: u16) {
+        use self::AddressingMode::*;
+
+        let index = match *self {
+            AbsIndexedX(_) => cpu.x,
+            AbsIndexedY(_) | DirectIndirectIndexed(_) => cpu.y,
+            _ => return,
+        };
+        let eff_addr = ((bank as u32) << 16) | addr as u32;
+        let base = eff_addr.wrapping_sub(index as u32) & 0xffffff;
+        if !cpu.p.small_idx() || (base & 0xffff00) != (eff_addr & 0xffff00) {
+            cpu.cycles += 1;
+            cpu.io();
+        }
     }
 
+    fn next_in_bank0<B: SystemBus>(&self, cpu: &W65C816<B>, addr: u16) -> Option<u16> {
+        use self::AddressingMode::*;
 
-    pub fn address(&self, cpu: &mut W65C816) -> (u8, u16) {
+        match *self {
+            Direct(_) | DirectIndexedX(_) | DirectIndexedY(_) => {
+                if direct_page_wraps(cpu) {
+                    Some((addr & 0xff00) | (addr.wrapping_add(1) & 0xff))
+                } else {
+                    Some(addr.wrapping_add(1))
+                }
+            }
+            StackRel(_) => Some(addr.wrapping_add(1)),
+            _ => None,
+        }
+    }
+
+    pub fn address<B: SystemBus>(&self, cpu: &mut W65C816<B>) -> (u8, u16) {
         use self::AddressingMode::*;
 
+        if self.is_direct() && cpu.d & 0xff != 0 {
+            // a direct page that isn't page aligned costs an extra cycle
+            cpu.cycles += 1;
+            cpu.io();
+        }
+
         match *self {
             Direct(offset) => {
-                (0, cpu.d.wrapping_add(offset as u16))
+                (0, direct(cpu, offset as u16))
+            }
+            DirectIndexedX(offset) => {
+                cpu.io();
+                (0, direct(cpu, (offset as u16).wrapping_add(cpu.x)))
+            }
+            DirectIndexedY(offset) => {
+                cpu.io();
+                (0, direct(cpu, (offset as u16).wrapping_add(cpu.y)))
             }
             Absolute(addr) => {
                 (cpu.dbr, addr)
@@ -76,18 +247,13 @@ impl AddressingMode {
                 (bank, addr)
             }
             AbsLongIndexedX(bank, addr) => {
-                let a = ((bank as u32) << 16) | addr as u32;
-                let eff_addr = a + cpu.x as u32;
-                assert!(eff_addr & 0xff000000 == 0, "address overflow");
-                let bank = eff_addr >> 16;
-                let addr = eff_addr as u16;
-                (bank as u8, addr)
+                index_long(bank, addr, cpu.x)
             }
             AbsIndexedX(offset) => {
-                (cpu.dbr, offset.wrapping_add(cpu.x))
+                index_long(cpu.dbr, offset, cpu.x)
             }
             AbsIndexedY(offset) => {
-                (cpu.dbr, offset.wrapping_add(cpu.y))
+                index_long(cpu.dbr, offset, cpu.y)
             }
             Rel(rel) => {
                 (cpu.pbr, (cpu.pc as i16).wrapping_add(rel as i16) as u16)
@@ -95,11 +261,26 @@ impl AddressingMode {
             RelLong(rel_long) => {
                 (cpu.pbr, (cpu.pc as i16).wrapping_add(rel_long) as u16)
             }
+            DirectIndexedIndirect(offset) => {
+                cpu.io();
+                let ptr = direct_pointer(cpu, (offset as u16).wrapping_add(cpu.x));
+                (cpu.dbr, ptr)
+            }
+            DirectIndirect(offset) => {
+                let ptr = direct_pointer(cpu, offset as u16);
+                (cpu.dbr, ptr)
+            }
+            DirectIndirectIndexed(offset) => {
+                let ptr = direct_pointer(cpu, offset as u16);
+                index_long(cpu.dbr, ptr, cpu.y)
+            }
             DirectIndirectLong(offset) => {
+                // Long pointers never wrap around within the direct page, not even in emulation
+                // mode
                 let addr_ptr = cpu.d.wrapping_add(offset as u16);
                 let lo = cpu.loadb(0, addr_ptr) as u16;
-                let hi = cpu.loadb(0, addr_ptr + 1) as u16;
-                let bank = cpu.loadb(0, addr_ptr + 2);
+                let hi = cpu.loadb(0, addr_ptr.wrapping_add(1)) as u16;
+                let bank = cpu.loadb(0, addr_ptr.wrapping_add(2));
                 (bank, (hi << 8) | lo)
             }
             DirectIndirectLongIdx(offset) => {
@@ -107,18 +288,76 @@ impl AddressingMode {
                 // instruction and the Direct Register. The effective address is this 24-bit base
                 // address plus the Y Index Register."
                 let addr_ptr = cpu.d.wrapping_add(offset as u16);
-                let lo = cpu.loadb(0, addr_ptr) as u32;
-                let hi = cpu.loadb(0, addr_ptr + 1) as u32;
-                let bank = cpu.loadb(0, addr_ptr + 2) as u32;
-                let base_address = (bank << 16) | (hi << 8) | lo;
-                let eff_addr = base_address + cpu.y as u32;
-                assert!(eff_addr & 0xff000000 == 0, "address overflow");
-
-                let bank = (eff_addr >> 16) as u8;
-                let addr = eff_addr as u16;
-                (bank, addr)
+                let lo = cpu.loadb(0, addr_ptr) as u16;
+                let hi = cpu.loadb(0, addr_ptr.wrapping_add(1)) as u16;
+                let bank = cpu.loadb(0, addr_ptr.wrapping_add(2));
+                index_long(bank, (hi << 8) | lo, cpu.y)
             }
-            _ => todo!()
+            AbsIndexedIndirect(addr) => {
+                // The pointer is read from the program bank, not the data bank
+                cpu.io();
+                let addr_ptr = addr.wrapping_add(cpu.x);
+                let lo = cpu.loadb(cpu.pbr, addr_ptr) as u16;
+                let hi = cpu.loadb(cpu.pbr, addr_ptr.wrapping_add(1)) as u16;
+                (cpu.pbr, (hi << 8) | lo)
+            }
+            AbsoluteIndirect(addr) => {
+                let lo = cpu.loadb(0, addr) as u16;
+                let hi = cpu.loadb(0, addr.wrapping_add(1)) as u16;
+                (cpu.pbr, (hi << 8) | lo)
+            }
+            AbsoluteIndirectLong(addr) => {
+                let lo = cpu.loadb(0, addr) as u16;
+                let hi = cpu.loadb(0, addr.wrapping_add(1)) as u16;
+                let bank = cpu.loadb(0, addr.wrapping_add(2));
+                (bank, (hi << 8) | lo)
+            }
+            StackRel(offset) => {
+                cpu.io();
+                (0, cpu.s.wrapping_add(offset as u16))
+            }
+            StackRelIndirectIdx(offset) => {
+                cpu.io();
+                let addr_ptr = cpu.s.wrapping_add(offset as u16);
+                let lo = cpu.loadb(0, addr_ptr) as u16;
+                let hi = cpu.loadb(0, addr_ptr.wrapping_add(1)) as u16;
+                cpu.io();
+                index_long(cpu.dbr, (hi << 8) | lo, cpu.y)
+            }
+            Immediate(_) | Immediate8(_) => panic!("address of immediate operand"),
+            BlockMove(..) => panic!("address of block move operand"),
         }
     }
-}
\ No newline at end of file
+}
+
+/// In emulation mode, a page aligned direct page (DL = 0) behaves like the 6502 zero page: the
+/// address wraps around within the page instead of running into the next one.
+fn direct_page_wraps<B: SystemBus>(cpu: &W65C816<B>) -> bool {
+    cpu.emulation && cpu.d & 0xff == 0
+}
+
+/// Resolves an offset (with any index already added) into the direct page. The result is always
+/// in bank 0.
+fn direct<B: SystemBus>(cpu: &W65C816<B>, offset: u16) -> u16 {
+    if direct_page_wraps(cpu) {
+        cpu.d | (offset & 0xff)
+    } else {
+        cpu.d.wrapping_add(offset)
+    }
+}
+
+/// Reads a 16-bit pointer from the direct page, both bytes follow the wrapping rules of `direct`.
+fn direct_pointer<B: SystemBus>(cpu: &mut W65C816<B>, offset: u16) -> u16 {
+    let lo_addr = direct(cpu, offset);
+    let hi_addr = direct(cpu, offset.wrapping_add(1));
+    let lo = cpu.loadb(0, lo_addr) as u16;
+    let hi = cpu.loadb(0, hi_addr) as u16;
+    (hi << 8) | lo
+}
+
+/// Adds an index register to a 24-bit address. Unlike direct page addressing, indexing carries
+/// into the next bank (and wraps around from bank $FF to bank $00).
+fn index_long(bank: u8, addr: u16, index: u16) -> (u8, u16) {
+    let eff_addr = ((((bank as u32) << 16) | addr as u32) + index as u32) & 0xffffff;
+    ((eff_addr >> 16) as u8, eff_addr as u16)
+}
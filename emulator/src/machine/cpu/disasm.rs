@@ -0,0 +1,65 @@
+use super::addressing::AddressingMode;
+use super::opcodes::{Mode, Opcode, OPCODES};
+use super::status::Status;
+
+/// Disassembles the instruction at the start of `bytes`, which was read from address `pc`. The
+/// size of immediate operands is taken from M and X in `p`.
+///
+/// Returns the instruction in WDC syntax and its length, or `None` if `bytes` ends before the
+/// instruction does.
+pub fn disassemble(bytes: &[u8], pc: u16, p: &Status) -> Option<(String, u8)> {
+    let op = &OPCODES[*bytes.first()? as usize];
+    let len = op.len(p);
+    let operand = bytes.get(1..len as usize)?
+        .iter()
+        .rev()
+        .fold(0u32, |acc, &b| (acc << 8) | b as u32);
+    let am = AddressingMode::decode(op.mode, op.size.is_wide(p), operand);
+    Some((format_instruction(op, am, pc.wrapping_add(len as u16)), len))
+}
+
+/// Formats an already decoded instruction. `next_pc` is the address following the instruction,
+/// branches are shown with their target address.
+pub fn format_instruction(op: &Opcode, am: Option<AddressingMode>, next_pc: u16) -> String {
+    match am {
+        Some(am) => format!("{} {}", op.mnemonic, format_operand(am, next_pc)),
+        None if op.mode == Mode::Accumulator => format!("{} A", op.mnemonic),
+        None => op.mnemonic.to_string(),
+    }
+}
+
+fn format_operand(am: AddressingMode, next_pc: u16) -> String {
+    use super::addressing::AddressingMode::*;
+
+    match am {
+        Immediate(val) => format!("#${:04X}", val),
+        Immediate8(val) => format!("#${:02X}", val),
+
+        Rel(rel) => format!("${:04X}", next_pc.wrapping_add(rel as u16)),
+        RelLong(rel) => format!("${:04X}", next_pc.wrapping_add(rel as u16)),
+
+        Direct(offset) => format!("${:02X}", offset),
+        DirectIndexedX(offset) => format!("${:02X},X", offset),
+        DirectIndexedY(offset) => format!("${:02X},Y", offset),
+        DirectIndexedIndirect(offset) => format!("(${:02X},X)", offset),
+        DirectIndirect(offset) => format!("(${:02X})", offset),
+        DirectIndirectIndexed(offset) => format!("(${:02X}),Y", offset),
+        DirectIndirectLong(offset) => format!("[${:02X}]", offset),
+        DirectIndirectLongIdx(offset) => format!("[${:02X}],Y", offset),
+
+        Absolute(addr) => format!("${:04X}", addr),
+        AbsIndexedX(addr) => format!("${:04X},X", addr),
+        AbsIndexedY(addr) => format!("${:04X},Y", addr),
+        AbsIndexedIndirect(addr) => format!("(${:04X},X)", addr),
+        AbsLongIndexedX(bank, addr) => format!("${:02X}{:04X},X", bank, addr),
+        AbsoluteLong(bank, addr) => format!("${:02X}{:04X}", bank, addr),
+        AbsoluteIndirect(addr) => format!("(${:04X})", addr),
+        AbsoluteIndirectLong(addr) => format!("[${:04X}]", addr),
+
+        StackRel(offset) => format!("${:02X},S", offset),
+        StackRelIndirectIdx(offset) => format!("(${:02X},S),Y", offset),
+
+        // the assembler syntax has the banks the other way around than the encoding
+        BlockMove(dst, src) => format!("${:02X},${:02X}", src, dst),
+    }
+}
@@ -0,0 +1,41 @@
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interrupt {
+    Cop, Brk, Abort, Nmi, Irq
+}
+
+const COP_VEC16: u16 = 0xFFE4;
+const BRK_VEC16: u16 = 0xFFE6;
+const ABORT_VEC16: u16 = 0xFFE8;
+const NMI_VEC16: u16 = 0xFFEA;
+const IRQ_VEC16: u16 = 0xFFEE;
+
+const COP_VEC8: u16 = 0xFFF4;
+const ABORT_VEC8: u16 = 0xFFF8;
+const NMI_VEC8: u16 = 0xFFFA;
+pub const RESET_VEC8: u16 = 0xFFFC;
+const IRQ_VEC8: u16 = 0xFFFE;   // shared with BRK
+
+impl Interrupt {
+    /// Address of the vector in bank 0 to jump through
+    pub fn vector(self, emulation: bool) -> u16 {
+        match (self, emulation) {
+            (Interrupt::Cop, false)   => COP_VEC16,
+            (Interrupt::Brk, false)   => BRK_VEC16,
+            (Interrupt::Abort, false) => ABORT_VEC16,
+            (Interrupt::Nmi, false)   => NMI_VEC16,
+            (Interrupt::Irq, false)   => IRQ_VEC16,
+
+            (Interrupt::Cop, true)    => COP_VEC8,
+            (Interrupt::Abort, true)  => ABORT_VEC8,
+            (Interrupt::Nmi, true)    => NMI_VEC8,
+            (Interrupt::Brk, true) |
+            (Interrupt::Irq, true)    => IRQ_VEC8,
+        }
+    }
+
+    /// Hardware interrupts push P with the B flag cleared in emulation mode, so the shared
+    /// IRQ/BRK handler can tell them apart.
+    pub fn is_hardware(self) -> bool {
+        matches!(self, Interrupt::Abort | Interrupt::Nmi | Interrupt::Irq)
+    }
+}
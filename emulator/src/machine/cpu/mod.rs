@@ -1,16 +1,34 @@
 pub mod addressing;
+pub mod disasm;
+pub mod interrupt;
+pub mod observer;
+pub mod opcodes;
+pub mod registers;
+pub mod semihost;
 pub mod status;
+#[cfg(test)]
+mod single_step;
 
 use super::bus::*;
+use super::fault::{CpuFault, Fault};
 use addressing::AddressingMode;
+use disasm::format_instruction;
+use interrupt::{Interrupt, RESET_VEC8};
+use observer::{Access, AccessKind, Observer, ObserverId};
+use opcodes::{Mode, Opcode, OPCODES};
+use registers::Registers;
+use semihost::Semihost;
 use status::Status;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RunStatus {
-    Running, Waiting, Stopped
+    Running, Waiting, Stopped,
+    /// The program asked to exit the emulator through semihosting, with an exit code
+    Exited(u8),
 }
 
-pub struct W65C816 {
+/// The CPU, talking to the rest of the machine through `B`
+pub struct W65C816<B = Bus> {
     a: u16,
     x: u16,
     y: u16,
@@ -26,33 +44,157 @@ pub struct W65C816 {
     p: Status,
 
     run_status: RunStatus,
-
-    bus: Bus,
+    cycles: u64,
+
+    // first fault of the current instruction, and where that instruction started
+    fault: Option<Fault>,
+    instr_pbr: u8,
+    instr_pc: u16,
+    /// Cycle of the next bus cycle of the current instruction, accesses are stamped with it
+    bus_cycle: u64,
+    trace: bool,
+    semihost: Option<Semihost>,
+    observers: Vec<(ObserverId, Box<dyn Observer>)>,
+    next_observer: u32,
+
+    // interrupt inputs, IRQ is level triggered and NMI is edge triggered
+    irq: bool,
+    nmi: bool,
+    nmi_pending: bool,
+    abort_pending: bool,
+
+    bus: B,
 }
 
-const RESET_VEC8: u16 = 0xFFFC;
-
-impl W65C816 {
-    pub fn new(mut bus: Bus) -> Self {
-        let pcl = bus.read(0, RESET_VEC8) as u16;
-        let pch = bus.read(0, RESET_VEC8 + 1) as u16;
-
-        let pc = (pch << 8) | pcl;
-
-        W65C816 {
+impl<B: SystemBus> W65C816<B> {
+    pub fn new(bus: B) -> Self {
+        let mut cpu = W65C816 {
             a: 0,
             x: 0,
             y: 0,
             s: 0x100,
             dbr: 0, pbr: 0,
-            d:   0, pc,
+            d:   0, pc: 0,
             emulation: true,
             p: Status::new(),
             run_status: RunStatus::Running,
+            cycles: 0,
+            fault: None,
+            instr_pbr: 0,
+            instr_pc: 0,
+            bus_cycle: 0,
+            trace: false,
+            semihost: None,
+            observers: Vec::new(),
+            next_observer: 0,
+            irq: false,
+            nmi: false,
+            nmi_pending: false,
+            abort_pending: false,
             bus,
+        };
+        cpu.reset();
+        cpu
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            dbr: self.dbr,
+            pbr: self.pbr,
+            d: self.d,
+            pc: self.pc,
+            emulation: self.emulation,
+            p: self.p,
         }
     }
 
+    /// Replaces the whole CPU state, like a debugger or a save state would.
+    ///
+    /// The registers are fixed up where they don't make sense for the mode: in emulation mode M
+    /// and X are set and the stack is in page 1, and with X set the index registers are 8 bits.
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.a = registers.a;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.s = registers.s;
+        self.dbr = registers.dbr;
+        self.pbr = registers.pbr;
+        self.d = registers.d;
+        self.pc = registers.pc;
+        self.emulation = registers.emulation;
+        if self.emulation {
+            self.s = 0x0100 | (self.s & 0xff);
+        }
+        // set_p takes care of M, X and the index registers
+        self.p.set_small_idx(false);
+        self.set_p(registers.p.0);
+    }
+
+    pub fn run_status(&self) -> RunStatus {
+        self.run_status
+    }
+
+    /// Prints every instruction to stderr before it is executed
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Turns WDM into a call to the host, see `semihost`. Without this, WDM does nothing, like on
+    /// the real chip.
+    pub fn enable_semihosting(&mut self, semihost: Semihost) {
+        self.semihost = Some(semihost);
+    }
+
+    /// Registers an observer that is told about every memory access from now on
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) -> ObserverId {
+        let id = ObserverId(self.next_observer);
+        self.next_observer += 1;
+        self.observers.push((id, observer));
+        id
+    }
+
+    /// Unregisters an observer, handing it back
+    pub fn remove_observer(&mut self, id: ObserverId) -> Option<Box<dyn Observer>> {
+        let index = self.observers.iter().position(|(i, _)| *i == id)?;
+        Some(self.observers.remove(index).1)
+    }
+
+    /// The rest of the machine
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    /// Number of cycles the CPU has run for since it was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Pulls the RESET line, of the CPU and the devices on the bus. This is the only way out of
+    /// STP.
+    pub fn reset(&mut self) {
+        self.bus.reset();
+
+        self.emulation = true;
+        self.p = Status::new();
+        self.s = 0x0100 | (self.s & 0xFF);
+        self.x &= 0xFF;
+        self.y &= 0xFF;
+        self.d = 0;
+        self.dbr = 0;
+        self.pbr = 0;
+
+        self.nmi_pending = false;
+        self.abort_pending = false;
+        self.run_status = RunStatus::Running;
+
+        self.bus_cycle = self.cycles;
+        self.pc = self.load_vector(RESET_VEC8);
+    }
+
     fn compare(&mut self, a: u16, b: u16) {
         self.p.set_zero(a == b);
         self.p.set_carry(a >= b);
@@ -74,6 +216,8 @@ impl W65C816 {
             self.x &= 0xFF;
             self.y &= 0xFF;
         }
+        // Leaving emulation mode leaves M and X set, they just aren't forced anymore
+        self.emulation = value;
     }
 
     fn branch(&mut self, target: (u8, u16)) {
@@ -81,9 +225,103 @@ impl W65C816 {
         self.pc = target.1;
     }
 
+    /// Short branches take an extra cycle in emulation mode when they cross into another page
+    fn relative_branch(&mut self, target: (u8, u16)) {
+        if self.emulation && (target.1 & 0xff00) != (self.pc & 0xff00) {
+            self.cycles += 1;
+            self.io();
+        }
+        self.branch(target);
+    }
+
+    /// Conditional branches take an extra cycle when they are taken
+    fn take_branch(&mut self, target: (u8, u16)) {
+        self.cycles += 1;
+        self.io();
+        self.relative_branch(target);
+    }
+
+    /// Drives the IRQ input. Stays asserted until the device releases it.
+    pub fn set_irq(&mut self, level: bool) {
+        self.irq = level;
+    }
+
+    /// Drives the NMI input, an NMI is taken once for every time it gets asserted.
+    pub fn set_nmi(&mut self, level: bool) {
+        if level && !self.nmi {
+            self.nmi_pending = true;
+        }
+        self.nmi = level;
+    }
+
+    /// Signals ABORT. The next instruction is not executed, its address is pushed instead so the
+    /// ABORT handler can return to it with RTI.
+    pub fn abort(&mut self) {
+        self.abort_pending = true;
+    }
+
+    fn irq_pending(&self) -> bool {
+        (self.irq || self.bus.irq()) && !self.p.interrupt()
+    }
+
+    /// Takes a pending hardware interrupt, if any. Returns whether one was taken.
+    fn poll_interrupts(&mut self) -> bool {
+        // hardware interrupts take as long as BRK
+        if self.abort_pending {
+            self.abort_pending = false;
+            self.interrupt(Interrupt::Abort);
+        } else if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(Interrupt::Nmi);
+        } else if self.irq_pending() {
+            self.interrupt(Interrupt::Irq);
+        } else {
+            return false;
+        }
+        self.cycles += 7;
+        true
+    }
+
+    fn interrupt(&mut self, int: Interrupt) {
+        if int.is_hardware() {
+            // internal cycles where BRK and COP fetch the opcode and the signature byte
+            self.io();
+            self.io();
+        }
+        if !self.emulation {
+            let pbr = self.pbr;
+            self.pushb(pbr);
+            self.cycles += 1;
+        }
+        let pc = self.pc;
+        self.pushw(pc);
+        let p = if self.emulation && int.is_hardware() {
+            self.p.0 & !0x10    // B flag
+        } else {
+            self.p.0
+        };
+        self.pushb(p);
+
+        self.p.set_interrupt(true);
+        self.p.set_decimal(false);
+        self.pbr = 0;
+        let mut vec = int.vector(self.emulation);
+        if int == Interrupt::Irq {
+            if let Some(irq_vec) = self.bus.irq_vector(self.emulation) {
+                vec = irq_vec;
+            }
+        }
+        self.pc = self.load_vector(vec);
+    }
+
     fn set_p(&mut self, new: u8) {
         let small_idx = self.p.small_idx();
         self.p.0 = new;
+        if self.emulation {
+            // M and X always read as 1 in emulation mode (X doubles as the B flag when pushed)
+            self.p.set_small_acc(true);
+            self.p.set_small_idx(true);
+        }
         if !small_idx && self.p.small_idx() {
             self.x &= 0xff;
             self.y &= 0xff;
@@ -96,22 +334,21 @@ impl W65C816 {
         val
     }
 
-    fn fetchw(&mut self) -> u16 {
-        let low = self.fetchb() as u16;
-        let high = self.fetchb() as u16;
-        (high << 8) | low
+    fn fetch_opcode(&mut self) -> u8 {
+        let val = self.read(self.pbr, self.pc, AccessKind::OpcodeFetch);
+        self.pc = self.pc.wrapping_add(1);
+        val
     }
 
     fn pushb(&mut self, value: u8) {
         let s = self.s;
         self.storeb(0, s, value);
         if self.emulation {
-            // stack must stay in 0x01xx
-            assert_eq!(self.s & 0xff00, 0x0100);
-            let s = self.s as u8 - 1;
-            self.s = (self.s & 0xff00) | s as u16;
+            // stack wraps around within 0x01xx
+            let s = (self.s as u8).wrapping_sub(1);
+            self.s = 0x0100 | s as u16;
         } else {
-            self.s -= 1;
+            self.s = self.stack_step(self.s, 0xffff);
         }
     }
 
@@ -124,12 +361,11 @@ impl W65C816 {
 
     fn popb(&mut self) -> u8 {
         if self.emulation {
-            // stack must stay in 0x01xx
-            assert_eq!(self.s & 0xff00, 0x0100);
-            let s = self.s as u8 + 1;
-            self.s = (self.s & 0xff00) | s as u16;
+            // stack wraps around within 0x01xx
+            let s = (self.s as u8).wrapping_add(1);
+            self.s = 0x0100 | s as u16;
         } else {
-            self.s += 1;
+            self.s = self.stack_step(self.s, 1);
         }
 
         let s = self.s;
@@ -142,223 +378,436 @@ impl W65C816 {
         (hi << 8) | lo
     }
 
+    // The instructions that are new to the 65C816 (PEA, PEI, PER, PHD, PLD, PLB, JSL, RTL and
+    // JSR (a,x)) don't wrap the stack pointer around in page 1 in emulation mode. They can run past
+    // either end of it, only afterwards SH is forced back to 0x01 by `clip_stack`.
+
+    fn pushb_unwrapped(&mut self, value: u8) {
+        let s = self.s;
+        self.storeb(0, s, value);
+        self.s = self.stack_step(self.s, 0xffff);
+    }
+
+    fn pushw_unwrapped(&mut self, value: u16) {
+        self.pushb_unwrapped((value >> 8) as u8);
+        self.pushb_unwrapped(value as u8);
+    }
+
+    fn popb_unwrapped(&mut self) -> u8 {
+        self.s = self.stack_step(self.s, 1);
+        let s = self.s;
+        self.loadb(0, s)
+    }
+
+    fn popw_unwrapped(&mut self) -> u16 {
+        let lo = self.popb_unwrapped() as u16;
+        let hi = self.popb_unwrapped() as u16;
+        (hi << 8) | lo
+    }
+
+    /// Moves the stack pointer by one, running off either end of bank 0 is a `StackViolation`
+    fn stack_step(&mut self, s: u16, step: u16) -> u16 {
+        let (new, wrapped) = if step == 1 {
+            s.overflowing_add(1)
+        } else {
+            s.overflowing_sub(1)
+        };
+        if wrapped {
+            self.fault(Fault::StackViolation(s));
+        }
+        new
+    }
+
+    fn clip_stack(&mut self) {
+        if self.emulation {
+            self.s = 0x0100 | (self.s & 0xFF);
+        }
+    }
+
+    /// Remembers the first fault of the current instruction. It is reported once the instruction
+    /// is done, so the CPU is never left halfway an instruction.
+    fn fault(&mut self, fault: Fault) {
+        if self.fault.is_none() {
+            self.fault = Some(fault);
+        }
+    }
+
+    /// Tells the observers about an access. Only called when there are any, so unobserved
+    /// accesses don't pay for building the `Access`.
+    fn observe(&mut self, kind: AccessKind, bank: u8, addr: u16, value: u8) {
+        let access = Access {
+            kind,
+            addr: ((bank as u32) << 16) | addr as u32,
+            value,
+            cycle: self.bus_cycle,
+        };
+        for (_, observer) in self.observers.iter_mut() {
+            observer.access(&access);
+        }
+    }
+
+    fn read(&mut self, bank: u8, addr: u16, kind: AccessKind) -> u8 {
+        let val = match self.bus.read(bank, addr) {
+            Ok(val) => val,
+            Err(fault) => {
+                self.fault(fault);
+                0xFF
+            }
+        };
+        if !self.observers.is_empty() {
+            self.observe(kind, bank, addr, val);
+        }
+        self.next_bus_cycle();
+        val
+    }
+
+    /// Moves on after an access. Wait states stretch the access, which makes the instruction take
+    /// that much longer.
+    fn next_bus_cycle(&mut self) {
+        let wait_states = self.bus.take_wait_states();
+        self.cycles += wait_states;
+        self.bus_cycle += 1 + wait_states;
+    }
+
+    /// An internal operation cycle, the bus is idle. These are part of the cycle count of the
+    /// instruction already, they only move the accesses after them back.
+    fn io(&mut self) {
+        self.bus_cycle += 1;
+    }
+
     fn loadb(&mut self, bank: u8, addr: u16) -> u8 {
-        self.bus.read(bank, addr)
+        self.read(bank, addr, AccessKind::Read)
+    }
+
+    fn load_vector(&mut self, vec: u16) -> u16 {
+        let lo = self.read(0, vec, AccessKind::VectorFetch) as u16;
+        let hi = self.read(0, vec.wrapping_add(1), AccessKind::VectorFetch) as u16;
+        (hi << 8) | lo
     }
-    
-    fn loadw(&mut self, bank: u8, addr: u16) -> u16 {
-        assert!(addr < 0xffff, "loadw on bank boundary");
-        // ^ if this should be supported, make sure to fix the potential overflow below
 
+    fn loadw(&mut self, bank: u8, addr: u16) -> u16 {
         let lo = self.loadb(bank, addr) as u16;
-        let hi = self.loadb(bank, addr + 1) as u16;
+        let hi = if addr == 0xffff {
+            self.loadb(bank.wrapping_add(1), 0) as u16
+        } else {
+            self.loadb(bank, addr + 1) as u16
+        };
         (hi << 8) | lo
     }
 
     fn storeb(&mut self, bank: u8, addr: u16, value: u8) {
-        self.bus.write(bank, addr, value)
+        if let Err(fault) = self.bus.write(bank, addr, value) {
+            self.fault(fault);
+        }
+        if !self.observers.is_empty() {
+            self.observe(AccessKind::Write, bank, addr, value);
+        }
+        self.next_bus_cycle();
     }
     
     fn storew(&mut self, bank: u8, addr: u16, value: u16) {
         self.storeb(bank, addr, value as u8);
         if addr == 0xffff {
-            self.storeb(bank + 1, 0, (value >> 8) as u8);
+            self.storeb(bank.wrapping_add(1), 0, (value >> 8) as u8);
         } else {
             self.storeb(bank, addr + 1, (value >> 8) as u8);
         }
     }
 
-    pub fn instruction(&mut self) -> RunStatus {
-        if self.run_status != RunStatus::Running {
-            return self.run_status;
+    /// Runs a single instruction, or takes an interrupt.
+    ///
+    /// Returns the state the CPU is in afterwards, or the fault caused by the instruction. After a
+    /// fault the CPU is in a consistent state and can keep running.
+    pub fn instruction(&mut self) -> Result<RunStatus, CpuFault> {
+        match self.run_status {
+            RunStatus::Running => {}
+            RunStatus::Stopped | RunStatus::Exited(_) => return Ok(self.run_status),
+            RunStatus::Waiting => {
+                // WAI ends on any interrupt, even a masked IRQ. In that case execution just
+                // continues after the WAI instead of going through the IRQ vector.
+                if !(self.nmi_pending || self.abort_pending || self.irq || self.bus.irq()) {
+                    // with nothing scheduled on the bus only the host can end the wait, so
+                    // time stands still until it does
+                    let cycles = self.bus.next_event();
+                    if cycles != u64::MAX {
+                        self.cycles += cycles;
+                        self.bus.idle(cycles);
+                    }
+                    return Ok(self.run_status);
+                }
+                self.run_status = RunStatus::Running;
+            }
         }
-        let opcode = self.fetchb();
 
-        macro_rules! instr {
-            ( $name:ident ) => {{
-                //println!("{:02X}{:04X} {}", self.pbr, self.pc.wrapping_sub(1), stringify!($name));
-                self.$name();
-            }};
-            ( $name:ident $am:ident ) => {{
-                //println!("{:02X}{:04X} {}", self.pbr, self.pc.wrapping_sub(1), stringify!($name));
-                let am = self.$am();
-                self.$name(am);
-            }};
-        }
-
-        match opcode {
-            // math
-            0xE9 => instr!( sbc immediate_acc ),
-
-            0x0A => instr!( asl_a ),
-            0x4A => instr!( lsr_a ),
+        let start = self.cycles;
+        self.instr_pbr = self.pbr;
+        self.instr_pc = self.pc;
+        self.bus_cycle = start;
+        if !self.poll_interrupts() {
+            let op = &OPCODES[self.fetch_opcode() as usize];
+            self.cycles += op.cycles as u64;
+            self.execute(op);
+        }
+        // let the rest of the machine catch up with the CPU
+        let elapsed = self.cycles - start;
+        self.bus.idle(elapsed);
+
+        match self.fault.take() {
+            None => Ok(self.run_status),
+            Some(fault) => Err(CpuFault {
+                fault,
+                pbr: self.instr_pbr,
+                pc: self.instr_pc,
+                registers: self.registers(),
+            }),
+        }
+    }
 
-            // logic
-            0x29 => instr!( and immediate_acc ),
+    /// Fetches the operand bytes of `op` and decodes them
+    fn operand(&mut self, op: &Opcode) -> Option<AddressingMode> {
+        let wide = op.size.is_wide(&self.p);
+        let mut operand = 0;
+        for i in 0..op.mode.operand_bytes(wide) {
+            operand |= (self.fetchb() as u32) << (8 * i);
+        }
+        AddressingMode::decode(op.mode, wide, operand)
+    }
 
-            0x05 => instr!( ora direct ),
-            0x09 => instr!( ora immediate_acc ),
+    fn execute(&mut self, op: &Opcode) {
+        use self::opcodes::Mnemonic::*;
 
-            // branches
-            0x80 => instr!( bra rel ),
-            0x82 => instr!( bra relative_long ),
+        // JSL and JSR (a,x) push the return address in between fetching their operand bytes
+        let am = match op.mnemonic {
+            Jsl => Some(self.jsl_operand()),
+            Jsr if op.mode == Mode::AbsIndexedIndirect => Some(self.jsr_indexed_indirect_operand()),
+            _ => self.operand(op),
+        };
+        if self.trace {
+            eprintln!("{:02X}{:04X}  {}", self.instr_pbr, self.instr_pc,
+                format_instruction(op, am, self.pc));
+        }
 
-            0xF0 => instr!( beq rel ),
-            0x30 => instr!( bmi rel ),
-            0xD0 => instr!( bne rel ),
+        // instructions without an operand spend the cycle after the opcode fetch on themselves,
+        // pulls, returns and a few others take one more before they touch the bus
+        if op.mode == Mode::Implied || op.mode == Mode::Accumulator {
+            self.io();
+            if matches!(op.mnemonic, Pla | Plx | Ply | Plp | Plb | Pld | Rts | Rtl | Rti | Xba |
+                Wai | Stp) {
+                self.io();
+            }
+        }
 
-            // jumps
-            0x4C => instr!( jmp absolute ),
-            0x5C => instr!( jml absolute_long ),
+        macro_rules! instr {
+            ( $name:ident ) => {
+                self.$name()
+            };
+            ( $name:ident am ) => {
+                self.$name(am.expect("opcode without operand"))
+            };
+        }
 
-            0x20 => instr!( jsr absolute ),
-            0x22 => instr!( jsl absolute_long ),
+        match op.mnemonic {
+            // math
+            Adc => instr!( adc am ),
+            Sbc => instr!( sbc am ),
+
+            // shifts + rotates
+            Asl if op.mode == Mode::Accumulator => instr!( asl_a ),
+            Asl => instr!( asl am ),
+            Lsr if op.mode == Mode::Accumulator => instr!( lsr_a ),
+            Lsr => instr!( lsr am ),
+            Rol if op.mode == Mode::Accumulator => instr!( rol_a ),
+            Rol => instr!( rol am ),
+            Ror if op.mode == Mode::Accumulator => instr!( ror_a ),
+            Ror => instr!( ror am ),
 
-            0x60 => instr!( rts ),
-            0x6B => instr!( rtl ),
+            // logic
+            And => instr!( and am ),
+            Eor => instr!( eor am ),
+            Ora => instr!( ora am ),
+            Bit if op.mode == Mode::Immediate => instr!( bit_immediate am ),
+            Bit => instr!( bit am ),
+            Tsb => instr!( tsb am ),
+            Trb => instr!( trb am ),
 
-            // comparisons
-            0xCD => instr!( cmp absolute ),
-            0xDD => instr!( cmp absolute_indexed_x ),
-            0xC9 => instr!( cmp immediate_acc ),
+            // branches
+            Bra => instr!( bra am ),
+            Brl => instr!( brl am ),
+            Bpl => instr!( bpl am ),
+            Bmi => instr!( bmi am ),
+            Bvc => instr!( bvc am ),
+            Bvs => instr!( bvs am ),
+            Bcc => instr!( bcc am ),
+            Bcs => instr!( bcs am ),
+            Bne => instr!( bne am ),
+            Beq => instr!( beq am ),
 
-            0xEC => instr!( cpx absolute ),
-            0xE0 => instr!( cpx immediate_index ),
+            // jumps
+            Jmp => instr!( jmp am ),
+            Jml => instr!( jml am ),
+            Jsr if op.mode == Mode::AbsIndexedIndirect => instr!( jsr_indexed_indirect am ),
+            Jsr => instr!( jsr am ),
+            Jsl => instr!( jsl am ),
+            Rts => instr!( rts ),
+            Rtl => instr!( rtl ),
+            Rti => instr!( rti ),
+
+            // interrupts
+            Brk => instr!( brk am ),
+            Cop => instr!( cop am ),
 
-            0xC0 => instr!( cpy immediate_index ),
+            // comparisons
+            Cmp => instr!( cmp am ),
+            Cpx => instr!( cpx am ),
+            Cpy => instr!( cpy am ),
 
             // register load + store
-            0xA9 => instr!( lda immediate_acc ),
-
-            0xA5 => instr!( lda direct ),
-            0xA7 => instr!( lda direct_indirect_long ),
-            0xB7 => instr!( lda direct_indirect_long_idx ),
-            0xAD => instr!( lda absolute ),
-            0xBD => instr!( lda absolute_indexed_x ),
-            0xBF => instr!( lda absolute_long_indexed_x ),
-
-            0xA2 => instr!( ldx immediate_index ),
-
-            0xA6 => instr!( ldx direct ),
-            0xAE => instr!( ldx absolute ),
-            
-            0xA0 => instr!( ldy immediate_index ),
-
-            0xA4 => instr!( ldy direct ),
-
-            0x85 => instr!( sta direct ),
-            0x8D => instr!( sta absolute ),
-            0x9D => instr!( sta absolute_indexed_x ),
-            0x99 => instr!( sta absolute_indexed_y ),
-
-            0x97 => instr!( sta direct_indirect_long_idx ),
-
-            0x86 => instr!( stx direct ),
-            0x8E => instr!( stx absolute ),
-
-            0x84 => instr!( sty direct ),
-            0x8C => instr!( sty absolute ),
+            Lda => instr!( lda am ),
+            Ldx => instr!( ldx am ),
+            Ldy => instr!( ldy am ),
+            Sta => instr!( sta am ),
+            Stx => instr!( stx am ),
+            Sty => instr!( sty am ),
+            Stz => instr!( stz am ),
+
+            // block moves
+            Mvp => instr!( mvp am ),
+            Mvn => instr!( mvn am ),
 
             // register transfers
-            0xAA => instr!( tax ),
-            0x8A => instr!( txa ),
-            0x9A => instr!( txs ),
-            0xBB => instr!( tyx ),
-
-            0xEB => instr!( xba ),
+            Tax => instr!( tax ),
+            Tay => instr!( tay ),
+            Txa => instr!( txa ),
+            Tya => instr!( tya ),
+            Txy => instr!( txy ),
+            Tyx => instr!( tyx ),
+            Tsx => instr!( tsx ),
+            Txs => instr!( txs ),
+            Tcd => instr!( tcd ),
+            Tdc => instr!( tdc ),
+            Tcs => instr!( tcs ),
+            Tsc => instr!( tsc ),
+            Xba => instr!( xba ),
 
             // stack manipulation
-            0x48 => instr!( pha ),
-            0xDA => instr!( phx ),
-            0x5A => instr!( phy ),
-
-            0x68 => instr!( pla ),
-            0xFA => instr!( plx ),
-            0x7A => instr!( ply ),
+            Pha => instr!( pha ),
+            Phx => instr!( phx ),
+            Phy => instr!( phy ),
+            Php => instr!( php ),
+            Phb => instr!( phb ),
+            Phd => instr!( phd ),
+            Phk => instr!( phk ),
+            Pla => instr!( pla ),
+            Plx => instr!( plx ),
+            Ply => instr!( ply ),
+            Plp => instr!( plp ),
+            Plb => instr!( plb ),
+            Pld => instr!( pld ),
+            Pea => instr!( pea am ),
+            Pei => instr!( pei am ),
+            Per => instr!( per am ),
 
             // increment/decrement
-            0xE8 => instr!( inx ),
-            0xCA => instr!( dex ),
-
-            0xC8 => instr!( iny ),
-            0x88 => instr!( dey ),
-
-            0xEE => instr!( inc absolute ),
+            Inx => instr!( inx ),
+            Dex => instr!( dex ),
+            Iny => instr!( iny ),
+            Dey => instr!( dey ),
+            Inc if op.mode == Mode::Accumulator => instr!( inc_a ),
+            Inc => instr!( inc am ),
+            Dec if op.mode == Mode::Accumulator => instr!( dec_a ),
+            Dec => instr!( dec am ),
 
             // flag manipulation
-            0x18 => instr!( clc ),
-            0xD8 => instr!( cld ),
-            0x58 => instr!( cli ),
-            0xB8 => instr!( clv ),
-
-            0x38 => instr!( sec ),
-            0xF8 => instr!( sed ),
-            0x78 => instr!( sei ),
-
-            0xFB => instr!( xce ),
-
-            0xC2 => instr!( rep immediate8 ),
-            0xE2 => instr!( sep immediate8 ),
+            Clc => instr!( clc ),
+            Cld => instr!( cld ),
+            Cli => instr!( cli ),
+            Clv => instr!( clv ),
+            Sec => instr!( sec ),
+            Sed => instr!( sed ),
+            Sei => instr!( sei ),
+            Xce => instr!( xce ),
+            Rep => instr!( rep am ),
+            Sep => instr!( sep am ),
 
             // processor control
-            0xDB => instr!( stp ),
-            0xCB => instr!( wai ),
+            Stp => instr!( stp ),
+            Wai => instr!( wai ),
+
             // other
-            _ => { panic!("Opcode {:02X} not implemented yet [at {:02X}{:04X}]", opcode, self.pbr, self.pc.wrapping_sub(1)); }
+            Nop => instr!( nop ),
+            Wdm => instr!( wdm am ),
         }
+    }
 
-        self.run_status
+    fn adc(&mut self, am: AddressingMode) {
+        // Sets N, Z, C and V
+        let v = if self.p.small_acc() {
+            am.loadb(self) as u16
+        } else {
+            am.loadw(self)
+        };
+        self.add_with_carry(v, false);
     }
 
     fn sbc(&mut self, am: AddressingMode) {
-        // Sets N, Z, C and V
-        let c: i16 = if self.p.carry() { 1 } else { 0 };
+        // Sets N, Z, C and V. A - B - !C is computed as A + !B + C
+        let v = if self.p.small_acc() {
+            (am.loadb(self) ^ 0xff) as u16
+        } else {
+            !am.loadw(self)
+        };
+        self.add_with_carry(v, true);
+    }
+
+    /// Adds `v` and the carry to the accumulator, as binary or BCD depending on the D flag.
+    ///
+    /// SBC passes in the one's complement of its operand, in which case the decimal adjustment of
+    /// each digit has to go the other way (subtract 6 when the digit did *not* produce a carry).
+    fn add_with_carry(&mut self, v: u16, subtract: bool) {
+        let (a, digits, sign) = if self.p.small_acc() {
+            (self.a as i32 & 0xff, 2, 0x80)
+        } else {
+            (self.a as i32, 4, 0x8000)
+        };
+        let v = v as i32;
+        let c: i32 = if self.p.carry() { 1 } else { 0 };
+
+        let overflow = |res: i32| (a ^ v) & sign == 0 && (a ^ res) & sign != 0;
+
+        let res = if self.p.decimal() {
+            let mut res = 0;
+            let mut carry = c;
+            for digit in 0..digits {
+                let shift = digit * 4;
+                let max = (0x10 << shift) - 1;
+                res = (a & (0xf << shift)) + (v & (0xf << shift)) + (carry << shift) + (res & (max >> 4));
+                if digit == digits - 1 {
+                    // V is taken from the result before the top digit is adjusted
+                    self.p.set_overflow(overflow(res));
+                }
+                if subtract {
+                    if res <= max { res -= 6 << shift; }
+                } else if res > max - (6 << shift) {
+                    res += 6 << shift;
+                }
+                carry = if res > max { 1 } else { 0 };
+            }
+            self.p.set_carry(carry != 0);
+            res
+        } else {
+            let res = a + v + c;
+            self.p.set_overflow(overflow(res));
+            self.p.set_carry(res > (sign << 1) - 1);
+            res
+        };
 
         if self.p.small_acc() {
-            let a = self.a as i16 & 0xff;
-            let v = am.loadb(self) as i16 ^ 0xff;
-            let mut res: i16 = if self.p.decimal() {
-                let mut low: i16 = (a & 0x0f) + (v & 0x0f) + c;
-                if low < 0x10 { low -= 6; }
-
-                (a & 0xf0) + (v & 0xf0) + (low & 0x0f) + if low > 0x0f { 0x10 } else { 0x00 }
-            } else {
-                a + v + c
-            };
-            self.p.set_overflow((a & 0x80) == (v & 0x80) && (a & 0x80) != (res & 0x80));
-            if self.p.decimal() && res < 0x100 { res -= 0x60; }
-            self.p.set_carry(res > 255);
-
             self.a = (self.a & 0xff00) | self.p.set_nz_8(res as u8) as u16;
         } else {
-            let a = self.a as i32;
-            let v = am.loadw(self) as i32 ^ 0xffff;
-            let mut res: i32 = if self.p.decimal() {
-                let mut res0 = (a & 0x000f) + (v & 0x000f) + c as i32;
-                if res0 < 0x0010 { res0 -= 0x0006; }
-
-                let mut res1 = (a & 0x00f0) + (v & 0x00f0) + (res0 & 0x000f) +
-                    if res0 > 0x000f { 0x10 } else { 0x00 };
-                if res1 < 0x0100 { res1 -= 0x0060; }
-
-                let mut res2 = (a & 0x0f00) + (v & 0x0f00) + (res1 & 0x00ff) +
-                    if res1 > 0x00ff { 0x100 } else { 0x000 };
-                if res2 < 0x1000 { res2 -= 0x0600; }
-
-                (a as i32 & 0xf000) + (v as i32 & 0xf000) + (res2 as i32 & 0x0fff) +
-                    if res2 > 0x0fff { 0x1000 } else { 0x0000 }
-            } else {
-                self.a as i32 + v as i32 + c as i32
-            };
-            self.p.set_overflow((self.a ^ res as u16) & 0x8000 != 0 && (self.a ^ v as u16) & 0x8000 == 0);
-            if self.p.decimal() && res < 0x10000 { res -= 0x6000; }
-            self.p.set_carry(res > 65535);
-
             self.a = self.p.set_nz(res as u16);
         }
     }
 
-
     fn asl_a(&mut self) {
         // Sets N, Z and C. The rightmost bit is filled with 0.
         if self.p.small_acc() {
@@ -383,6 +832,96 @@ impl W65C816 {
         }
     }
 
+    fn rol_a(&mut self) {
+        // Sets N, Z and C. The rightmost bit is filled with the old carry.
+        let c = if self.p.carry() { 1 } else { 0 };
+        if self.p.small_acc() {
+            let a = self.a as u8;
+            self.p.set_carry(a & 0x80 != 0);
+            self.a = (self.a & 0xff00) | self.p.set_nz_8((a << 1) | c) as u16;
+        } else {
+            self.p.set_carry(self.a & 0x8000 != 0);
+            self.a = self.p.set_nz((self.a << 1) | c as u16);
+        }
+    }
+
+    fn ror_a(&mut self) {
+        // Sets N, Z and C. The leftmost bit is filled with the old carry.
+        let c = self.p.carry();
+        if self.p.small_acc() {
+            let a = self.a as u8;
+            self.p.set_carry(a & 0x01 != 0);
+            let res = (a >> 1) | if c { 0x80 } else { 0x00 };
+            self.a = (self.a & 0xff00) | self.p.set_nz_8(res) as u16;
+        } else {
+            self.p.set_carry(self.a & 0x0001 != 0);
+            let res = (self.a >> 1) | if c { 0x8000 } else { 0x0000 };
+            self.a = self.p.set_nz(res);
+        }
+    }
+
+    fn asl(&mut self, am: AddressingMode) {
+        let (bank, addr) = am.write_address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_carry(val & 0x80 != 0);
+            let res = self.p.set_nz_8(val << 1);
+            am.modifyb_at(self, bank, addr, res);
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_carry(val & 0x8000 != 0);
+            let res = self.p.set_nz(val << 1);
+            am.modifyw_at(self, bank, addr, res);
+        }
+    }
+
+    fn lsr(&mut self, am: AddressingMode) {
+        let (bank, addr) = am.write_address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_carry(val & 0x01 != 0);
+            let res = self.p.set_nz_8(val >> 1);
+            am.modifyb_at(self, bank, addr, res);
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_carry(val & 0x0001 != 0);
+            let res = self.p.set_nz(val >> 1);
+            am.modifyw_at(self, bank, addr, res);
+        }
+    }
+
+    fn rol(&mut self, am: AddressingMode) {
+        let (bank, addr) = am.write_address(self);
+        let c = if self.p.carry() { 1 } else { 0 };
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_carry(val & 0x80 != 0);
+            let res = self.p.set_nz_8((val << 1) | c);
+            am.modifyb_at(self, bank, addr, res);
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_carry(val & 0x8000 != 0);
+            let res = self.p.set_nz((val << 1) | c as u16);
+            am.modifyw_at(self, bank, addr, res);
+        }
+    }
+
+    fn ror(&mut self, am: AddressingMode) {
+        let (bank, addr) = am.write_address(self);
+        let c = self.p.carry();
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_carry(val & 0x01 != 0);
+            let res = self.p.set_nz_8((val >> 1) | if c { 0x80 } else { 0x00 });
+            am.modifyb_at(self, bank, addr, res);
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_carry(val & 0x0001 != 0);
+            let res = self.p.set_nz((val >> 1) | if c { 0x8000 } else { 0x0000 });
+            am.modifyw_at(self, bank, addr, res);
+        }
+    }
+
     fn and(&mut self, am: AddressingMode) {
         if self.p.small_acc() {
             let val = am.loadb(self);
@@ -409,29 +948,138 @@ impl W65C816 {
         }
     }
 
+    fn eor(&mut self, am: AddressingMode) {
+        if self.p.small_acc() {
+            let val = am.loadb(self);
+            let res = self.a as u8 ^ val;
+            self.p.set_nz_8(res);
+            self.a = (self.a & 0xFF00) | res as u16;
+        } else {
+            let val = am.loadw(self);
+            let res = self.a ^ val;
+            self.a = self.p.set_nz(res);
+        }
+    }
+
+    fn bit(&mut self, am: AddressingMode) {
+        // Sets Z from A & value, N and V are copied from the two topmost bits of the value
+        if self.p.small_acc() {
+            let val = am.loadb(self);
+            self.p.set_zero(self.a as u8 & val == 0);
+            self.p.set_negative(val & 0x80 != 0);
+            self.p.set_overflow(val & 0x40 != 0);
+        } else {
+            let val = am.loadw(self);
+            self.p.set_zero(self.a & val == 0);
+            self.p.set_negative(val & 0x8000 != 0);
+            self.p.set_overflow(val & 0x4000 != 0);
+        }
+    }
+
+    fn bit_immediate(&mut self, am: AddressingMode) {
+        // Immediate BIT only changes Z
+        if self.p.small_acc() {
+            let val = am.loadb(self);
+            self.p.set_zero(self.a as u8 & val == 0);
+        } else {
+            let val = am.loadw(self);
+            self.p.set_zero(self.a & val == 0);
+        }
+    }
+
+    fn tsb(&mut self, am: AddressingMode) {
+        // Sets Z from A & value (before the bits are set)
+        let (bank, addr) = am.write_address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_zero(self.a as u8 & val == 0);
+            am.modifyb_at(self, bank, addr, val | self.a as u8);
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_zero(self.a & val == 0);
+            am.modifyw_at(self, bank, addr, val | self.a);
+        }
+    }
+
+    fn trb(&mut self, am: AddressingMode) {
+        // Sets Z from A & value (before the bits are cleared)
+        let (bank, addr) = am.write_address(self);
+        if self.p.small_acc() {
+            let val = self.loadb(bank, addr);
+            self.p.set_zero(self.a as u8 & val == 0);
+            am.modifyb_at(self, bank, addr, val & !(self.a as u8));
+        } else {
+            let val = am.loadw_at(self, bank, addr);
+            self.p.set_zero(self.a & val == 0);
+            am.modifyw_at(self, bank, addr, val & !self.a);
+        }
+    }
+
     fn bra(&mut self, am: AddressingMode) {
         let a = am.address(self);
+        self.io();
+        self.relative_branch(a);
+    }
+
+    fn brl(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        self.io();
         self.branch(a);
     }
 
+    fn bpl(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        if !self.p.negative() {
+            self.take_branch(a);
+        }
+    }
+
+    fn bvc(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        if !self.p.overflow() {
+            self.take_branch(a);
+        }
+    }
+
+    fn bvs(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        if self.p.overflow() {
+            self.take_branch(a);
+        }
+    }
+
+    fn bcc(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        if !self.p.carry() {
+            self.take_branch(a);
+        }
+    }
+
+    fn bcs(&mut self, am: AddressingMode) {
+        let a = am.address(self);
+        if self.p.carry() {
+            self.take_branch(a);
+        }
+    }
+
     fn beq(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.zero() {
-            self.branch(a);
+            self.take_branch(a);
         }
     }
 
     fn bmi(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if self.p.negative() {
-            self.branch(a);
+            self.take_branch(a);
         }
     }
 
     fn bne(&mut self, am: AddressingMode) {
         let a = am.address(self);
         if !self.p.zero() {
-            self.branch(a);
+            self.take_branch(a);
         }
     }
 
@@ -446,39 +1094,84 @@ impl W65C816 {
     }
 
     fn jsr(&mut self, am: AddressingMode) {
-        let pc = self.pc - 1;
+        self.io();
+        let pc = self.pc.wrapping_sub(1);
         self.pushb((pc >> 8) as u8);
         self.pushb(pc as u8);
 
         self.pc = am.address(self).1;
     }
 
-    fn jsl(&mut self, am: AddressingMode) {
+    /// Fetches the operand of JSR (a,x), pushing the return address after the low byte
+    fn jsr_indexed_indirect_operand(&mut self) -> AddressingMode {
+        let lo = self.fetchb() as u16;
+        // the return address is that of the high byte, the last one of the instruction
+        let pc = self.pc;
+        self.pushw_unwrapped(pc);
+        let hi = self.fetchb() as u16;
+        AddressingMode::AbsIndexedIndirect((hi << 8) | lo)
+    }
+
+    fn jsr_indexed_indirect(&mut self, am: AddressingMode) {
+        self.pc = am.address(self).1;
+        self.clip_stack();
+    }
+
+    /// Fetches the operand of JSL, pushing PBR before the bank byte and the PC after it
+    fn jsl_operand(&mut self) -> AddressingMode {
+        let lo = self.fetchb() as u16;
+        let hi = self.fetchb() as u16;
         let pbr = self.pbr;
-        self.pushb(pbr);
-        let pc = self.pc - 1;
-        self.pushb((pc >> 8) as u8);
-        self.pushb(pc as u8);
+        self.pushb_unwrapped(pbr);
+        self.io();
+        let bank = self.fetchb();
+        let pc = self.pc.wrapping_sub(1);
+        self.pushw_unwrapped(pc);
+        AddressingMode::AbsoluteLong(bank, (hi << 8) | lo)
+    }
 
+    fn jsl(&mut self, am: AddressingMode) {
         let (pbr, pc) = am.address(self);
         self.pbr = pbr;
         self.pc = pc;
+        self.clip_stack();
     }
 
     fn rts(&mut self) {
         let pcl = self.popb() as u16;
         let pch = self.popb() as u16;
         let pc = (pch << 8) | pcl;
-        self.pc = pc + 1;   // +1 since the last byte of the JSR was saved
+        self.pc = pc.wrapping_add(1);   // +1 since the last byte of the JSR was saved
+        self.io();
     }
 
     fn rtl(&mut self) {
+        let pc = self.popw_unwrapped();
+        let pbr = self.popb_unwrapped();
+        self.pbr = pbr;
+        self.pc = pc.wrapping_add(1);   // +1 since the last byte of the JSL was saved
+        self.clip_stack();
+    }
+
+    fn rti(&mut self) {
+        let p = self.popb();
+        self.set_p(p);
         let pcl = self.popb() as u16;
         let pch = self.popb() as u16;
-        let pbr = self.popb();
-        let pc = (pch << 8) | pcl;
-        self.pbr = pbr;
-        self.pc = pc + 1;   // +1 since the last byte of the JSL was saved
+        self.pc = (pch << 8) | pcl;
+        if !self.emulation {
+            self.pbr = self.popb();
+            self.cycles += 1;
+        }
+    }
+
+    fn brk(&mut self, _am: AddressingMode) {
+        // BRK and COP are 2 bytes long, the second one is a signature byte that is skipped
+        self.interrupt(Interrupt::Brk);
+    }
+
+    fn cop(&mut self, _am: AddressingMode) {
+        self.interrupt(Interrupt::Cop);
     }
 
     fn cmp(&mut self, am: AddressingMode) {
@@ -577,6 +1270,52 @@ impl W65C816 {
         }
     }
 
+    fn stz(&mut self, am: AddressingMode) {
+        if self.p.small_acc() {
+            am.storeb(self, 0);
+        } else {
+            am.storew(self, 0);
+        }
+    }
+
+    fn mvn(&mut self, am: AddressingMode) {
+        self.block_move(am, 1);
+    }
+
+    fn mvp(&mut self, am: AddressingMode) {
+        self.block_move(am, 0xffff);
+    }
+
+    /// Moves a single byte from src:X to dst:Y, then steps X and Y by `step` and decrements A.
+    ///
+    /// Until A wraps around to $FFFF the PC is moved back onto the instruction, so it is executed
+    /// again for the next byte. This makes every byte take 7 cycles and allows interrupts to be
+    /// taken in the middle of a move, just like on the real chip.
+    fn block_move(&mut self, am: AddressingMode, step: u16) {
+        let (dst, src) = match am {
+            AddressingMode::BlockMove(dst, src) => (dst, src),
+            _ => panic!("block move without banks"),
+        };
+        self.dbr = dst;
+
+        let val = self.loadb(src, self.x);
+        self.storeb(dst, self.y, val);
+        self.io();
+        self.io();
+        if self.p.small_idx() {
+            self.x = self.x.wrapping_add(step) & 0xff;
+            self.y = self.y.wrapping_add(step) & 0xff;
+        } else {
+            self.x = self.x.wrapping_add(step);
+            self.y = self.y.wrapping_add(step);
+        }
+
+        self.a = self.a.wrapping_sub(1);
+        if self.a != 0xffff {
+            self.pc = self.pc.wrapping_sub(3);
+        }
+    }
+
     fn tax(&mut self) {
         if self.p.small_idx() {
             self.x = (self.x & 0xFF00) | self.p.set_nz_8(self.a as u8) as u16;
@@ -585,6 +1324,14 @@ impl W65C816 {
         }
     }
 
+    fn tay(&mut self) {
+        if self.p.small_idx() {
+            self.y = (self.y & 0xFF00) | self.p.set_nz_8(self.a as u8) as u16;
+        } else {
+            self.y = self.p.set_nz(self.a);
+        }
+    }
+
     fn txa(&mut self) {
         if self.p.small_acc() {
             self.a = (self.a & 0xFF00) | self.p.set_nz_8(self.x as u8) as u16;
@@ -593,6 +1340,22 @@ impl W65C816 {
         }
     }
 
+    fn tya(&mut self) {
+        if self.p.small_acc() {
+            self.a = (self.a & 0xFF00) | self.p.set_nz_8(self.y as u8) as u16;
+        } else {
+            self.a = self.p.set_nz(self.y);
+        }
+    }
+
+    fn txy(&mut self) {
+        if self.p.small_idx() {
+            self.y = (self.y & 0xFF00) | self.p.set_nz_8(self.x as u8) as u16;
+        } else {
+            self.y = self.p.set_nz(self.x);
+        }
+    }
+
     fn tyx(&mut self) {
         if self.p.small_idx() {
             self.x = (self.x & 0xFF00) | self.p.set_nz_8(self.y as u8) as u16;
@@ -601,6 +1364,14 @@ impl W65C816 {
         }
     }
 
+    fn tsx(&mut self) {
+        if self.p.small_idx() {
+            self.x = self.p.set_nz_8(self.s as u8) as u16;
+        } else {
+            self.x = self.p.set_nz(self.s);
+        }
+    }
+
     fn txs(&mut self) {
         if self.emulation {
             self.s = 0x0100 | (self.x & 0xFF);
@@ -609,6 +1380,27 @@ impl W65C816 {
         }
     }
 
+    fn tcs(&mut self) {
+        // Transfers all 16 bits of C, regardless of the accumulator size. Sets no flags.
+        if self.emulation {
+            self.s = 0x0100 | (self.a & 0xFF);
+        } else {
+            self.s = self.a;
+        }
+    }
+
+    fn tsc(&mut self) {
+        self.a = self.p.set_nz(self.s);
+    }
+
+    fn tcd(&mut self) {
+        self.d = self.p.set_nz(self.a);
+    }
+
+    fn tdc(&mut self) {
+        self.a = self.p.set_nz(self.d);
+    }
+
     fn xba(&mut self) {
         // Changes N and Z: "The flags are changed based on the new value of the low byte, the A
         // accumulator (that is, on the former value of the high byte, the B accumulator), even in
@@ -625,6 +1417,7 @@ impl W65C816 {
         } else {
             let a = self.a;
             self.pushw(a);
+            self.cycles += 1;
         }
     }
 
@@ -634,6 +1427,7 @@ impl W65C816 {
             self.a = (self.a & 0xFF00) | self.p.set_nz_8(a) as u16;
         } else {
             let a = self.popw();
+            self.cycles += 1;
             self.a = self.p.set_nz(a);
         }
     }
@@ -645,6 +1439,7 @@ impl W65C816 {
         } else {
             let a = self.x;
             self.pushw(a);
+            self.cycles += 1;
         }
     }
 
@@ -654,6 +1449,7 @@ impl W65C816 {
             self.x = (self.x & 0xFF00) | self.p.set_nz_8(a) as u16;
         } else {
             let a = self.popw();
+            self.cycles += 1;
             self.x = self.p.set_nz(a);
         }
     }
@@ -665,6 +1461,7 @@ impl W65C816 {
         } else {
             let a = self.y;
             self.pushw(a);
+            self.cycles += 1;
         }
     }
 
@@ -674,13 +1471,72 @@ impl W65C816 {
             self.y = (self.y & 0xFF00) | self.p.set_nz_8(a) as u16;
         } else {
             let a = self.popw();
+            self.cycles += 1;
             self.y = self.p.set_nz(a);
         }
     }
 
+    fn php(&mut self) {
+        let p = self.p.0;
+        self.pushb(p);
+    }
+
+    fn plp(&mut self) {
+        let p = self.popb();
+        self.set_p(p);
+    }
+
+    fn phb(&mut self) {
+        let dbr = self.dbr;
+        self.pushb(dbr);
+    }
+
+    fn plb(&mut self) {
+        let dbr = self.popb_unwrapped();
+        self.dbr = self.p.set_nz_8(dbr);
+        self.clip_stack();
+    }
+
+    fn phd(&mut self) {
+        let d = self.d;
+        self.pushw_unwrapped(d);
+        self.clip_stack();
+    }
+
+    fn pld(&mut self) {
+        let d = self.popw_unwrapped();
+        self.d = self.p.set_nz(d);
+        self.clip_stack();
+    }
+
+    fn phk(&mut self) {
+        let pbr = self.pbr;
+        self.pushb(pbr);
+    }
+
+    fn pea(&mut self, am: AddressingMode) {
+        // PEA pushes the address that abs would access
+        let (_, val) = am.address(self);
+        self.pushw_unwrapped(val);
+        self.clip_stack();
+    }
+
+    fn pei(&mut self, am: AddressingMode) {
+        // PEI pushes the pointer that (d) would dereference
+        let (_, val) = am.address(self);
+        self.pushw_unwrapped(val);
+        self.clip_stack();
+    }
+
+    fn per(&mut self, am: AddressingMode) {
+        let (_, addr) = am.address(self);
+        self.io();
+        self.pushw_unwrapped(addr);
+        self.clip_stack();
+    }
+
     fn inx(&mut self) {
         if self.p.small_idx() {
-            println!("[inx] SMALL_IDX");
             let res = self.p.set_nz_8((self.x as u8).wrapping_add(1));
             self.x = (self.x & 0xFF00) | res as u16;
         } else {
@@ -690,7 +1546,6 @@ impl W65C816 {
 
     fn iny(&mut self) {
         if self.p.small_idx() {
-            println!("[inx] SMALL_IDX");
             let res = self.p.set_nz_8((self.y as u8).wrapping_add(1));
             self.y = (self.y & 0xFF00) | res as u16;
         } else {
@@ -716,16 +1571,47 @@ impl W65C816 {
         }
     }
 
+    fn inc_a(&mut self) {
+        if self.p.small_acc() {
+            let res = self.p.set_nz_8((self.a as u8).wrapping_add(1));
+            self.a = (self.a & 0xFF00) | res as u16;
+        } else {
+            self.a = self.p.set_nz(self.a.wrapping_add(1));
+        }
+    }
+
+    fn dec_a(&mut self) {
+        if self.p.small_acc() {
+            let res = self.p.set_nz_8((self.a as u8).wrapping_sub(1));
+            self.a = (self.a & 0xFF00) | res as u16;
+        } else {
+            self.a = self.p.set_nz(self.a.wrapping_sub(1));
+        }
+    }
+
     fn inc(&mut self, am: AddressingMode) {
-        let (bank, addr) = am.address(self);
+        let (bank, addr) = am.write_address(self);
         if self.p.small_acc() {
             let res = self.loadb(bank, addr).wrapping_add(1);
             self.p.set_nz_8(res);
-            self.storeb(bank, addr, res);
+            am.modifyb_at(self, bank, addr, res);
         } else {
-            let res = self.loadw(bank, addr).wrapping_add(1);
+            let res = am.loadw_at(self, bank, addr).wrapping_add(1);
             self.p.set_nz(res);
-            self.storew(bank, addr, res);
+            am.modifyw_at(self, bank, addr, res);
+        }
+    }
+
+    fn dec(&mut self, am: AddressingMode) {
+        let (bank, addr) = am.write_address(self);
+        if self.p.small_acc() {
+            let res = self.loadb(bank, addr).wrapping_sub(1);
+            self.p.set_nz_8(res);
+            am.modifyb_at(self, bank, addr, res);
+        } else {
+            let res = am.loadw_at(self, bank, addr).wrapping_sub(1);
+            self.p.set_nz(res);
+            am.modifyw_at(self, bank, addr, res);
         }
     }
 
@@ -739,12 +1625,14 @@ impl W65C816 {
 
     fn rep(&mut self, am: AddressingMode) {
         let p = self.p.0 & !am.loadb(self);
+        self.io();
         self.set_p(p);
         
     }
 
     fn sep(&mut self, am: AddressingMode) {
         let p = self.p.0 | am.loadb(self);
+        self.io();
         self.set_p(p);
         
     }
@@ -765,66 +1653,14 @@ impl W65C816 {
         self.run_status = RunStatus::Waiting
     }
 
-    fn direct(&mut self) -> AddressingMode {
-        AddressingMode::Direct(self.fetchb())
-    }
-
-    fn absolute(&mut self) -> AddressingMode {
-        AddressingMode::Absolute(self.fetchw())
-    }
+    fn nop(&mut self) {}
 
-    fn absolute_indexed_x(&mut self) -> AddressingMode {
-        AddressingMode::AbsIndexedX(self.fetchw())
-    }
-
-    fn absolute_indexed_y(&mut self) -> AddressingMode {
-        AddressingMode::AbsIndexedY(self.fetchw())
-    }
-
-    fn absolute_long(&mut self) -> AddressingMode {
-        let addr = self.fetchw();
-        let bank = self.fetchb();
-        AddressingMode::AbsoluteLong(bank, addr)
-    }
-    
-    fn absolute_long_indexed_x(&mut self) -> AddressingMode {
-        let addr = self.fetchw();
-        let bank = self.fetchb();
-        AddressingMode::AbsLongIndexedX(bank, addr)
-    }
-
-    fn direct_indirect_long(&mut self) -> AddressingMode {
-        AddressingMode::DirectIndirectLong(self.fetchb())
-    }
-
-    fn direct_indirect_long_idx(&mut self) -> AddressingMode {
-        AddressingMode::DirectIndirectLongIdx(self.fetchb())
-    }
-
-    fn immediate8(&mut self) -> AddressingMode {
-        AddressingMode::Immediate8(self.fetchb())
-    }
-
-    fn immediate_acc(&mut self) -> AddressingMode {
-        if self.p.small_acc() {
-            AddressingMode::Immediate8(self.fetchb())
-        } else {
-            AddressingMode::Immediate(self.fetchw())
-        }
-    }
-
-    fn immediate_index(&mut self) -> AddressingMode {
-        if self.p.small_idx() {
-            AddressingMode::Immediate8(self.fetchb())
-        } else {
-            AddressingMode::Immediate(self.fetchw())
+    fn wdm(&mut self, am: AddressingMode) {
+        // Reserved for future expansion, acts as a 2 byte NOP unless semihosting is enabled
+        let signature = am.loadb(self);
+        if let Some(mut semihost) = self.semihost.take() {
+            semihost.call(self, signature);
+            self.semihost = Some(semihost);
         }
     }
-
-    fn rel(&mut self) -> AddressingMode {
-        AddressingMode::Rel(self.fetchb() as i8)
-    }
-    fn relative_long(&mut self) -> AddressingMode {
-        AddressingMode::RelLong(self.fetchw() as i16)
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,42 @@
+/// What the CPU was doing when it accessed memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// Any read that isn't one of the fetches below, this includes operand bytes
+    Read,
+    Write,
+    OpcodeFetch,
+    /// Reading an interrupt or reset vector
+    VectorFetch,
+}
+
+/// A single memory access by the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    /// 24-bit address
+    pub addr: u32,
+    /// The value read or written. Reads of unmapped memory see $FF.
+    pub value: u8,
+    /// The CPU cycle the access happens in, counting internal operations and wait states before
+    /// it in the same instruction. Wait states stretch the access itself, the next access comes
+    /// that many cycles later.
+    pub cycle: u64,
+}
+
+/// Gets told about every memory access of the CPU it's registered with.
+///
+/// Observers that need to hand results back to the tooling that registered them can share them
+/// through an `Rc<RefCell<_>>`, or be taken back with `W65C816::remove_observer`.
+pub trait Observer {
+    fn access(&mut self, access: &Access);
+}
+
+impl<F: FnMut(&Access)> Observer for F {
+    fn access(&mut self, access: &Access) {
+        self(access)
+    }
+}
+
+/// Identifies a registered observer, so it can be removed again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObserverId(pub(super) u32);
@@ -0,0 +1,384 @@
+use std::fmt;
+
+use super::status::Status;
+
+use self::Mnemonic::*;
+use self::Mode::*;
+use self::Size::*;
+
+/// Everything about an opcode that doesn't depend on the CPU state.
+///
+/// Execution, cycle counting, disassembly and tracing all work from `OPCODES`, so they can't
+/// disagree on what an opcode is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Opcode {
+    pub mnemonic: Mnemonic,
+    pub mode: Mode,
+    pub size: Size,
+    /// Base cycle count, as documented by WDC. This is the count for 8-bit M and X, DL = 0, no
+    /// page crossing and untaken branches. The extra cycles for the other cases are added while
+    /// executing the instruction.
+    pub cycles: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    Adc, And, Asl, Bcc, Bcs, Beq, Bit, Bmi, Bne, Bpl, Bra, Brk, Brl, Bvc, Bvs, Clc,
+    Cld, Cli, Clv, Cmp, Cop, Cpx, Cpy, Dec, Dex, Dey, Eor, Inc, Inx, Iny, Jml, Jmp,
+    Jsl, Jsr, Lda, Ldx, Ldy, Lsr, Mvn, Mvp, Nop, Ora, Pea, Pei, Per, Pha, Phb, Phd,
+    Phk, Php, Phx, Phy, Pla, Plb, Pld, Plp, Plx, Ply, Rep, Rol, Ror, Rti, Rtl, Rts,
+    Sbc, Sec, Sed, Sei, Sep, Sta, Stp, Stx, Sty, Stz, Tax, Tay, Tcd, Tcs, Tdc, Trb,
+    Tsb, Tsc, Tsx, Txa, Txs, Txy, Tya, Tyx, Wai, Wdm, Xba, Xce,
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+/// How the operand bytes following the opcode are used, named like the `AddressingMode` they
+/// decode to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// No operand, this includes the stack instructions
+    Implied,
+    /// Works on A, like `ASL A`
+    Accumulator,
+    /// 8 or 16 bits, depending on the `Size` of the opcode
+    Immediate,
+
+    Rel,
+    RelLong,
+
+    Direct,
+    DirectIndexedX,
+    DirectIndexedY,
+    DirectIndexedIndirect,
+    DirectIndirect,
+    DirectIndirectIndexed,
+    DirectIndirectLong,
+    DirectIndirectLongIdx,
+
+    Absolute,
+    AbsIndexedX,
+    AbsIndexedY,
+    AbsIndexedIndirect,
+    AbsLongIndexedX,
+    AbsoluteLong,
+    AbsoluteIndirect,
+    AbsoluteIndirectLong,
+
+    StackRel,
+    StackRelIndirectIdx,
+
+    /// Destination and source bank of MVN/MVP
+    BlockMove,
+}
+
+/// What decides whether the instruction works on 8 or 16 bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// Doesn't depend on M or X. The immediate operand of a fixed size opcode is a single byte.
+    Fixed,
+    /// 16 bits when M is clear
+    Acc,
+    /// 16 bits when X is clear
+    Index,
+}
+
+impl Size {
+    pub fn is_wide(self, p: &Status) -> bool {
+        match self {
+            Fixed => false,
+            Acc => !p.small_acc(),
+            Index => !p.small_idx(),
+        }
+    }
+}
+
+impl Mode {
+    /// Number of operand bytes following the opcode
+    pub fn operand_bytes(self, wide: bool) -> u8 {
+        match self {
+            Implied | Accumulator => 0,
+            Immediate => if wide { 2 } else { 1 },
+            Rel | Direct | DirectIndexedX | DirectIndexedY | DirectIndexedIndirect |
+            DirectIndirect | DirectIndirectIndexed | DirectIndirectLong | DirectIndirectLongIdx |
+            StackRel | StackRelIndirectIdx => 1,
+            RelLong | Absolute | AbsIndexedX | AbsIndexedY | AbsIndexedIndirect |
+            AbsoluteIndirect | AbsoluteIndirectLong | BlockMove => 2,
+            AbsoluteLong | AbsLongIndexedX => 3,
+        }
+    }
+}
+
+impl Opcode {
+    /// Length of the instruction in bytes, including the opcode
+    pub fn len(&self, p: &Status) -> u8 {
+        1 + self.mode.operand_bytes(self.size.is_wide(p))
+    }
+}
+
+const fn op(mnemonic: Mnemonic, mode: Mode, size: Size, cycles: u8) -> Opcode {
+    Opcode { mnemonic, mode, size, cycles }
+}
+
+pub static OPCODES: [Opcode; 256] = [
+    /* 00 */ op(Brk, Immediate, Fixed, 7),
+    /* 01 */ op(Ora, DirectIndexedIndirect, Acc, 6),
+    /* 02 */ op(Cop, Immediate, Fixed, 7),
+    /* 03 */ op(Ora, StackRel, Acc, 4),
+    /* 04 */ op(Tsb, Direct, Acc, 5),
+    /* 05 */ op(Ora, Direct, Acc, 3),
+    /* 06 */ op(Asl, Direct, Acc, 5),
+    /* 07 */ op(Ora, DirectIndirectLong, Acc, 6),
+    /* 08 */ op(Php, Implied, Fixed, 3),
+    /* 09 */ op(Ora, Immediate, Acc, 2),
+    /* 0A */ op(Asl, Accumulator, Acc, 2),
+    /* 0B */ op(Phd, Implied, Fixed, 4),
+    /* 0C */ op(Tsb, Absolute, Acc, 6),
+    /* 0D */ op(Ora, Absolute, Acc, 4),
+    /* 0E */ op(Asl, Absolute, Acc, 6),
+    /* 0F */ op(Ora, AbsoluteLong, Acc, 5),
+    /* 10 */ op(Bpl, Rel, Fixed, 2),
+    /* 11 */ op(Ora, DirectIndirectIndexed, Acc, 5),
+    /* 12 */ op(Ora, DirectIndirect, Acc, 5),
+    /* 13 */ op(Ora, StackRelIndirectIdx, Acc, 7),
+    /* 14 */ op(Trb, Direct, Acc, 5),
+    /* 15 */ op(Ora, DirectIndexedX, Acc, 4),
+    /* 16 */ op(Asl, DirectIndexedX, Acc, 6),
+    /* 17 */ op(Ora, DirectIndirectLongIdx, Acc, 6),
+    /* 18 */ op(Clc, Implied, Fixed, 2),
+    /* 19 */ op(Ora, AbsIndexedY, Acc, 4),
+    /* 1A */ op(Inc, Accumulator, Acc, 2),
+    /* 1B */ op(Tcs, Implied, Fixed, 2),
+    /* 1C */ op(Trb, Absolute, Acc, 6),
+    /* 1D */ op(Ora, AbsIndexedX, Acc, 4),
+    /* 1E */ op(Asl, AbsIndexedX, Acc, 7),
+    /* 1F */ op(Ora, AbsLongIndexedX, Acc, 5),
+    /* 20 */ op(Jsr, Absolute, Fixed, 6),
+    /* 21 */ op(And, DirectIndexedIndirect, Acc, 6),
+    /* 22 */ op(Jsl, AbsoluteLong, Fixed, 8),
+    /* 23 */ op(And, StackRel, Acc, 4),
+    /* 24 */ op(Bit, Direct, Acc, 3),
+    /* 25 */ op(And, Direct, Acc, 3),
+    /* 26 */ op(Rol, Direct, Acc, 5),
+    /* 27 */ op(And, DirectIndirectLong, Acc, 6),
+    /* 28 */ op(Plp, Implied, Fixed, 4),
+    /* 29 */ op(And, Immediate, Acc, 2),
+    /* 2A */ op(Rol, Accumulator, Acc, 2),
+    /* 2B */ op(Pld, Implied, Fixed, 5),
+    /* 2C */ op(Bit, Absolute, Acc, 4),
+    /* 2D */ op(And, Absolute, Acc, 4),
+    /* 2E */ op(Rol, Absolute, Acc, 6),
+    /* 2F */ op(And, AbsoluteLong, Acc, 5),
+    /* 30 */ op(Bmi, Rel, Fixed, 2),
+    /* 31 */ op(And, DirectIndirectIndexed, Acc, 5),
+    /* 32 */ op(And, DirectIndirect, Acc, 5),
+    /* 33 */ op(And, StackRelIndirectIdx, Acc, 7),
+    /* 34 */ op(Bit, DirectIndexedX, Acc, 4),
+    /* 35 */ op(And, DirectIndexedX, Acc, 4),
+    /* 36 */ op(Rol, DirectIndexedX, Acc, 6),
+    /* 37 */ op(And, DirectIndirectLongIdx, Acc, 6),
+    /* 38 */ op(Sec, Implied, Fixed, 2),
+    /* 39 */ op(And, AbsIndexedY, Acc, 4),
+    /* 3A */ op(Dec, Accumulator, Acc, 2),
+    /* 3B */ op(Tsc, Implied, Fixed, 2),
+    /* 3C */ op(Bit, AbsIndexedX, Acc, 4),
+    /* 3D */ op(And, AbsIndexedX, Acc, 4),
+    /* 3E */ op(Rol, AbsIndexedX, Acc, 7),
+    /* 3F */ op(And, AbsLongIndexedX, Acc, 5),
+    /* 40 */ op(Rti, Implied, Fixed, 6),
+    /* 41 */ op(Eor, DirectIndexedIndirect, Acc, 6),
+    /* 42 */ op(Wdm, Immediate, Fixed, 2),
+    /* 43 */ op(Eor, StackRel, Acc, 4),
+    /* 44 */ op(Mvp, BlockMove, Fixed, 7),
+    /* 45 */ op(Eor, Direct, Acc, 3),
+    /* 46 */ op(Lsr, Direct, Acc, 5),
+    /* 47 */ op(Eor, DirectIndirectLong, Acc, 6),
+    /* 48 */ op(Pha, Implied, Acc, 3),
+    /* 49 */ op(Eor, Immediate, Acc, 2),
+    /* 4A */ op(Lsr, Accumulator, Acc, 2),
+    /* 4B */ op(Phk, Implied, Fixed, 3),
+    /* 4C */ op(Jmp, Absolute, Fixed, 3),
+    /* 4D */ op(Eor, Absolute, Acc, 4),
+    /* 4E */ op(Lsr, Absolute, Acc, 6),
+    /* 4F */ op(Eor, AbsoluteLong, Acc, 5),
+    /* 50 */ op(Bvc, Rel, Fixed, 2),
+    /* 51 */ op(Eor, DirectIndirectIndexed, Acc, 5),
+    /* 52 */ op(Eor, DirectIndirect, Acc, 5),
+    /* 53 */ op(Eor, StackRelIndirectIdx, Acc, 7),
+    /* 54 */ op(Mvn, BlockMove, Fixed, 7),
+    /* 55 */ op(Eor, DirectIndexedX, Acc, 4),
+    /* 56 */ op(Lsr, DirectIndexedX, Acc, 6),
+    /* 57 */ op(Eor, DirectIndirectLongIdx, Acc, 6),
+    /* 58 */ op(Cli, Implied, Fixed, 2),
+    /* 59 */ op(Eor, AbsIndexedY, Acc, 4),
+    /* 5A */ op(Phy, Implied, Index, 3),
+    /* 5B */ op(Tcd, Implied, Fixed, 2),
+    /* 5C */ op(Jml, AbsoluteLong, Fixed, 4),
+    /* 5D */ op(Eor, AbsIndexedX, Acc, 4),
+    /* 5E */ op(Lsr, AbsIndexedX, Acc, 7),
+    /* 5F */ op(Eor, AbsLongIndexedX, Acc, 5),
+    /* 60 */ op(Rts, Implied, Fixed, 6),
+    /* 61 */ op(Adc, DirectIndexedIndirect, Acc, 6),
+    /* 62 */ op(Per, RelLong, Fixed, 6),
+    /* 63 */ op(Adc, StackRel, Acc, 4),
+    /* 64 */ op(Stz, Direct, Acc, 3),
+    /* 65 */ op(Adc, Direct, Acc, 3),
+    /* 66 */ op(Ror, Direct, Acc, 5),
+    /* 67 */ op(Adc, DirectIndirectLong, Acc, 6),
+    /* 68 */ op(Pla, Implied, Acc, 4),
+    /* 69 */ op(Adc, Immediate, Acc, 2),
+    /* 6A */ op(Ror, Accumulator, Acc, 2),
+    /* 6B */ op(Rtl, Implied, Fixed, 6),
+    /* 6C */ op(Jmp, AbsoluteIndirect, Fixed, 5),
+    /* 6D */ op(Adc, Absolute, Acc, 4),
+    /* 6E */ op(Ror, Absolute, Acc, 6),
+    /* 6F */ op(Adc, AbsoluteLong, Acc, 5),
+    /* 70 */ op(Bvs, Rel, Fixed, 2),
+    /* 71 */ op(Adc, DirectIndirectIndexed, Acc, 5),
+    /* 72 */ op(Adc, DirectIndirect, Acc, 5),
+    /* 73 */ op(Adc, StackRelIndirectIdx, Acc, 7),
+    /* 74 */ op(Stz, DirectIndexedX, Acc, 4),
+    /* 75 */ op(Adc, DirectIndexedX, Acc, 4),
+    /* 76 */ op(Ror, DirectIndexedX, Acc, 6),
+    /* 77 */ op(Adc, DirectIndirectLongIdx, Acc, 6),
+    /* 78 */ op(Sei, Implied, Fixed, 2),
+    /* 79 */ op(Adc, AbsIndexedY, Acc, 4),
+    /* 7A */ op(Ply, Implied, Index, 4),
+    /* 7B */ op(Tdc, Implied, Fixed, 2),
+    /* 7C */ op(Jmp, AbsIndexedIndirect, Fixed, 6),
+    /* 7D */ op(Adc, AbsIndexedX, Acc, 4),
+    /* 7E */ op(Ror, AbsIndexedX, Acc, 7),
+    /* 7F */ op(Adc, AbsLongIndexedX, Acc, 5),
+    /* 80 */ op(Bra, Rel, Fixed, 3),
+    /* 81 */ op(Sta, DirectIndexedIndirect, Acc, 6),
+    /* 82 */ op(Brl, RelLong, Fixed, 4),
+    /* 83 */ op(Sta, StackRel, Acc, 4),
+    /* 84 */ op(Sty, Direct, Index, 3),
+    /* 85 */ op(Sta, Direct, Acc, 3),
+    /* 86 */ op(Stx, Direct, Index, 3),
+    /* 87 */ op(Sta, DirectIndirectLong, Acc, 6),
+    /* 88 */ op(Dey, Implied, Index, 2),
+    /* 89 */ op(Bit, Immediate, Acc, 2),
+    /* 8A */ op(Txa, Implied, Acc, 2),
+    /* 8B */ op(Phb, Implied, Fixed, 3),
+    /* 8C */ op(Sty, Absolute, Index, 4),
+    /* 8D */ op(Sta, Absolute, Acc, 4),
+    /* 8E */ op(Stx, Absolute, Index, 4),
+    /* 8F */ op(Sta, AbsoluteLong, Acc, 5),
+    /* 90 */ op(Bcc, Rel, Fixed, 2),
+    /* 91 */ op(Sta, DirectIndirectIndexed, Acc, 6),
+    /* 92 */ op(Sta, DirectIndirect, Acc, 5),
+    /* 93 */ op(Sta, StackRelIndirectIdx, Acc, 7),
+    /* 94 */ op(Sty, DirectIndexedX, Index, 4),
+    /* 95 */ op(Sta, DirectIndexedX, Acc, 4),
+    /* 96 */ op(Stx, DirectIndexedY, Index, 4),
+    /* 97 */ op(Sta, DirectIndirectLongIdx, Acc, 6),
+    /* 98 */ op(Tya, Implied, Acc, 2),
+    /* 99 */ op(Sta, AbsIndexedY, Acc, 5),
+    /* 9A */ op(Txs, Implied, Fixed, 2),
+    /* 9B */ op(Txy, Implied, Index, 2),
+    /* 9C */ op(Stz, Absolute, Acc, 4),
+    /* 9D */ op(Sta, AbsIndexedX, Acc, 5),
+    /* 9E */ op(Stz, AbsIndexedX, Acc, 5),
+    /* 9F */ op(Sta, AbsLongIndexedX, Acc, 5),
+    /* A0 */ op(Ldy, Immediate, Index, 2),
+    /* A1 */ op(Lda, DirectIndexedIndirect, Acc, 6),
+    /* A2 */ op(Ldx, Immediate, Index, 2),
+    /* A3 */ op(Lda, StackRel, Acc, 4),
+    /* A4 */ op(Ldy, Direct, Index, 3),
+    /* A5 */ op(Lda, Direct, Acc, 3),
+    /* A6 */ op(Ldx, Direct, Index, 3),
+    /* A7 */ op(Lda, DirectIndirectLong, Acc, 6),
+    /* A8 */ op(Tay, Implied, Index, 2),
+    /* A9 */ op(Lda, Immediate, Acc, 2),
+    /* AA */ op(Tax, Implied, Index, 2),
+    /* AB */ op(Plb, Implied, Fixed, 4),
+    /* AC */ op(Ldy, Absolute, Index, 4),
+    /* AD */ op(Lda, Absolute, Acc, 4),
+    /* AE */ op(Ldx, Absolute, Index, 4),
+    /* AF */ op(Lda, AbsoluteLong, Acc, 5),
+    /* B0 */ op(Bcs, Rel, Fixed, 2),
+    /* B1 */ op(Lda, DirectIndirectIndexed, Acc, 5),
+    /* B2 */ op(Lda, DirectIndirect, Acc, 5),
+    /* B3 */ op(Lda, StackRelIndirectIdx, Acc, 7),
+    /* B4 */ op(Ldy, DirectIndexedX, Index, 4),
+    /* B5 */ op(Lda, DirectIndexedX, Acc, 4),
+    /* B6 */ op(Ldx, DirectIndexedY, Index, 4),
+    /* B7 */ op(Lda, DirectIndirectLongIdx, Acc, 6),
+    /* B8 */ op(Clv, Implied, Fixed, 2),
+    /* B9 */ op(Lda, AbsIndexedY, Acc, 4),
+    /* BA */ op(Tsx, Implied, Index, 2),
+    /* BB */ op(Tyx, Implied, Index, 2),
+    /* BC */ op(Ldy, AbsIndexedX, Index, 4),
+    /* BD */ op(Lda, AbsIndexedX, Acc, 4),
+    /* BE */ op(Ldx, AbsIndexedY, Index, 4),
+    /* BF */ op(Lda, AbsLongIndexedX, Acc, 5),
+    /* C0 */ op(Cpy, Immediate, Index, 2),
+    /* C1 */ op(Cmp, DirectIndexedIndirect, Acc, 6),
+    /* C2 */ op(Rep, Immediate, Fixed, 3),
+    /* C3 */ op(Cmp, StackRel, Acc, 4),
+    /* C4 */ op(Cpy, Direct, Index, 3),
+    /* C5 */ op(Cmp, Direct, Acc, 3),
+    /* C6 */ op(Dec, Direct, Acc, 5),
+    /* C7 */ op(Cmp, DirectIndirectLong, Acc, 6),
+    /* C8 */ op(Iny, Implied, Index, 2),
+    /* C9 */ op(Cmp, Immediate, Acc, 2),
+    /* CA */ op(Dex, Implied, Index, 2),
+    /* CB */ op(Wai, Implied, Fixed, 3),
+    /* CC */ op(Cpy, Absolute, Index, 4),
+    /* CD */ op(Cmp, Absolute, Acc, 4),
+    /* CE */ op(Dec, Absolute, Acc, 6),
+    /* CF */ op(Cmp, AbsoluteLong, Acc, 5),
+    /* D0 */ op(Bne, Rel, Fixed, 2),
+    /* D1 */ op(Cmp, DirectIndirectIndexed, Acc, 5),
+    /* D2 */ op(Cmp, DirectIndirect, Acc, 5),
+    /* D3 */ op(Cmp, StackRelIndirectIdx, Acc, 7),
+    /* D4 */ op(Pei, DirectIndirect, Fixed, 6),
+    /* D5 */ op(Cmp, DirectIndexedX, Acc, 4),
+    /* D6 */ op(Dec, DirectIndexedX, Acc, 6),
+    /* D7 */ op(Cmp, DirectIndirectLongIdx, Acc, 6),
+    /* D8 */ op(Cld, Implied, Fixed, 2),
+    /* D9 */ op(Cmp, AbsIndexedY, Acc, 4),
+    /* DA */ op(Phx, Implied, Index, 3),
+    /* DB */ op(Stp, Implied, Fixed, 3),
+    /* DC */ op(Jml, AbsoluteIndirectLong, Fixed, 6),
+    /* DD */ op(Cmp, AbsIndexedX, Acc, 4),
+    /* DE */ op(Dec, AbsIndexedX, Acc, 7),
+    /* DF */ op(Cmp, AbsLongIndexedX, Acc, 5),
+    /* E0 */ op(Cpx, Immediate, Index, 2),
+    /* E1 */ op(Sbc, DirectIndexedIndirect, Acc, 6),
+    /* E2 */ op(Sep, Immediate, Fixed, 3),
+    /* E3 */ op(Sbc, StackRel, Acc, 4),
+    /* E4 */ op(Cpx, Direct, Index, 3),
+    /* E5 */ op(Sbc, Direct, Acc, 3),
+    /* E6 */ op(Inc, Direct, Acc, 5),
+    /* E7 */ op(Sbc, DirectIndirectLong, Acc, 6),
+    /* E8 */ op(Inx, Implied, Index, 2),
+    /* E9 */ op(Sbc, Immediate, Acc, 2),
+    /* EA */ op(Nop, Implied, Fixed, 2),
+    /* EB */ op(Xba, Implied, Fixed, 3),
+    /* EC */ op(Cpx, Absolute, Index, 4),
+    /* ED */ op(Sbc, Absolute, Acc, 4),
+    /* EE */ op(Inc, Absolute, Acc, 6),
+    /* EF */ op(Sbc, AbsoluteLong, Acc, 5),
+    /* F0 */ op(Beq, Rel, Fixed, 2),
+    /* F1 */ op(Sbc, DirectIndirectIndexed, Acc, 5),
+    /* F2 */ op(Sbc, DirectIndirect, Acc, 5),
+    /* F3 */ op(Sbc, StackRelIndirectIdx, Acc, 7),
+    /* F4 */ op(Pea, Absolute, Fixed, 5),
+    /* F5 */ op(Sbc, DirectIndexedX, Acc, 4),
+    /* F6 */ op(Inc, DirectIndexedX, Acc, 6),
+    /* F7 */ op(Sbc, DirectIndirectLongIdx, Acc, 6),
+    /* F8 */ op(Sed, Implied, Fixed, 2),
+    /* F9 */ op(Sbc, AbsIndexedY, Acc, 4),
+    /* FA */ op(Plx, Implied, Index, 4),
+    /* FB */ op(Xce, Implied, Fixed, 2),
+    /* FC */ op(Jsr, AbsIndexedIndirect, Fixed, 8),
+    /* FD */ op(Sbc, AbsIndexedX, Acc, 4),
+    /* FE */ op(Inc, AbsIndexedX, Acc, 7),
+    /* FF */ op(Sbc, AbsLongIndexedX, Acc, 5),
+];
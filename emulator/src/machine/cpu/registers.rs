@@ -0,0 +1,53 @@
+use std::fmt;
+
+use super::status::Status;
+
+/// Snapshot of the programmer visible state of a `W65C816`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Registers {
+    pub(super) a: u16,
+    pub(super) x: u16,
+    pub(super) y: u16,
+    pub(super) s: u16,
+
+    pub(super) dbr: u8,
+    pub(super) pbr: u8,
+
+    pub(super) d:  u16,
+    pub(super) pc: u16,
+
+    pub(super) emulation: bool,
+    pub(super) p: Status,
+}
+
+impl Registers {
+    pub fn a(&self) -> u16 { self.a }
+    pub fn x(&self) -> u16 { self.x }
+    pub fn y(&self) -> u16 { self.y }
+    pub fn s(&self) -> u16 { self.s }
+    pub fn dbr(&self) -> u8 { self.dbr }
+    pub fn pbr(&self) -> u8 { self.pbr }
+    pub fn d(&self) -> u16 { self.d }
+    pub fn pc(&self) -> u16 { self.pc }
+    pub fn emulation(&self) -> bool { self.emulation }
+    pub fn p(&self) -> Status { self.p }
+
+    pub fn set_a(&mut self, value: u16) { self.a = value; }
+    pub fn set_x(&mut self, value: u16) { self.x = value; }
+    pub fn set_y(&mut self, value: u16) { self.y = value; }
+    pub fn set_s(&mut self, value: u16) { self.s = value; }
+    pub fn set_dbr(&mut self, value: u8) { self.dbr = value; }
+    pub fn set_pbr(&mut self, value: u8) { self.pbr = value; }
+    pub fn set_d(&mut self, value: u16) { self.d = value; }
+    pub fn set_pc(&mut self, value: u16) { self.pc = value; }
+    pub fn set_emulation(&mut self, value: bool) { self.emulation = value; }
+    pub fn set_p(&mut self, value: Status) { self.p = value; }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A={:04X} X={:04X} Y={:04X} S={:04X} D={:04X} DBR={:02X} PC={:02X}{:04X} P={} E={}",
+            self.a, self.x, self.y, self.s, self.d, self.dbr, self.pbr, self.pc, self.p,
+            if self.emulation { 1 } else { 0 })
+    }
+}
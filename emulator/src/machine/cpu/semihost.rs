@@ -0,0 +1,503 @@
+//! Host services for programs running in the emulator, called through the WDM opcode.
+//!
+//! The signature byte following WDM selects the service. Pointers are passed with the address in
+//! C (the whole 16-bit accumulator, whatever the size of A) and the bank in the low byte of X.
+//! Services that fail set the carry and return $FFFF in C, successful ones clear the carry. C is
+//! only changed by services that return something.
+//!
+//! | Signature | Service                                                                     |
+//! |-----------|-----------------------------------------------------------------------------|
+//! | $00       | Print the zero terminated string at the pointer                             |
+//! | $01       | Print A in hex, 2 or 4 digits depending on M                                |
+//! | $02       | Exit the emulator, with the low byte of A as exit code                      |
+//! | $03       | Store the CPU cycle counter at the pointer, as 8 bytes                      |
+//! | $04       | Save battery backed RAM to its host files                                   |
+//! | $10       | Open the file named by the string at the pointer, the low byte of Y is the  |
+//! |           | mode (0 = read, 1 = write, 2 = append). Returns the handle in C.            |
+//! | $11       | Close the file with the handle in the low byte of Y                         |
+//! | $12       | Read from a file, the pointer points to a parameter block                   |
+//! | $13       | Write to a file, the pointer points to a parameter block                    |
+//!
+//! The parameter block for reading and writing holds the handle (1 byte), a pointer to the buffer
+//! (3 bytes) and the number of bytes to transfer (2 bytes). Both return the number of bytes
+//! transferred in C.
+//!
+//! Files are only accessible inside the sandbox directory, names must be relative and can't
+//! contain "..", and symbolic links can't lead out of it. Without a sandbox, all file services
+//! fail.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::super::bus::SystemBus;
+use super::super::fault::Fault;
+use super::{RunStatus, W65C816};
+
+const PRINT_STRING: u8 = 0x00;
+const PRINT_HEX: u8 = 0x01;
+const EXIT: u8 = 0x02;
+const CYCLES: u8 = 0x03;
+const FLUSH: u8 = 0x04;
+const OPEN: u8 = 0x10;
+const CLOSE: u8 = 0x11;
+const READ: u8 = 0x12;
+const WRITE: u8 = 0x13;
+
+/// Longest string or file name read from emulated memory
+const MAX_STRING: usize = 4096;
+
+pub struct Semihost {
+    sandbox: Option<PathBuf>,
+    files: Vec<Option<File>>,
+    /// Where printed text goes, stdout
+    out: Box<dyn Write>,
+}
+
+impl Semihost {
+    /// Semihosting with file access restricted to `sandbox`, or no file access at all
+    pub fn new(sandbox: Option<PathBuf>) -> Self {
+        Semihost {
+            sandbox,
+            files: Vec::new(),
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    /// Runs the service selected by `signature`. Errors of the service itself are reported to the
+    /// program, only an unknown signature is a fault.
+    pub(super) fn call<B: SystemBus>(&mut self, cpu: &mut W65C816<B>, signature: u8) {
+        let result = match signature {
+            PRINT_STRING => {
+                let s = read_string(cpu);
+                write!(self.out, "{}", String::from_utf8_lossy(&s))
+                    .and_then(|_| self.out.flush())
+                    .map(|_| None)
+            }
+            PRINT_HEX => {
+                if cpu.p.small_acc() {
+                    write!(self.out, "{:02X}", cpu.a as u8)
+                } else {
+                    write!(self.out, "{:04X}", cpu.a)
+                }
+                .and_then(|_| self.out.flush())
+                .map(|_| None)
+            }
+            EXIT => {
+                cpu.run_status = RunStatus::Exited(cpu.a as u8);
+                Ok(None)
+            }
+            CYCLES => {
+                let (bank, addr) = pointer(cpu);
+                for (i, b) in cpu.cycles.to_le_bytes().iter().enumerate() {
+                    store(cpu, bank, addr, i, *b);
+                }
+                Ok(None)
+            }
+            FLUSH => cpu.bus.flush().map(|_| None).map_err(io::Error::other),
+            OPEN => self.open(cpu),
+            CLOSE => self.close(cpu.y as u8),
+            READ => self.transfer(cpu, false),
+            WRITE => self.transfer(cpu, true),
+            _ => {
+                cpu.fault(Fault::UnknownSemihostCall(signature));
+                return;
+            }
+        };
+
+        match result {
+            Ok(c) => {
+                if let Some(c) = c {
+                    cpu.a = c;
+                }
+                cpu.p.set_carry(false);
+            }
+            Err(_) => {
+                cpu.a = 0xffff;
+                cpu.p.set_carry(true);
+            }
+        }
+    }
+
+    fn open<B: SystemBus>(&mut self, cpu: &mut W65C816<B>) -> io::Result<Option<u16>> {
+        let name = String::from_utf8(read_string(cpu))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file name isn't UTF-8"))?;
+        let path = self.sandboxed(&name)?;
+        let file = match cpu.y as u8 {
+            0 => File::open(path)?,
+            1 => File::create(path)?,
+            2 => OpenOptions::new().append(true).create(true).open(path)?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown file mode")),
+        };
+
+        // reuse the lowest free handle, handles are a single byte
+        let handle = match self.files.iter().position(|f| f.is_none()) {
+            Some(handle) => handle,
+            None if self.files.len() <= 0xff => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return Err(io::Error::other("too many open files")),
+        };
+        self.files[handle] = Some(file);
+        Ok(Some(handle as u16))
+    }
+
+    fn close(&mut self, handle: u8) -> io::Result<Option<u16>> {
+        match self.files.get_mut(handle as usize).and_then(|f| f.take()) {
+            Some(_) => Ok(None),
+            None => Err(bad_handle()),
+        }
+    }
+
+    fn transfer<B: SystemBus>(&mut self, cpu: &mut W65C816<B>, write: bool)
+        -> io::Result<Option<u16>>
+    {
+        let (bank, addr) = pointer(cpu);
+        let handle = load(cpu, bank, addr, 0);
+        let buf_addr = u16::from_le_bytes([load(cpu, bank, addr, 1), load(cpu, bank, addr, 2)]);
+        let buf_bank = load(cpu, bank, addr, 3);
+        let len = u16::from_le_bytes([load(cpu, bank, addr, 4), load(cpu, bank, addr, 5)]);
+
+        let file = self.files.get_mut(handle as usize)
+            .and_then(|f| f.as_mut())
+            .ok_or_else(bad_handle)?;
+        let mut buf = vec![0u8; len as usize];
+        if write {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = load(cpu, buf_bank, buf_addr, i);
+            }
+            file.write_all(&buf)?;
+            Ok(Some(len))
+        } else {
+            let count = file.read(&mut buf)?;
+            for (i, b) in buf[..count].iter().enumerate() {
+                store(cpu, buf_bank, buf_addr, i, *b);
+            }
+            Ok(Some(count as u16))
+        }
+    }
+
+    /// Resolves a file name inside the sandbox, refusing anything that could escape it. Symbolic
+    /// links are followed, and have to end up inside the sandbox as well.
+    fn sandboxed(&self, name: &str) -> io::Result<PathBuf> {
+        let sandbox = self.sandbox.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "no sandbox directory")
+        })?;
+        let path = Path::new(name);
+        if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(outside_sandbox());
+        }
+
+        let path = sandbox.join(path);
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            // a file that's about to be created, the directory it goes in has to be inside. A
+            // dangling link doesn't count, creating the file would follow it.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && path.symlink_metadata().is_err() => {
+                let (Some(dir), Some(file_name)) = (path.parent(), path.file_name()) else {
+                    return Err(e);
+                };
+                dir.canonicalize()?.join(file_name)
+            }
+            Err(e) => return Err(e),
+        };
+        if !resolved.starts_with(sandbox.canonicalize()?) {
+            return Err(outside_sandbox());
+        }
+        Ok(resolved)
+    }
+}
+
+fn outside_sandbox() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "file outside the sandbox")
+}
+
+fn bad_handle() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "bad file handle")
+}
+
+/// The pointer argument, bank in X and address in C
+fn pointer<B: SystemBus>(cpu: &W65C816<B>) -> (u8, u16) {
+    (cpu.x as u8, cpu.a)
+}
+
+/// Loads the byte at `offset` from a 24-bit pointer, carrying into the next bank
+fn load<B: SystemBus>(cpu: &mut W65C816<B>, bank: u8, addr: u16, offset: usize) -> u8 {
+    let eff_addr = ((bank as usize) << 16 | addr as usize).wrapping_add(offset) & 0xffffff;
+    cpu.loadb((eff_addr >> 16) as u8, eff_addr as u16)
+}
+
+fn store<B: SystemBus>(cpu: &mut W65C816<B>, bank: u8, addr: u16, offset: usize, value: u8) {
+    let eff_addr = ((bank as usize) << 16 | addr as usize).wrapping_add(offset) & 0xffffff;
+    cpu.storeb((eff_addr >> 16) as u8, eff_addr as u16, value)
+}
+
+fn read_string<B: SystemBus>(cpu: &mut W65C816<B>) -> Vec<u8> {
+    let (bank, addr) = pointer(cpu);
+    (0..MAX_STRING)
+        .map(|i| load(cpu, bank, addr, i))
+        .take_while(|&b| b != 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+
+    use super::*;
+    use super::super::super::bus::{Bus, Mapping};
+    use super::super::super::device::{NvRam, Ram};
+    use super::super::super::fault::CpuFault;
+
+    /// Where the results of the calls made by `calls` are stored, 2 bytes per call
+    const RESULTS: u16 = 0x3000;
+
+    /// Collects what the program prints
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A program making the service calls `(signature, C, Y)` in native mode with 16-bit
+    /// registers and X = 0, so pointers are in bank 0. After each call C is stored at `RESULTS`
+    /// onwards, with the carry set when the call failed. Stops at the end.
+    fn calls(calls: &[(u8, u16, u16)]) -> Vec<u8> {
+        // CLC, XCE, REP #$30, LDX #$0000
+        let mut program = vec![0x18, 0xFB, 0xC2, 0x30, 0xA2, 0x00, 0x00];
+        for (i, &(signature, c, y)) in calls.iter().enumerate() {
+            let [c_lo, c_hi] = c.to_le_bytes();
+            let [y_lo, y_hi] = y.to_le_bytes();
+            let [result_lo, result_hi] = (RESULTS + 2 * i as u16).to_le_bytes();
+            // LDA #c, LDY #y, WDM signature, STA result
+            program.extend([0xA9, c_lo, c_hi, 0xA0, y_lo, y_hi, 0x42, signature]);
+            program.extend([0x8D, result_lo, result_hi]);
+        }
+        // STP
+        program.push(0xDB);
+        program
+    }
+
+    /// A bus with 64K of RAM in bank 0 holding `program` at $8000 and the reset vector pointing
+    /// at it, and `data` at the given addresses
+    fn ram_bus(program: &[u8], data: &[(u16, &[u8])]) -> Bus {
+        let mut bus = Bus::new();
+        let ram = bus.add_device(Box::new(Ram::new(0x10000)));
+        bus.map(ram, Mapping::new(0x00..=0x00, 0x0000..=0xFFFF));
+        for &(addr, bytes) in [(0x8000, program)].iter().chain(data) {
+            for (i, &byte) in bytes.iter().enumerate() {
+                bus.write(0x00, addr + i as u16, byte).unwrap();
+            }
+        }
+        bus.write(0x00, 0xFFFC, 0x00).unwrap();
+        bus.write(0x00, 0xFFFD, 0x80).unwrap();
+        bus
+    }
+
+    /// Runs until the program stops, exits or faults
+    fn run(cpu: &mut W65C816, semihost: Semihost) -> Result<RunStatus, CpuFault> {
+        cpu.enable_semihosting(semihost);
+        loop {
+            match cpu.instruction()? {
+                RunStatus::Running => {}
+                status => return Ok(status),
+            }
+        }
+    }
+
+    /// The 16-bit values at `addr` onwards
+    fn words(cpu: &mut W65C816, addr: u16, count: u16) -> Vec<u16> {
+        let bus = cpu.bus_mut();
+        (0..count)
+            .map(|i| {
+                let lo = bus.read(0x00, addr + 2 * i).unwrap() as u16;
+                let hi = bus.read(0x00, addr + 2 * i + 1).unwrap() as u16;
+                (hi << 8) | lo
+            })
+            .collect()
+    }
+
+    /// Semihosting with printed text going to the returned buffer
+    fn with_output(sandbox: Option<PathBuf>) -> (Semihost, Output) {
+        let out = Output::default();
+        (Semihost { out: Box::new(out.clone()), ..Semihost::new(sandbox) }, out)
+    }
+
+    /// A fresh sandbox under the system temp dir
+    fn sandbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("cate16-semihost-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn print_and_exit() {
+        let mut program = calls(&[(PRINT_STRING, 0x1000, 0), (PRINT_HEX, 0xBEEF, 0)]);
+        // drop the STP, then SEP #$20, LDA #$42, WDM $01, WDM $02
+        program.pop();
+        program.extend([0xE2, 0x20, 0xA9, 0x42, 0x42, PRINT_HEX, 0x42, EXIT]);
+        let mut cpu = W65C816::new(ram_bus(&program, &[(0x1000, b"hi \0")]));
+        let (semihost, out) = with_output(None);
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Exited(0x42)));
+        assert_eq!(*out.0.borrow(), b"hi BEEF42");
+
+        // the string is passed in C and X, and only C is changed by services returning something
+        assert_eq!(words(&mut cpu, RESULTS, 2), [0x1000, 0xBEEF]);
+        assert!(!cpu.registers().p().carry());
+    }
+
+    #[test]
+    fn cycles() {
+        let program = calls(&[(CYCLES, 0x2000, 0)]);
+        let mut cpu = W65C816::new(ram_bus(&program, &[]));
+        let (semihost, _) = with_output(None);
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Stopped));
+
+        // taken during WDM, STA and STP come after it
+        let bytes = (0..8u16).map(|i| cpu.bus_mut().read(0x00, 0x2000 + i).unwrap());
+        let cycles = u64::from_le_bytes(bytes.collect::<Vec<_>>().try_into().unwrap());
+        assert_eq!(cycles, cpu.cycles() - 5 - 3);
+    }
+
+    #[test]
+    fn flush() {
+        let dir = sandbox("flush");
+        let path = dir.join("nvram.bin");
+        let program = calls(&[(FLUSH, 0x1234, 0)]);
+        let mut bus = ram_bus(&program, &[]);
+        let nvram = bus.add_device(Box::new(NvRam::open(path.clone(), 0x10).unwrap()));
+        bus.map(nvram, Mapping::new(0x01..=0x01, 0x0000..=0x000F));
+        bus.write(0x01, 0x0003, 0x99).unwrap();
+        assert!(!path.exists());
+
+        let mut cpu = W65C816::new(bus);
+        let (semihost, _) = with_output(None);
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Stopped));
+        assert_eq!(words(&mut cpu, RESULTS, 1), [0x1234]);
+        assert_eq!(fs::read(&path).unwrap(), [0, 0, 0, 0x99, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files() {
+        let dir = sandbox("files");
+        fs::write(dir.join("in.txt"), b"from the host").unwrap();
+        let program = calls(&[
+            // write "hello" to a new file, handle 0
+            (OPEN, 0x1000, 1),
+            (WRITE, 0x1200, 0),
+            // read the start of another one, handle 1
+            (OPEN, 0x1010, 0),
+            (READ, 0x1210, 0),
+            // closing leaves C alone, and the freed handle is used again, for appending
+            (CLOSE, 0x1111, 0),
+            (CLOSE, 0x1111, 0),
+            (OPEN, 0x1000, 2),
+            (WRITE, 0x1200, 0),
+            (CLOSE, 0x1111, 0),
+            (CLOSE, 0x1111, 1),
+            // unknown mode
+            (OPEN, 0x1010, 3),
+            // reading a closed file
+            (READ, 0x1210, 0),
+        ]);
+        let data: &[(u16, &[u8])] = &[
+            (0x1000, b"out.txt\0"),
+            (0x1010, b"in.txt\0"),
+            (0x1100, b"hello"),
+            // handle, buffer and length for writing and reading
+            (0x1200, &[0x00, 0x00, 0x11, 0x00, 0x05, 0x00]),
+            (0x1210, &[0x01, 0x00, 0x14, 0x00, 0x08, 0x00]),
+        ];
+        let mut cpu = W65C816::new(ram_bus(&program, data));
+        let (semihost, _) = with_output(Some(dir.clone()));
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Stopped));
+        assert_eq!(words(&mut cpu, RESULTS, 12), [
+            0x0000, 5, 0x0001, 8, 0x1111, 0xFFFF, 0x0000, 5, 0x1111, 0x1111, 0xFFFF, 0xFFFF,
+        ]);
+        assert!(cpu.registers().p().carry());
+
+        assert_eq!(fs::read(dir.join("out.txt")).unwrap(), b"hellohello");
+        let read = (0..8u16).map(|i| cpu.bus_mut().read(0x00, 0x1400 + i).unwrap());
+        assert_eq!(read.collect::<Vec<_>>(), b"from the");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sandbox_rejections() {
+        let dir = sandbox("rejections");
+        let outside = sandbox("rejections-outside");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file.txt"), b"inside").unwrap();
+        fs::write(outside.join("secret.txt"), b"outside").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("new.txt"), dir.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("inner")).unwrap();
+
+        let names: &[&[u8]] = &[
+            b"../rejections-outside/secret.txt\0",
+            b"sub/../../rejections-outside/secret.txt\0",
+            b"/etc/hostname\0",
+            b"\0",
+            b"link/secret.txt\0",
+            b"link/new.txt\0",
+            b"dangling\0",
+            // links that stay inside are fine
+            b"inner/file.txt\0",
+        ];
+        let data: Vec<(u16, &[u8])> = names.iter()
+            .enumerate()
+            .map(|(i, &name)| (0x1000 + 0x40 * i as u16, name))
+            .collect();
+        let mut file_calls: Vec<_> = (0..names.len())
+            .map(|i| (OPEN, 0x1000 + 0x40 * i as u16, 1))
+            .collect();
+        // the existing files outside are opened for reading, the rest for writing
+        file_calls[0].2 = 0;
+        file_calls[4].2 = 0;
+        let program = calls(&file_calls);
+
+        let mut cpu = W65C816::new(ram_bus(&program, &data));
+        let (semihost, _) = with_output(Some(dir.clone()));
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Stopped));
+        assert_eq!(words(&mut cpu, RESULTS, names.len() as u16),
+            [0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0x0000]);
+        assert!(!outside.join("new.txt").exists());
+        assert_eq!(fs::read(outside.join("secret.txt")).unwrap(), b"outside");
+
+        // without a sandbox there are no files at all
+        let mut cpu = W65C816::new(ram_bus(&program, &data));
+        let (semihost, _) = with_output(None);
+        assert_eq!(run(&mut cpu, semihost), Ok(RunStatus::Stopped));
+        assert_eq!(words(&mut cpu, RESULTS, names.len() as u16), [0xFFFF; 8]);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn unknown_call() {
+        let program = calls(&[(0x05, 0x0000, 0)]);
+        let mut cpu = W65C816::new(ram_bus(&program, &[]));
+        let (semihost, _) = with_output(None);
+        let fault = run(&mut cpu, semihost).unwrap_err();
+        assert_eq!(fault.fault, Fault::UnknownSemihostCall(0x05));
+        // reported at the WDM
+        assert_eq!((fault.pbr, fault.pc), (0x00, 0x800D));
+    }
+}
@@ -0,0 +1,249 @@
+//! Runs the CPU against test vectors in the single-step test format of the SingleStepTests/65816
+//! project: one file per opcode and mode, `xx.n.json` for native and `xx.e.json` for emulation
+//! mode, each holding a list of tests that give the state before and after executing a single
+//! instruction, plus one entry per bus cycle.
+//!
+//! A cycle is `[address, value, pins]`, where the pins are a string like "dp-remx-" with a letter
+//! for each of VDA, VPA, VPB, R/W, E, M, X and MLB that's active, and a "-" for the others. Cycles
+//! with VDA or VPA set are reads or writes, which are checked against the accesses of the CPU,
+//! along with the cycle they happen in. The others are internal operations, which only count
+//! towards the number of cycles.
+//!
+//! The vectors in `tests/vectors/65816` are made by `generate.py` next to them, see the README
+//! there. Set `SINGLE_STEP_VECTORS` to the `v1` directory of SingleStepTests/65816 to run against
+//! those instead.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use super::observer::Access;
+use super::registers::Registers;
+use super::status::Status;
+use super::W65C816;
+use super::super::bus::SystemBus;
+use super::super::fault::{CpuFault, Fault};
+
+/// A read or write on the bus: cycle counted from the start of the instruction, address, value and
+/// "r" or "w"
+type BusCycle = (u64, u32, u8, &'static str);
+
+/// 16MB of RAM, anything that wasn't given by the test reads as 0. Keeps a log of every access,
+/// the cycles are filled in from what the CPU tells its observers.
+struct TestBus {
+    ram: HashMap<u32, u8>,
+    log: Vec<BusCycle>,
+}
+
+impl SystemBus for TestBus {
+    fn read(&mut self, bank: u8, addr: u16) -> Result<u8, Fault> {
+        let value = self.ram.get(&address(bank, addr)).copied().unwrap_or(0);
+        self.log.push((0, address(bank, addr), value, "r"));
+        Ok(value)
+    }
+
+    fn write(&mut self, bank: u8, addr: u16, value: u8) -> Result<(), Fault> {
+        self.ram.insert(address(bank, addr), value);
+        self.log.push((0, address(bank, addr), value, "w"));
+        Ok(())
+    }
+
+    fn idle(&mut self, _cycles: u64) {}
+
+    fn next_event(&self) -> u64 {
+        1
+    }
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {}
+}
+
+fn address(bank: u8, addr: u16) -> u32 {
+    ((bank as u32) << 16) | addr as u32
+}
+
+fn vector_dir() -> PathBuf {
+    match env::var_os("SINGLE_STEP_VECTORS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors/65816"),
+    }
+}
+
+fn field(state: &Value, name: &str) -> u64 {
+    state[name].as_u64().unwrap_or_else(|| panic!("missing field {}", name))
+}
+
+fn ram(state: &Value) -> impl Iterator<Item = (u32, u8)> + '_ {
+    state["ram"].as_array().expect("missing ram").iter().map(|entry| {
+        (entry[0].as_u64().unwrap() as u32, entry[1].as_u64().unwrap() as u8)
+    })
+}
+
+fn registers(state: &Value) -> Registers {
+    let mut registers = Registers::default();
+    registers.set_a(field(state, "a") as u16);
+    registers.set_x(field(state, "x") as u16);
+    registers.set_y(field(state, "y") as u16);
+    registers.set_s(field(state, "s") as u16);
+    registers.set_d(field(state, "d") as u16);
+    registers.set_dbr(field(state, "dbr") as u8);
+    registers.set_pbr(field(state, "pbr") as u8);
+    registers.set_pc(field(state, "pc") as u16);
+    registers.set_p(Status(field(state, "p") as u8));
+    registers.set_emulation(field(state, "e") != 0);
+    registers
+}
+
+fn set_state(cpu: &mut W65C816<TestBus>, state: &Value) {
+    cpu.set_registers(&registers(state));
+    for (addr, value) in ram(state) {
+        cpu.bus.ram.insert(addr, value);
+    }
+}
+
+/// Compares the CPU with the expected state, returns a description of every difference
+fn check_state(cpu: &W65C816<TestBus>, state: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    let expected = registers(state);
+    if cpu.registers() != expected {
+        errors.push(format!("registers are {}, expected {}", cpu.registers(), expected));
+    }
+    for (addr, expected) in ram(state) {
+        let actual = cpu.bus.ram.get(&addr).copied().unwrap_or(0);
+        if actual != expected {
+            errors.push(format!("{:06X} is {:02X}, expected {:02X}", addr, actual, expected));
+        }
+    }
+    errors
+}
+
+/// The reads and writes of a test, without the internal operation cycles
+fn bus_cycles(test: &Value) -> Vec<BusCycle> {
+    test["cycles"].as_array().expect("missing cycles").iter().enumerate()
+        .filter_map(|(n, cycle)| {
+            let pins = cycle[2].as_str().filter(|pins| pins.len() == 8)
+                .unwrap_or_else(|| panic!("{}: bad pins in cycle {}", test["name"], cycle));
+            if !pins.contains('d') && !pins.contains('p') {
+                return None;
+            }
+            let kind = if pins.contains('w') { "w" } else { "r" };
+            let field = |i: usize| cycle[i].as_u64()
+                .unwrap_or_else(|| panic!("{}: bad cycle {}", test["name"], cycle));
+            Some((n as u64, field(0) as u32, field(1) as u8, kind))
+        })
+        .collect()
+}
+
+fn format_cycles(cycles: &[BusCycle]) -> String {
+    cycles.iter()
+        .map(|(cycle, addr, value, kind)| format!("{}:{} {:06X} {:02X}", cycle, kind, addr, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn run_test(test: &Value) -> Vec<String> {
+    let mut cpu = W65C816::new(TestBus { ram: HashMap::new(), log: Vec::new() });
+    set_state(&mut cpu, &test["initial"]);
+    // forget the reset vector fetch
+    cpu.bus.log.clear();
+
+    let start = cpu.cycles;
+    let stamps = Rc::new(RefCell::new(Vec::new()));
+    let log = stamps.clone();
+    cpu.add_observer(Box::new(move |access: &Access| log.borrow_mut().push(access.cycle - start)));
+    match cpu.instruction() {
+        // the stack pointer wrapping around is reported, but it does wrap like on the real chip
+        Ok(_) | Err(CpuFault { fault: Fault::StackViolation(_), .. }) => {}
+        Err(fault) => return vec![format!("fault: {}", fault)],
+    }
+
+    let mut errors = check_state(&cpu, &test["final"]);
+    for (cycle, stamp) in cpu.bus.log.iter_mut().zip(stamps.borrow().iter()) {
+        cycle.0 = *stamp;
+    }
+    let expected = bus_cycles(test);
+    if cpu.bus.log != expected {
+        errors.push(format!("bus cycles are [{}], expected [{}]", format_cycles(&cpu.bus.log),
+            format_cycles(&expected)));
+    }
+    let expected = test["cycles"].as_array().expect("missing cycles").len() as u64;
+    let actual = cpu.cycles - start;
+    if actual != expected {
+        errors.push(format!("took {} cycles, expected {}", actual, expected));
+    }
+    errors
+}
+
+/// Runs the tests of every opcode in one mode, `mode` is "n" or "e"
+fn run_mode(mode: &str) {
+    let mut failures = Vec::new();
+    let mut count = 0;
+    for opcode in 0..=0xFF {
+        let path = vector_dir().join(format!("{:02x}.{}.json", opcode, mode));
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e));
+        let tests: Value = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("can't parse {}: {}", path.display(), e));
+
+        for test in tests.as_array().expect("expected a list of tests") {
+            count += 1;
+            let errors = run_test(test);
+            if !errors.is_empty() {
+                failures.push(format!("{}: {}", test["name"], errors.join(", ")));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} tests failed:\n{}", failures.len(), count,
+        failures.join("\n"));
+}
+
+#[test]
+fn native() {
+    run_mode("n");
+}
+
+#[test]
+fn emulation() {
+    run_mode("e");
+}
+
+/// A test in the format of SingleStepTests/65816, written by hand: LDA $12 with a direct page that
+/// isn't page aligned. Like the upstream files, the internal cycle has an address but no value.
+const LDA_DIRECT: &str = r#"{
+    "name": "a5 n",
+    "initial": {"pc": 4096, "s": 511, "p": 48, "a": 0, "x": 0, "y": 0, "dbr": 0, "d": 257,
+        "pbr": 0, "e": 0, "ram": [[4096, 165], [4097, 18], [275, 66]]},
+    "final": {"pc": 4098, "s": 511, "p": 48, "a": 66, "x": 0, "y": 0, "dbr": 0, "d": 257,
+        "pbr": 0, "e": 0, "ram": [[4096, 165], [4097, 18], [275, 66]]},
+    "cycles": [[4096, 165, "dp-r-mx-"], [4097, 18, "-p-r-mx-"], [4097, null, "---r-mx-"],
+        [275, 66, "d--r-mx-"]]
+}"#;
+
+#[test]
+fn upstream_format() {
+    let test: Value = serde_json::from_str(LDA_DIRECT).unwrap();
+    assert_eq!(bus_cycles(&test),
+        [(0, 0x1000, 0xA5, "r"), (1, 0x1001, 0x12, "r"), (3, 0x0113, 0x42, "r")]);
+    assert_eq!(run_test(&test), Vec::<String>::new());
+
+    // a wrong value on the bus is caught
+    let mut wrong = test.clone();
+    wrong["cycles"][3][1] = 0x43.into();
+    assert_eq!(run_test(&wrong), ["bus cycles are [0:r 001000 A5, 1:r 001001 12, 3:r 000113 42], \
+        expected [0:r 001000 A5, 1:r 001001 12, 3:r 000113 43]"]);
+
+    // and so is the data read coming a cycle early
+    let mut early = test.clone();
+    early["cycles"].as_array_mut().unwrap().swap(2, 3);
+    assert_eq!(run_test(&early), ["bus cycles are [0:r 001000 A5, 1:r 001001 12, 3:r 000113 42], \
+        expected [0:r 001000 A5, 1:r 001001 12, 2:r 000113 42]"]);
+}
@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Status(pub u8);
 
 const CARRY_FLAG: u8        = 1 << 0;
@@ -10,6 +12,12 @@ const SMALL_ACC_FLAG: u8    = 1 << 5;
 const OVERFLOW_FLAG: u8      = 1 << 6;
 const NEG_FLAG: u8          = 1 << 7;
 
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Status {
     pub fn new() -> Status {
         let mut s: Status = Status(0);
@@ -27,9 +35,11 @@ impl Status {
 
     pub fn carry(&self) -> bool { (self.0 & CARRY_FLAG) != 0 }
     pub fn zero(&self) -> bool { (self.0 & ZERO_FLAG) != 0 }
+    pub fn interrupt(&self) -> bool { (self.0 & IRQ_FLAG) != 0 }
     pub fn decimal(&self) -> bool { (self.0 & DEC_FLAG) != 0 }
     pub fn small_idx(&self) -> bool { (self.0 & SMALL_IDX_FLAG) != 0 }
     pub fn small_acc(&self) -> bool { (self.0 & SMALL_ACC_FLAG) != 0 }
+    pub fn overflow(&self) -> bool { (self.0 & OVERFLOW_FLAG) != 0 }
     pub fn negative(&self) -> bool { (self.0 & NEG_FLAG) != 0 }
 
     pub fn set_carry(&mut self, value: bool) { self.set(CARRY_FLAG, value); }
@@ -41,6 +51,10 @@ impl Status {
     pub fn set_overflow(&mut self, value: bool) { self.set(OVERFLOW_FLAG, value); }
     pub fn set_negative(&mut self, value: bool) { self.set(NEG_FLAG, value); }
 
+    /// The B flag shares bit 4 with X. It only exists in the copy of P pushed in emulation mode,
+    /// where it tells BRK apart from IRQ.
+    pub fn brk(&self) -> bool { self.small_idx() }
+
     pub fn set_nz_8(&mut self, val: u8) -> u8 {
         self.set_zero(val == 0);
         self.set_negative(val & 0x80 != 0);
@@ -52,4 +66,15 @@ impl Status {
         self.set_negative(val & 0x8000 != 0);
         val
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Status {
+    /// The flags as "NVMXDIZC", cleared flags in lower case
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, c) in "NVMXDIZC".chars().enumerate() {
+            let set = self.0 & (0x80 >> i) != 0;
+            write!(f, "{}", if set { c } else { c.to_ascii_lowercase() })?;
+        }
+        Ok(())
+    }
+}
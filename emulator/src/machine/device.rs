@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::fault::Fault;
+
+/// Anything that can be mapped into the address space by `Bus`.
+///
+/// Devices only see offsets into themselves, the bus works out the offset from the address
+/// according to the `Mapping` of the device.
+pub trait BusDevice {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault>;
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault>;
+
+    /// Lets the device run for `cycles` CPU cycles. Never called with more cycles than
+    /// `next_event` asked for.
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Puts the device back in its power on state, called when the CPU is reset
+    fn reset(&mut self) {}
+
+    /// Number of cycles the device can be left alone before it needs a `tick`
+    fn next_event(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Whether the device is pulling the IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Saves whatever the device keeps on the host
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Ram { data: vec![0; size] }
+    }
+}
+
+impl BusDevice for Ram {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        Ok(self.data[offset as usize % self.data.len()])
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        let len = self.data.len();
+        self.data[offset as usize % len] = value;
+        Ok(())
+    }
+}
+
+/// RAM that keeps its contents in a host file, like battery backed SRAM
+pub struct NvRam {
+    data: Vec<u8>,
+    path: PathBuf,
+    /// Written to since the last flush
+    dirty: bool,
+}
+
+impl NvRam {
+    /// Loads the contents from `path`. Without a file the RAM starts out zeroed, a shorter file
+    /// fills the start of it.
+    pub fn open(path: PathBuf, size: usize) -> Result<Self, String> {
+        let mut data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        if data.len() > size {
+            return Err(format!("{}: {} bytes don't fit in {} bytes of RAM",
+                path.display(), data.len(), size));
+        }
+        data.resize(size, 0);
+        Ok(NvRam { data, path, dirty: false })
+    }
+}
+
+impl BusDevice for NvRam {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        Ok(self.data[offset as usize % self.data.len()])
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        let len = self.data.len();
+        self.data[offset as usize % len] = value;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if self.dirty {
+            fs::write(&self.path, &self.data)
+                .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("cate16-nvram-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn nvram_file() {
+        let dir = temp_dir("file");
+        let path = dir.join("nvram.bin");
+        fs::write(&path, [1, 2, 3, 4]).unwrap();
+        let mut nvram = NvRam::open(path.clone(), 4).unwrap();
+        assert_eq!(nvram.read(2), Ok(3));
+
+        // only written back once something changed
+        fs::write(&path, [9, 9, 9, 9]).unwrap();
+        nvram.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [9, 9, 9, 9]);
+        nvram.write(1, 0x42).unwrap();
+        nvram.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [1, 0x42, 3, 4]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nvram_missing_or_short_file() {
+        let dir = temp_dir("short");
+        let path = dir.join("nvram.bin");
+        let mut nvram = NvRam::open(path.clone(), 4).unwrap();
+        assert_eq!(nvram.read(0), Ok(0));
+        // nothing is created until there's something to keep
+        nvram.flush().unwrap();
+        assert!(!path.exists());
+        nvram.write(3, 7).unwrap();
+        nvram.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0, 0, 0, 7]);
+
+        fs::write(&path, [5, 6]).unwrap();
+        let mut nvram = NvRam::open(path.clone(), 4).unwrap();
+        assert_eq!((0..4).map(|offset| nvram.read(offset).unwrap()).collect::<Vec<_>>(),
+            [5, 6, 0, 0]);
+
+        let error = NvRam::open(path.clone(), 1).err().unwrap();
+        assert_eq!(error, format!("{}: 2 bytes don't fit in 1 bytes of RAM", path.display()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use std::error::Error;
+use std::fmt;
+
+use super::cpu::registers::Registers;
+
+/// Something the emulated machine did that real hardware would not (or could not) survive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Access to bank, address where nothing is mapped
+    UnmappedAddress(u8, u16),
+    /// Write of a value to bank, address in the flash ROM
+    FlashWrite(u8, u16, u8),
+    /// A device could not handle an access to one of its registers
+    Device(String),
+    /// The stack pointer ran past the start or end of bank 0
+    StackViolation(u16),
+    /// WDM with a signature that doesn't select a semihosting service
+    UnknownSemihostCall(u8),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::UnmappedAddress(bank, addr) => write!(f, "nothing mapped at {:02X}{:04X}", bank, addr),
+            Fault::FlashWrite(bank, addr, value) => {
+                write!(f, "write to flash ROM {:02X}{:04X} = {:02X}", bank, addr, value)
+            }
+            Fault::Device(msg) => write!(f, "device error: {}", msg),
+            Fault::StackViolation(s) => write!(f, "stack pointer wrapped around at {:04X}", s),
+            Fault::UnknownSemihostCall(signature) => {
+                write!(f, "unknown semihosting call {:02X}", signature)
+            }
+        }
+    }
+}
+
+/// A `Fault` together with the instruction that caused it and the state of the CPU after it.
+///
+/// The faulting access itself was harmless: a faulting read returned $FF and a faulting write was
+/// dropped. The CPU can keep running after it, if the front-end wants to be lenient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuFault {
+    pub fault: Fault,
+    pub pbr: u8,
+    pub pc: u16,
+    pub registers: Registers,
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [at {:02X}{:04X}]\n{}", self.fault, self.pbr, self.pc, self.registers)
+    }
+}
+
+impl Error for CpuFault {}
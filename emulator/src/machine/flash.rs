@@ -0,0 +1,310 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+
+use super::CPU_CLOCK;
+use super::device::BusDevice;
+use super::fault::Fault;
+
+/// Software ID of the SST39SF040
+const MANUFACTURER_ID: u8 = 0xBF;
+const DEVICE_ID: u8 = 0xB7;
+
+/// Erase granularity of `SectorErase`
+const SECTOR_SIZE: usize = 4 * 1024;
+
+/// Typical busy times from the datasheet, in microseconds
+const PROGRAM_TIME: u64 = 14;
+const SECTOR_ERASE_TIME: u64 = 18_000;
+const CHIP_ERASE_TIME: u64 = 70_000;
+
+fn cycles(micros: u64) -> u64 {
+    CPU_CLOCK * micros / 1_000_000
+}
+
+/// Where the command state machine is. Commands are written to $5555 and $2AAA (only A14-A0
+/// are decoded) after the unlock sequence AA, 55.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Read,
+    /// Got AA at $5555
+    Unlock1,
+    /// Got 55 at $2AAA
+    Unlock2,
+    /// Got A0, the next write programs a byte
+    Program,
+    /// Got 80, waiting for AA at $5555
+    EraseSetup,
+    /// Got AA at $5555 after 80
+    EraseUnlock1,
+    /// Got 55 at $2AAA after 80, waiting for 30 at a sector or 10 at $5555
+    EraseUnlock2,
+}
+
+/// A byte program or erase in progress
+#[derive(Debug, Clone, Copy)]
+struct Busy {
+    cycles: u64,
+    /// Bit 7 of the data being programmed, reads show its complement on DQ7 until done
+    dq7: u8,
+}
+
+/// SST39SF040-style JEDEC flash.
+///
+/// Programming only clears bits, erasing sets a whole sector (or the chip) back to $FF. While a
+/// program or erase is running, reads return the status: DQ7 is the complement of the data being
+/// programmed (0 during erase) and DQ6 toggles on every read. Writes that aren't part of a command
+/// sequence are `Fault::FlashWrite`, like writes to the ROM they used to be.
+pub struct Flash {
+    data: Vec<u8>,
+    state: State,
+    /// Software ID mode, reads return the manufacturer and device ID
+    id_mode: bool,
+    busy: Option<Busy>,
+    toggle: u8,
+    /// The ROM image, programs and erases are written back to it as they happen
+    write_back: Option<File>,
+}
+
+impl Flash {
+    pub fn new(data: Vec<u8>) -> Self {
+        Flash { data, state: State::Read, id_mode: false, busy: None, toggle: 0, write_back: None }
+    }
+
+    /// Writes every change back to `file`, which has to hold the image at its start
+    pub fn with_write_back(self, file: File) -> Self {
+        Flash { write_back: Some(file), ..self }
+    }
+
+    fn command(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        let addr = offset & 0x7FFF;
+        self.state = match (self.state, addr, value) {
+            (State::Read, 0x5555, 0xAA) => State::Unlock1,
+            (State::Read, _, 0xF0) => {
+                self.id_mode = false;
+                State::Read
+            }
+            (State::Unlock1, 0x2AAA, 0x55) => State::Unlock2,
+            (State::Unlock2, 0x5555, 0xA0) => State::Program,
+            (State::Unlock2, 0x5555, 0x80) => State::EraseSetup,
+            (State::Unlock2, 0x5555, 0x90) => {
+                self.id_mode = true;
+                State::Read
+            }
+            (State::Unlock2, 0x5555, 0xF0) => {
+                self.id_mode = false;
+                State::Read
+            }
+            (State::Program, _, _) => {
+                let offset = offset as usize % self.data.len();
+                self.data[offset] &= value;
+                self.start(PROGRAM_TIME, value & 0x80, offset, 1)?;
+                State::Read
+            }
+            (State::EraseSetup, 0x5555, 0xAA) => State::EraseUnlock1,
+            (State::EraseUnlock1, 0x2AAA, 0x55) => State::EraseUnlock2,
+            (State::EraseUnlock2, _, 0x30) => {
+                let sector = offset as usize % self.data.len() / SECTOR_SIZE * SECTOR_SIZE;
+                self.data[sector..sector + SECTOR_SIZE].fill(0xFF);
+                self.start(SECTOR_ERASE_TIME, 0x80, sector, SECTOR_SIZE)?;
+                State::Read
+            }
+            (State::EraseUnlock2, 0x5555, 0x10) => {
+                self.data.fill(0xFF);
+                self.start(CHIP_ERASE_TIME, 0x80, 0, self.data.len())?;
+                State::Read
+            }
+            _ => {
+                self.state = State::Read;
+                // the bus fills in the address
+                return Err(Fault::FlashWrite(0, 0, value));
+            }
+        };
+        Ok(())
+    }
+
+    /// Starts the busy time of an operation that changed `len` bytes from `offset`
+    fn start(&mut self, micros: u64, dq7: u8, offset: usize, len: usize) -> Result<(), Fault> {
+        self.busy = Some(Busy { cycles: cycles(micros), dq7: !dq7 & 0x80 });
+        if let Some(file) = self.write_back.as_mut() {
+            file.seek(SeekFrom::Start(offset as u64))
+                .and_then(|_| file.write_all(&self.data[offset..offset + len]))
+                .map_err(|e| Fault::Device(format!("can't write back flash: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for Flash {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        if let Some(busy) = self.busy {
+            self.toggle ^= 0x40;
+            return Ok(busy.dq7 | self.toggle);
+        }
+        if self.id_mode {
+            return Ok(if offset & 1 == 0 { MANUFACTURER_ID } else { DEVICE_ID });
+        }
+        Ok(self.data[offset as usize % self.data.len()])
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        // the chip ignores writes until it's done
+        if self.busy.is_some() {
+            return Ok(());
+        }
+        self.command(offset, value)
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some(busy) = self.busy.as_mut() {
+            busy.cycles = busy.cycles.saturating_sub(cycles);
+            if busy.cycles == 0 {
+                self.busy = None;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Read;
+        self.id_mode = false;
+    }
+
+    fn next_event(&self) -> u64 {
+        self.busy.map_or(u64::MAX, |busy| busy.cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flash() -> Flash {
+        let mut data = vec![0xFF; 4 * SECTOR_SIZE];
+        data[..8].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        data[SECTOR_SIZE] = 0x00;
+        Flash::new(data)
+    }
+
+    fn command(flash: &mut Flash, command: u8) {
+        flash.write(0x5555, 0xAA).unwrap();
+        flash.write(0x2AAA, 0x55).unwrap();
+        flash.write(0x5555, command).unwrap();
+    }
+
+    fn erase(flash: &mut Flash, offset: u32, command: u8) {
+        self::command(flash, 0x80);
+        flash.write(0x5555, 0xAA).unwrap();
+        flash.write(0x2AAA, 0x55).unwrap();
+        flash.write(offset, command).unwrap();
+    }
+
+    /// Runs until the program or erase in progress is done, returns how long that took
+    fn finish(flash: &mut Flash) -> u64 {
+        let cycles = flash.next_event();
+        assert_ne!(cycles, u64::MAX, "not busy");
+        flash.tick(cycles);
+        assert_eq!(flash.next_event(), u64::MAX);
+        cycles
+    }
+
+    #[test]
+    fn byte_program() {
+        let mut flash = flash();
+        command(&mut flash, 0xA0);
+        flash.write(0x0100, 0x5A).unwrap();
+        assert_eq!(finish(&mut flash), cycles(PROGRAM_TIME));
+        assert_eq!(flash.read(0x0100), Ok(0x5A));
+
+        // programming only clears bits
+        command(&mut flash, 0xA0);
+        flash.write(0x0100, 0xA5).unwrap();
+        finish(&mut flash);
+        assert_eq!(flash.read(0x0100), Ok(0x00));
+    }
+
+    #[test]
+    fn stray_writes_fault() {
+        let mut flash = flash();
+        assert_eq!(flash.write(0x0100, 0x5A), Err(Fault::FlashWrite(0, 0, 0x5A)));
+        // a broken sequence starts over
+        flash.write(0x5555, 0xAA).unwrap();
+        assert_eq!(flash.write(0x5555, 0x55), Err(Fault::FlashWrite(0, 0, 0x55)));
+        command(&mut flash, 0xA0);
+        flash.write(0x0100, 0x5A).unwrap();
+        finish(&mut flash);
+        assert_eq!(flash.read(0x0100), Ok(0x5A));
+    }
+
+    #[test]
+    fn sector_erase() {
+        let mut flash = flash();
+        // any address in the sector selects it
+        erase(&mut flash, SECTOR_SIZE as u32 + 0x123, 0x30);
+        assert_eq!(finish(&mut flash), cycles(SECTOR_ERASE_TIME));
+        assert_eq!(flash.read(SECTOR_SIZE as u32), Ok(0xFF));
+        // the other sectors keep their data
+        assert_eq!(flash.read(7), Ok(7));
+    }
+
+    #[test]
+    fn chip_erase() {
+        let mut flash = flash();
+        erase(&mut flash, 0x5555, 0x10);
+        assert_eq!(finish(&mut flash), cycles(CHIP_ERASE_TIME));
+        assert!((0..4 * SECTOR_SIZE as u32).all(|offset| flash.read(offset) == Ok(0xFF)));
+    }
+
+    #[test]
+    fn software_id() {
+        let mut flash = flash();
+        command(&mut flash, 0x90);
+        assert_eq!(flash.read(0x0000), Ok(MANUFACTURER_ID));
+        assert_eq!(flash.read(0x0001), Ok(DEVICE_ID));
+        assert_eq!(flash.read(0x8002), Ok(MANUFACTURER_ID));
+
+        // F0 on its own leaves ID mode
+        flash.write(0x1234, 0xF0).unwrap();
+        assert_eq!(flash.read(0x0001), Ok(1));
+
+        // and so does the 3 byte form
+        command(&mut flash, 0x90);
+        command(&mut flash, 0xF0);
+        assert_eq!(flash.read(0x0001), Ok(1));
+
+        command(&mut flash, 0x90);
+        flash.reset();
+        assert_eq!(flash.read(0x0001), Ok(1));
+    }
+
+    #[test]
+    fn status_polling() {
+        let mut flash = flash();
+        command(&mut flash, 0xA0);
+        flash.write(0x0100, 0x80).unwrap();
+        // DQ7 is the complement of the data, DQ6 toggles
+        let first = flash.read(0x0100).unwrap();
+        let second = flash.read(0x0000).unwrap();
+        assert_eq!(first & 0x80, 0x00);
+        assert_eq!(second & 0x80, 0x00);
+        assert_eq!((first ^ second) & 0x40, 0x40);
+        // writes are ignored while busy
+        assert_eq!(flash.write(0x0100, 0x12), Ok(()));
+
+        flash.tick(1);
+        assert_ne!(flash.next_event(), u64::MAX);
+        finish(&mut flash);
+        assert_eq!(flash.read(0x0100), Ok(0x80));
+        assert_eq!(flash.read(0x0100), Ok(0x80));
+
+        command(&mut flash, 0xA0);
+        flash.write(0x0101, 0x7F).unwrap();
+        assert_eq!(flash.read(0x0101).unwrap() & 0x80, 0x80);
+        finish(&mut flash);
+
+        // erases read DQ7 as 0
+        erase(&mut flash, 0x0000, 0x30);
+        let first = flash.read(0x0000).unwrap();
+        let second = flash.read(0x0000).unwrap();
+        assert_eq!(first & 0x80, 0x00);
+        assert_eq!((first ^ second) & 0x40, 0x40);
+    }
+}
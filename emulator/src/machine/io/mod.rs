@@ -1,32 +1,166 @@
+pub mod pic;
+pub mod rtc;
 pub mod uart;
+pub mod via;
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use pic::PIC;
+use rtc::RTC;
 use uart::UART;
+use via::{Pins, VIA};
+
+use super::config::Config;
+use super::device::BusDevice;
+
+/// A device in the IO page and the registers it owns
+pub struct IoDevice {
+    pub name: String,
+    pub registers: RangeInclusive<u8>,
+    pub device: Box<dyn BusDevice>,
+    /// Input of the interrupt controller the IRQ output of the device goes to, or `None` if it
+    /// goes straight to the CPU
+    pub irq: Option<u8>,
+}
 
+/// The devices in the 256 byte IO page. Each one owns a range of registers and sees the register
+/// relative to the start of its range as offset. Registers no device owns are open bus.
 pub struct IO {
-    uart: UART
+    devices: Vec<IoDevice>,
+    interrupt_controller: Option<PIC>,
+    /// Pins of the VIA, which outlive it being boxed up as a `BusDevice`
+    via_pins: Option<Rc<RefCell<Pins>>>,
 }
 
 impl IO {
-    pub fn new() -> Self {
-        Self { uart: UART::new() }
+    /// The CATE-16 IO page, see the README
+    pub fn cate16(config: &Config) -> Result<Self, String> {
+        let mut rtc = RTC::new(config.rtc);
+        if let Some(path) = &config.rtc_nvram {
+            rtc = rtc.with_nvram_file(path.clone())?;
+        }
+
+        let via = VIA::new();
+        let mut io = IO::empty();
+        io.via_pins = Some(via.pins());
+        io.register("VIA", 0x00..=0x0F, Box::new(via))?;
+        io.register("UART", 0x10..=0x17, Box::new(UART::new()))?;
+        io.set_interrupt_controller(0x20..=0x23, PIC::new())?;
+        io.register("RTC", 0x30..=0x43, Box::new(rtc))?;
+        io.connect_irq("VIA", 0)?;
+        io.connect_irq("UART", 1)?;
+        io.connect_irq("RTC", 2)?;
+        Ok(io)
+    }
+
+    /// An IO page with nothing in it
+    pub fn empty() -> Self {
+        IO { devices: Vec::new(), interrupt_controller: None, via_pins: None }
     }
 
-    pub fn cycle(&mut self) {
-        self.uart.cycle();
+    /// Gives `registers` to a device, they can't overlap the registers of another device
+    pub fn register(&mut self, name: &str, registers: RangeInclusive<u8>,
+        device: Box<dyn BusDevice>) -> Result<(), String> {
+        if registers.is_empty() {
+            return Err(format!("{} doesn't have any registers", name));
+        }
+        if let Some(other) = self.devices.iter().find(|other|
+            *registers.start() <= *other.registers.end() &&
+            *other.registers.start() <= *registers.end()) {
+            return Err(format!("{} can't have registers {:02X}-{:02X}, {} has {:02X}-{:02X}",
+                name, registers.start(), registers.end(),
+                other.name, other.registers.start(), other.registers.end()));
+        }
+        self.devices.push(IoDevice { name: name.to_string(), registers, device, irq: None });
+        Ok(())
     }
 
-    pub fn read(&mut self, addr: u8) -> u8 {
-        match addr {
-            0x00..=0x0F => todo!(),
-            0x10..=0x18 => self.uart.read(addr & 0x07),
-            0x19..=0xFF => todo!(),
+    /// Registers the interrupt controller, which devices can then be connected to
+    pub fn set_interrupt_controller(&mut self, registers: RangeInclusive<u8>, pic: PIC)
+        -> Result<(), String> {
+        if self.interrupt_controller.is_some() {
+            return Err("there already is an interrupt controller".to_string());
         }
+        self.register("PIC", registers, Box::new(pic.clone()))?;
+        self.interrupt_controller = Some(pic);
+        Ok(())
     }
 
-    pub fn write(&mut self, addr: u8, value: u8) {
-        match addr {
-            0x00..=0x0F => print!("[IO] {:02X}: {:02X} [/IO]", addr, value),
-            0x10..=0x18 => self.uart.write(addr & 0x07, value),
-            0x19..=0xFF => todo!(),
+    /// Connects the IRQ output of a device to an input of the interrupt controller
+    pub fn connect_irq(&mut self, name: &str, input: u8) -> Result<(), String> {
+        if self.interrupt_controller.is_none() {
+            return Err(format!("{} can't be connected without an interrupt controller", name));
+        }
+        if input > 7 {
+            return Err(format!("the interrupt controller has no input {}", input));
         }
+        if let Some(other) = self.devices.iter().find(|device| device.irq == Some(input)) {
+            return Err(format!("input {} is already connected to {}", input, other.name));
+        }
+        let device = self.devices.iter_mut()
+            .find(|device| device.name == name)
+            .ok_or_else(|| format!("there is no {}", name))?;
+        device.irq = Some(input);
+        Ok(())
+    }
+
+    pub fn interrupt_controller(&self) -> Option<PIC> {
+        self.interrupt_controller.clone()
     }
-}
\ No newline at end of file
+
+    /// The pins of the VIA, for attaching peripherals to them
+    pub fn via_pins(&self) -> Option<Rc<RefCell<Pins>>> {
+        self.via_pins.clone()
+    }
+
+    pub fn into_devices(self) -> Vec<IoDevice> {
+        self.devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::bus::{Bus, SystemBus};
+    use super::super::device::Ram;
+
+    #[test]
+    fn register_overlaps() {
+        let mut io = IO::empty();
+        io.register("A", 0x00..=0x0F, Box::new(Ram::new(0x10))).unwrap();
+        io.register("B", 0x10..=0x17, Box::new(Ram::new(0x08))).unwrap();
+        assert_eq!(io.register("C", 0x0F..=0x10, Box::new(Ram::new(0x02))),
+            Err("C can't have registers 0F-10, A has 00-0F".to_string()));
+        assert_eq!(io.register("C", 0x14..=0x14, Box::new(Ram::new(0x01))),
+            Err("C can't have registers 14-14, B has 10-17".to_string()));
+        assert_eq!(io.register("C", RangeInclusive::new(0x20, 0x1F), Box::new(Ram::new(0x01))),
+            Err("C doesn't have any registers".to_string()));
+        io.register("C", 0x18..=0x18, Box::new(Ram::new(0x01))).unwrap();
+
+        let names: Vec<_> = io.into_devices().into_iter().map(|device| device.name).collect();
+        assert_eq!(names, ["A", "B", "C"]);
+    }
+
+    #[test]
+    fn free_registers_are_open_bus() {
+        let mut io = IO::empty();
+        io.register("RAM", 0x10..=0x13, Box::new(Ram::new(0x04))).unwrap();
+        let config = Config { roms: Vec::new(), ..Config::default() };
+        let mut bus = Bus::cate16(&config, io).unwrap();
+
+        // the device sees its registers from offset 0, in every bank of the IO page
+        bus.write(0x00, 0x7F10, 0x12).unwrap();
+        assert_eq!(bus.read(0x05, 0x7F10), Ok(0x12));
+
+        // free registers read back the last value on the data bus and ignore writes
+        bus.write(0x00, 0x0000, 0x34).unwrap();
+        assert_eq!(bus.read(0x00, 0x7F00), Ok(0x34));
+        assert_eq!(bus.read(0x00, 0x7F14), Ok(0x34));
+        assert_eq!(bus.write(0x00, 0x7FFF, 0x56), Ok(()));
+        assert_eq!(bus.read(0x00, 0x7F10), Ok(0x12));
+        assert_eq!(bus.read(0x00, 0x7FFF), Ok(0x12));
+        assert_eq!(bus.io_map().map(|(registers, name)| (registers.clone(), name))
+            .collect::<Vec<_>>(), [(0x10..=0x13, "RAM")]);
+    }
+}
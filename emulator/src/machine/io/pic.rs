@@ -0,0 +1,88 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::super::device::BusDevice;
+use super::super::fault::Fault;
+
+/// Inputs that are set and enabled
+const PENDING: u32 = 0x0;
+/// 1 enables an input, all inputs are masked after reset
+const MASK: u32 = 0x1;
+/// Levels of the inputs, enabled or not
+const INPUTS: u32 = 0x2;
+
+/// The PRI_IRQ vector tables in bank 0, one vector per input
+const NATIVE_VECTORS: u16 = 0xFFC0;
+const EMULATION_VECTORS: u16 = 0xFFD0;
+
+/// Priority interrupt controller with 8 inputs, input 0 has the highest priority.
+///
+/// Devices pull their input like they would pull the IRQ line, the controller pulls IRQ while an
+/// enabled input is set. When the CPU takes the IRQ, it reads its vector from the PRI_IRQ table
+/// entry of the pending input with the highest priority instead of the usual IRQ vector.
+///
+/// The bus drives the inputs, it keeps a handle to the controller for that.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Default)]
+pub struct PIC {
+    inputs: Rc<Cell<u8>>,
+    mask: Rc<Cell<u8>>,
+}
+
+impl PIC {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the levels of all inputs, bit n is input n
+    pub fn set_inputs(&self, levels: u8) {
+        self.inputs.set(levels);
+    }
+
+    fn pending(&self) -> u8 {
+        self.inputs.get() & self.mask.get()
+    }
+
+    /// The pending input with the highest priority
+    fn active(&self) -> Option<u8> {
+        let pending = self.pending();
+        (pending != 0).then(|| pending.trailing_zeros() as u8)
+    }
+
+    /// Address of the vector the CPU takes the IRQ through, `None` if no input is pending
+    pub fn vector(&self, emulation: bool) -> Option<u16> {
+        let table = if emulation { EMULATION_VECTORS } else { NATIVE_VECTORS };
+        self.active().map(|input| table + 2 * input as u16)
+    }
+}
+
+/// The 4 registers of the controller, `offset` is the register
+impl BusDevice for PIC {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        Ok(match offset & 0x03 {
+            PENDING => self.pending(),
+            MASK => self.mask.get(),
+            INPUTS => self.inputs.get(),
+            // $3 is the number of the pending input with the highest priority, $FF if none is
+            _ => self.active().unwrap_or(0xFF),
+        })
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        match offset & 0x03 {
+            MASK => {
+                self.mask.set(value);
+                Ok(())
+            }
+            reg => Err(Fault::Device(format!("PIC register {} is read only", reg))),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.mask.set(0);
+    }
+
+    fn irq(&self) -> bool {
+        self.pending() != 0
+    }
+}
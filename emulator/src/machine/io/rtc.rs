@@ -0,0 +1,480 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::super::CPU_CLOCK;
+use super::super::config::RtcStart;
+use super::super::device::BusDevice;
+use super::super::fault::Fault;
+
+const SECONDS: u32 = 0x00;
+const CENTURY: u32 = 0x07;
+const ALARM_SECONDS: u32 = 0x08;
+const ALARM_DAY_DATE: u32 = 0x0B;
+const WATCHDOG_HUNDREDTHS: u32 = 0x0C;
+const WATCHDOG_SECONDS: u32 = 0x0D;
+const CONTROL_A: u32 = 0x0E;
+const CONTROL_B: u32 = 0x0F;
+const NVRAM_ADDRESS: u32 = 0x10;
+const NVRAM_DATA: u32 = 0x13;
+
+/// Control A flags
+const IRQF: u8 = 0x01;
+const TDF: u8 = 0x08;
+/// Control A bits the program can write, PRS and PAB
+const CONTROL_A_WRITABLE: u8 = 0x30;
+
+/// Control B bits
+const TE: u8 = 0x80;
+const BME: u8 = 0x20;
+const TIE: u8 = 0x08;
+
+/// Alarm registers ignore their field when this is set
+const ALARM_MASK: u8 = 0x80;
+/// Alarm day/date register compares the day of the week instead of the date
+const ALARM_DAY: u8 = 0x40;
+
+const NVRAM_SIZE: usize = 256;
+
+/// How often a clock following host time looks at it, in cycles
+const HOST_POLL: u64 = CPU_CLOCK / 100;
+
+/// Real-time clock with the register set of a DS1511: BCD time and date, a time of day alarm and
+/// 256 bytes of NVRAM behind an address and a data register.
+///
+/// | Register | Contents                                                                    |
+/// |----------|-----------------------------------------------------------------------------|
+/// | $00-$07  | Seconds, minutes, hours (24h), day of the week (1-7, Sunday is 1), date,    |
+/// |          | month, year and century, all BCD                                            |
+/// | $08-$0B  | Alarm seconds, minutes, hours and day/date. Bit 7 set ignores the field,    |
+/// |          | bit 6 of day/date compares the day of the week instead of the date.         |
+/// | $0C-$0D  | Watchdog, stored but not emulated                                           |
+/// | $0E      | Control A: TDF (bit 3) is set when the alarm matches, IRQF (bit 0) while an |
+/// |          | enabled interrupt is pending. Reading it clears the flags.                  |
+/// | $0F      | Control B: TE (bit 7) clear freezes the time registers, so they can be read |
+/// |          | or written consistently, the time written is taken over when it's set      |
+/// |          | again. BME (bit 5) increments the NVRAM address on data accesses, TIE       |
+/// |          | (bit 3) enables the alarm interrupt.                                        |
+/// | $10      | NVRAM address                                                               |
+/// | $13      | NVRAM data                                                                  |
+///
+/// The time is UTC. It follows host time, or starts at a pinned time and advances with emulated
+/// cycles. Setting the time only offsets it from that source. The day of the week is worked out
+/// from the date, writes to it are ignored.
+#[allow(clippy::upper_case_acronyms)]
+pub struct RTC {
+    start: RtcStart,
+    /// Cycles run, the pinned clock advances with them
+    cycles: u64,
+    /// Seconds the time that was set is ahead of the source
+    offset: i64,
+    /// Time registers while TE is clear, and whether they were written meanwhile
+    frozen: Option<([u8; 8], bool)>,
+    alarm: [u8; 4],
+    watchdog: [u8; 2],
+    control_a: u8,
+    control_b: u8,
+    nvram: Vec<u8>,
+    nvram_address: u8,
+    /// Host file the NVRAM is kept in
+    nvram_path: Option<PathBuf>,
+    nvram_dirty: bool,
+    /// Time the alarm was last checked at
+    last_checked: i64,
+    /// Cycles until a clock following host time looks at it again
+    poll: u64,
+}
+
+impl RTC {
+    pub fn new(start: RtcStart) -> Self {
+        let mut rtc = RTC {
+            start,
+            cycles: 0,
+            offset: 0,
+            frozen: None,
+            alarm: [0; 4],
+            watchdog: [0; 2],
+            control_a: 0,
+            control_b: TE,
+            nvram: vec![0; NVRAM_SIZE],
+            nvram_address: 0,
+            nvram_path: None,
+            nvram_dirty: false,
+            last_checked: 0,
+            poll: HOST_POLL,
+        };
+        rtc.last_checked = rtc.now();
+        rtc
+    }
+
+    /// Keeps the NVRAM in `path`, loading it now and saving it on flush. A missing file is
+    /// zeroed NVRAM.
+    pub fn with_nvram_file(mut self, path: PathBuf) -> Result<Self, String> {
+        match fs::read(&path) {
+            Ok(data) if data.len() == NVRAM_SIZE => self.nvram = data,
+            Ok(data) => return Err(format!("{}: RTC NVRAM has to be {} bytes, not {}",
+                path.display(), NVRAM_SIZE, data.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+        self.nvram_path = Some(path);
+        Ok(self)
+    }
+
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    fn now(&self) -> i64 {
+        let source = match self.start {
+            RtcStart::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs() as i64),
+            RtcStart::Pinned(start) => start + (self.cycles / CPU_CLOCK) as i64,
+        };
+        source + self.offset
+    }
+
+    fn set_time(&mut self, registers: [u8; 8]) {
+        let [seconds, minutes, hours, _, date, month, year, century] = registers.map(from_bcd);
+        let days = days_from_civil(century as i64 * 100 + year as i64, month as u32, date as u32);
+        let time = days * 86400 + hours as i64 * 3600 + minutes as i64 * 60 + seconds as i64;
+        self.offset += time - self.now();
+        self.last_checked = self.now();
+    }
+
+    fn check_alarm(&mut self) {
+        let now = self.now();
+        if now == self.last_checked {
+            return;
+        }
+        self.last_checked = now;
+        let time = time_registers(now);
+        let matches = |alarm: u8, value: u8| alarm & ALARM_MASK != 0 || alarm == value;
+        let day_date = self.alarm[3];
+        let day_date_matches = if day_date & ALARM_DAY != 0 {
+            matches(day_date & !ALARM_DAY, time[3])
+        } else {
+            matches(day_date, time[4])
+        };
+        if matches(self.alarm[0], time[0]) && matches(self.alarm[1], time[1]) &&
+            matches(self.alarm[2], time[2]) && day_date_matches {
+            self.control_a |= TDF;
+        }
+    }
+
+    fn nvram_access(&mut self) -> usize {
+        let address = self.nvram_address as usize;
+        if self.control_b & BME != 0 {
+            self.nvram_address = self.nvram_address.wrapping_add(1);
+        }
+        address
+    }
+}
+
+/// The 20 registers of the RTC, `offset` is the register
+impl BusDevice for RTC {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        self.check_alarm();
+        Ok(match offset {
+            SECONDS..=CENTURY => match self.frozen {
+                Some((registers, _)) => registers[offset as usize],
+                None => time_registers(self.now())[offset as usize],
+            },
+            ALARM_SECONDS..=ALARM_DAY_DATE => self.alarm[(offset - ALARM_SECONDS) as usize],
+            WATCHDOG_HUNDREDTHS | WATCHDOG_SECONDS => {
+                self.watchdog[(offset - WATCHDOG_HUNDREDTHS) as usize]
+            }
+            CONTROL_A => {
+                let value = self.control_a | if self.irq() { IRQF } else { 0 };
+                self.control_a &= CONTROL_A_WRITABLE;
+                value
+            }
+            CONTROL_B => self.control_b,
+            NVRAM_ADDRESS => self.nvram_address,
+            NVRAM_DATA => {
+                let address = self.nvram_access();
+                self.nvram[address]
+            }
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        self.check_alarm();
+        match offset {
+            SECONDS..=CENTURY => match self.frozen.as_mut() {
+                Some((registers, written)) => {
+                    registers[offset as usize] = value;
+                    *written = true;
+                }
+                None => {
+                    let mut registers = time_registers(self.now());
+                    registers[offset as usize] = value;
+                    self.set_time(registers);
+                }
+            },
+            ALARM_SECONDS..=ALARM_DAY_DATE => self.alarm[(offset - ALARM_SECONDS) as usize] = value,
+            WATCHDOG_HUNDREDTHS | WATCHDOG_SECONDS => {
+                self.watchdog[(offset - WATCHDOG_HUNDREDTHS) as usize] = value;
+            }
+            CONTROL_A => {
+                self.control_a = (self.control_a & !CONTROL_A_WRITABLE) |
+                    (value & CONTROL_A_WRITABLE);
+            }
+            CONTROL_B => {
+                if value & TE == 0 && self.frozen.is_none() {
+                    self.frozen = Some((time_registers(self.now()), false));
+                } else if value & TE != 0 {
+                    if let Some((registers, true)) = self.frozen.take() {
+                        self.set_time(registers);
+                    }
+                    self.frozen = None;
+                }
+                self.control_b = value;
+            }
+            NVRAM_ADDRESS => self.nvram_address = value,
+            NVRAM_DATA => {
+                let address = self.nvram_access();
+                self.nvram[address] = value;
+                self.nvram_dirty = true;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        match self.start {
+            RtcStart::Pinned(_) => self.check_alarm(),
+            // looking at the host clock after every instruction would slow everything down
+            RtcStart::Host => {
+                self.poll = self.poll.saturating_sub(cycles);
+                if self.poll == 0 {
+                    self.poll = HOST_POLL;
+                    self.check_alarm();
+                }
+            }
+        }
+    }
+
+    fn next_event(&self) -> u64 {
+        match self.start {
+            RtcStart::Pinned(_) => CPU_CLOCK - self.cycles % CPU_CLOCK,
+            RtcStart::Host => self.poll,
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.control_a & TDF != 0 && self.control_b & TIE != 0
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if let Some(path) = self.nvram_path.as_ref().filter(|_| self.nvram_dirty) {
+            fs::write(path, &self.nvram).map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.nvram_dirty = false;
+        }
+        Ok(())
+    }
+}
+
+fn to_bcd(n: u8) -> u8 {
+    ((n / 10) << 4) | (n % 10)
+}
+
+fn from_bcd(bcd: u8) -> u8 {
+    (bcd >> 4) * 10 + (bcd & 0x0F)
+}
+
+/// The time registers $00-$07 for a time in seconds since 1970
+fn time_registers(time: i64) -> [u8; 8] {
+    let days = time.div_euclid(86400);
+    let seconds = time.rem_euclid(86400);
+    let (year, month, date) = civil_from_days(days);
+    // 1970-01-01 was a Thursday, day 5 when Sunday is 1
+    let day_of_week = (days + 4).rem_euclid(7) + 1;
+    [
+        seconds % 60,
+        seconds / 60 % 60,
+        seconds / 3600,
+        day_of_week,
+        date as i64,
+        month as i64,
+        year.rem_euclid(100),
+        year.div_euclid(100),
+    ].map(|field| to_bcd(field as u8))
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+pub fn days_from_civil(year: i64, month: u32, date: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 +
+        date as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Number of days in `month` (1-12) of `year`
+pub fn days_in_month(year: i64, month: u32) -> u32 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Year, month and date of a number of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+        day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let date = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An RTC starting at `time`, advancing with the cycles run
+    fn pinned(time: &str) -> RTC {
+        RTC::new(time.parse().unwrap())
+    }
+
+    fn time(rtc: &mut RTC) -> [u8; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|register| rtc.read(register).unwrap())
+    }
+
+    /// Runs for `seconds` seconds, in the steps the bus would take
+    fn run(rtc: &mut RTC, seconds: u64) {
+        let mut cycles = seconds * CPU_CLOCK;
+        while cycles > 0 {
+            let step = rtc.next_event().min(cycles);
+            rtc.tick(step);
+            cycles -= step;
+        }
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(to_bcd(0), 0x00);
+        assert_eq!(to_bcd(9), 0x09);
+        assert_eq!(to_bcd(10), 0x10);
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(to_bcd(99), 0x99);
+        assert!((0..100).all(|n| from_bcd(to_bcd(n)) == n));
+
+        // a Thursday
+        let mut rtc = pinned("2024-02-29T13:05:09");
+        assert_eq!(time(&mut rtc), [0x09, 0x05, 0x13, 0x05, 0x29, 0x02, 0x24, 0x20]);
+        let mut rtc = pinned("1970-01-01");
+        assert_eq!(time(&mut rtc), [0x00, 0x00, 0x00, 0x05, 0x01, 0x01, 0x70, 0x19]);
+    }
+
+    #[test]
+    fn rollover() {
+        let mut rtc = pinned("2024-02-29T23:59:58");
+        run(&mut rtc, 1);
+        assert_eq!(time(&mut rtc), [0x59, 0x59, 0x23, 0x05, 0x29, 0x02, 0x24, 0x20]);
+        run(&mut rtc, 1);
+        assert_eq!(time(&mut rtc), [0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x24, 0x20]);
+
+        let mut rtc = pinned("2099-12-31T23:59:59");
+        run(&mut rtc, 1);
+        assert_eq!(time(&mut rtc), [0x00, 0x00, 0x00, 0x06, 0x01, 0x01, 0x00, 0x21]);
+    }
+
+    #[test]
+    fn set_time() {
+        let mut rtc = pinned("2024-02-28T23:59:58");
+        // without freezing, each write is taken over right away
+        rtc.write(0x06, 0x25).unwrap();
+        assert_eq!(time(&mut rtc), [0x58, 0x59, 0x23, 0x06, 0x28, 0x02, 0x25, 0x20]);
+        run(&mut rtc, 2);
+        assert_eq!(time(&mut rtc), [0x00, 0x00, 0x00, 0x07, 0x01, 0x03, 0x25, 0x20]);
+    }
+
+    #[test]
+    fn freeze() {
+        let mut rtc = pinned("2024-02-29T23:59:58");
+        rtc.write(CONTROL_B, 0x00).unwrap();
+        run(&mut rtc, 5);
+        assert_eq!(rtc.read(SECONDS), Ok(0x58));
+        // without writes the registers catch up with the time when TE is set again
+        rtc.write(CONTROL_B, TE).unwrap();
+        assert_eq!(time(&mut rtc), [0x03, 0x00, 0x00, 0x06, 0x01, 0x03, 0x24, 0x20]);
+
+        // the time written while frozen is taken over when TE is set
+        rtc.write(CONTROL_B, 0x00).unwrap();
+        let time_written = [0x30, 0x15, 0x08, 0x01, 0x10, 0x07, 0x30, 0x20];
+        for (register, value) in time_written.iter().enumerate() {
+            rtc.write(register as u32, *value).unwrap();
+        }
+        run(&mut rtc, 5);
+        assert_eq!(rtc.read(SECONDS), Ok(0x30));
+        rtc.write(CONTROL_B, TE).unwrap();
+        // the day of the week is worked out from the date
+        assert_eq!(time(&mut rtc), [0x30, 0x15, 0x08, 0x04, 0x10, 0x07, 0x30, 0x20]);
+        run(&mut rtc, 1);
+        assert_eq!(rtc.read(SECONDS), Ok(0x31));
+    }
+
+    #[test]
+    fn alarm() {
+        let mut rtc = pinned("2024-02-29T23:59:58");
+        // every day at 00:00:01
+        for (register, value) in [0x01, 0x00, 0x00, ALARM_MASK].iter().enumerate() {
+            rtc.write(ALARM_SECONDS + register as u32, *value).unwrap();
+        }
+        run(&mut rtc, 2);
+        assert_eq!(rtc.read(CONTROL_A), Ok(0x00));
+        run(&mut rtc, 1);
+        assert!(!rtc.irq());
+        assert_eq!(rtc.read(CONTROL_A), Ok(TDF));
+        // reading control A cleared it
+        assert_eq!(rtc.read(CONTROL_A), Ok(0x00));
+
+        // Saturdays at 00:00:01, with the interrupt enabled
+        rtc.write(ALARM_DAY_DATE, ALARM_DAY | 0x07).unwrap();
+        rtc.write(CONTROL_B, TE | TIE).unwrap();
+        run(&mut rtc, 86399);
+        assert!(!rtc.irq());
+        run(&mut rtc, 1);
+        assert!(rtc.irq());
+        assert_eq!(rtc.read(CONTROL_A), Ok(TDF | IRQF));
+        assert!(!rtc.irq());
+
+        // the 4th of the month, it's the 2nd now
+        rtc.write(ALARM_DAY_DATE, 0x04).unwrap();
+        run(&mut rtc, 86400);
+        assert!(!rtc.irq());
+        run(&mut rtc, 86400);
+        assert!(rtc.irq());
+    }
+
+    #[test]
+    fn nvram() {
+        let mut rtc = pinned("2024-02-29");
+        rtc.write(NVRAM_ADDRESS, 0xFF).unwrap();
+        rtc.write(CONTROL_B, TE | BME).unwrap();
+        rtc.write(NVRAM_DATA, 0x11).unwrap();
+        rtc.write(NVRAM_DATA, 0x22).unwrap();
+        assert_eq!(rtc.read(NVRAM_ADDRESS), Ok(0x01));
+        rtc.write(CONTROL_B, TE).unwrap();
+        rtc.write(NVRAM_ADDRESS, 0x00).unwrap();
+        assert_eq!(rtc.read(NVRAM_DATA), Ok(0x22));
+        assert_eq!(rtc.read(NVRAM_DATA), Ok(0x22));
+        rtc.write(NVRAM_ADDRESS, 0xFF).unwrap();
+        assert_eq!(rtc.read(NVRAM_DATA), Ok(0x11));
+    }
+}
@@ -2,15 +2,16 @@ use super::super::super::terminal::*;
 
 use std::io::{Read, Write};
 
+use super::super::CPU_CLOCK;
+use super::super::device::BusDevice;
+use super::super::fault::Fault;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct UART {
     term: Terminal,
     ier: u8,
     fcr: u8,
-    isr: u8,
     lcr: u8,
-    mcr: u8,
-    msr: u8,
-    spr: u8,
     brg: u16,
 
     tx_fifo: [u8;16],
@@ -21,17 +22,19 @@ pub struct UART {
     cycles: u64,
 }
 
+impl Default for UART {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UART {
     pub fn new() -> Self {
         Self { 
             term: Terminal::new(),
             ier: 0x00,
             fcr: 0x00,
-            isr: 0x01,
             lcr: 0x00,
-            mcr: 0x00,
-            msr: 0x00,
-            spr: 0xFF,
             brg: 0x0000,
             tx_fifo: [0;16],
             rx_fifo: [0;16],
@@ -41,10 +44,21 @@ impl UART {
         }
     }
 
+    /// Back to the register values after power on, anything still in the FIFOs is lost
+    pub fn reset(&mut self) {
+        self.ier = 0x00;
+        self.fcr = 0x00;
+        self.lcr = 0x00;
+        self.brg = 0x0000;
+        self.tx_count = 0;
+        self.rx_count = 0;
+        self.cycles = 0;
+    }
+
     pub fn handle_term(&mut self) -> bool {
         (
             if self.tx_count > 0 {
-                self.term.write(&[self.tx_fifo[0]]).unwrap();
+                self.term.write_all(&[self.tx_fifo[0]]).unwrap();
                 self.term.flush().unwrap();
                 for i in 1..self.tx_count as usize {
                     self.tx_fifo[i - 1] = self.tx_fifo[i];
@@ -68,24 +82,48 @@ impl UART {
         )
     }
 
-    pub fn cycle(&mut self) {
-        self.cycles = self.cycles.wrapping_add(1);
-        let cycles_between_char = ((25_175_000f64 / 8.0) / (1_843_200f64 / self.brg as f64 / 16.0) * 8.0) as u64;
-        if self.cycles >= cycles_between_char {
+    fn cycles_between_char(&self) -> u64 {
+        (CPU_CLOCK as f64 / (1_843_200f64 / self.brg as f64 / 16.0) * 8.0) as u64
+    }
+
+    /// Advances the UART by `cycles` at once. The terminal is serviced at most once, so this
+    /// should not skip past `next_event`. Nothing moves while the baud rate generator is off.
+    pub fn idle(&mut self, cycles: u64) {
+        if self.brg == 0 {
+            return;
+        }
+        self.cycles = self.cycles.wrapping_add(cycles);
+        if self.cycles >= self.cycles_between_char() {
             self.cycles = 0;
             self.handle_term();
         }
     }
 
-    pub fn read(&mut self, addr: u8) -> u8 {
+    /// Number of cycles until the terminal is serviced next. `u64::MAX` while the baud rate
+    /// generator is off, or when there's nothing to send and received data couldn't raise an
+    /// interrupt anyway.
+    pub fn next_event(&self) -> u64 {
+        let receiving = (self.ier & 0x01) != 0 && self.rx_count < 16;
+        if self.brg == 0 || (self.tx_count == 0 && !receiving) {
+            return u64::MAX;
+        }
+        self.cycles_between_char().saturating_sub(self.cycles).max(1)
+    }
+
+    pub fn irq(&self) -> bool {
+        ((self.ier & 0x01) != 0 && self.rx_count > 0) ||
+        ((self.ier & 0x02) != 0 && self.tx_count == 0)
+    }
+
+    pub fn read(&mut self, addr: u8) -> Result<u8, Fault> {
         match addr {
             0x00 => {
                 if (self.lcr & 0x80) != 0 {
                     // BRG
-                    todo!("BRG read");
+                    Ok(self.brg as u8)
                 } else {
                     if self.rx_count == 0 {
-                        panic!("UART empty")
+                        return Err(Fault::Device("UART receive FIFO read while empty".to_string()));
                     }
                     let val = self.rx_fifo[0];
                     for i in 1..self.rx_count as usize {
@@ -93,10 +131,28 @@ impl UART {
                     }
                     self.rx_count -= 1;
 
-                    val
+                    Ok(val)
                 }
             }
-            0x03 => self.lcr,
+            0x01 => {
+                if (self.lcr & 0x80) != 0 {
+                    // BRG
+                    Ok((self.brg >> 8) as u8)
+                } else {
+                    Ok(self.ier)
+                }
+            }
+            0x02 => {
+                // ISR, the FIFO enable bits are always set
+                if (self.ier & 0x01) != 0 && self.rx_count > 0 {
+                    Ok(0xC4)    // receive data available
+                } else if (self.ier & 0x02) != 0 && self.tx_count == 0 {
+                    Ok(0xC2)    // transmit holding register empty
+                } else {
+                    Ok(0xC1)    // no interrupt pending
+                }
+            }
+            0x03 => Ok(self.lcr),
             0x05 => {
                 let mut val = 0u8;
                 // bit 0: any data in RX
@@ -113,13 +169,13 @@ impl UART {
                 }
                 // bit 7: error (not possible rn)
 
-                val
+                Ok(val)
             }
-            _ => todo!("r {:02}", addr),
+            _ => Err(Fault::Device(format!("UART register {} read not emulated", addr))),
         }
     }
 
-    pub fn write(&mut self, addr: u8, value: u8) {
+    pub fn write(&mut self, addr: u8, value: u8) -> Result<(), Fault> {
         match addr {
             0x00 => {
                 if (self.lcr & 0x80) != 0 {
@@ -129,7 +185,7 @@ impl UART {
                     println!("[UART] BAUDRATE: {} BAUD", (1_843_200f64 / self.brg as f64) / 16f64);
                 } else {
                     if self.tx_count == 16 {
-                        panic!("UART filled too far")
+                        return Err(Fault::Device("UART transmit FIFO overrun".to_string()));
                     }
                     self.tx_fifo[self.tx_count as usize] = value;
                     self.tx_count += 1;
@@ -142,8 +198,8 @@ impl UART {
                     self.brg |= (value as u16) << 8;
                     println!("[UART] BAUDRATE: {} BAUD", (1_843_200f64 / self.brg as f64) / 16f64);
                 } else {
-                    // IER
-                    todo!("IER");
+                    // IER, only the receive (bit 0) and transmit (bit 1) interrupts are emulated
+                    self.ier = value & 0x0F;
                 }
             }
             0x02 => {
@@ -154,8 +210,36 @@ impl UART {
                 self.lcr = value;
                 println!("[UART] LCR: {:02X}", self.lcr);
             },
-            _ => todo!("w {:02}", addr),
+            _ => return Err(Fault::Device(format!("UART register {} write not emulated", addr))),
         }
+        Ok(())
+    }
+}
+
+/// The 8 registers of the UART, `offset` is the register
+impl BusDevice for UART {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        UART::read(self, offset as u8)
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        UART::write(self, offset as u8, value)
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        self.idle(cycles);
+    }
+
+    fn reset(&mut self) {
+        UART::reset(self);
+    }
+
+    fn next_event(&self) -> u64 {
+        UART::next_event(self)
+    }
+
+    fn irq(&self) -> bool {
+        UART::irq(self)
     }
 }
 
@@ -0,0 +1,898 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::super::device::BusDevice;
+use super::super::fault::Fault;
+
+const ORB: u32 = 0x0;
+const ORA: u32 = 0x1;
+const DDRB: u32 = 0x2;
+const DDRA: u32 = 0x3;
+const T1C_L: u32 = 0x4;
+const T1C_H: u32 = 0x5;
+const T1L_L: u32 = 0x6;
+const T1L_H: u32 = 0x7;
+const T2C_L: u32 = 0x8;
+const T2C_H: u32 = 0x9;
+const SR: u32 = 0xA;
+const ACR: u32 = 0xB;
+const PCR: u32 = 0xC;
+const IFR: u32 = 0xD;
+const IER: u32 = 0xE;
+
+/// Interrupt flags, the same bits are used in IER
+const IRQ_CA2: u8 = 0x01;
+const IRQ_CA1: u8 = 0x02;
+const IRQ_SR: u8 = 0x04;
+const IRQ_CB2: u8 = 0x08;
+const IRQ_CB1: u8 = 0x10;
+const IRQ_T2: u8 = 0x20;
+const IRQ_T1: u8 = 0x40;
+
+/// The pins of a VIA, shared with whatever is attached to them.
+///
+/// Peripherals drive the inputs and read the outputs whenever they like. The VIA looks at the
+/// inputs on every register access and after every instruction, edges shorter than that are lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pins {
+    /// Levels driven onto the port pins from outside, the VIA only sees them on its inputs.
+    /// Nothing attached reads as high, like the pull-ups on the real chip.
+    pub pa_in: u8,
+    pub pb_in: u8,
+    pub ca1: bool,
+    pub ca2_in: bool,
+    pub cb1_in: bool,
+    pub cb2_in: bool,
+
+    /// Levels of the port pins as the VIA drives them, and which pins it drives
+    pub pa_out: u8,
+    pub pa_driven: u8,
+    pub pb_out: u8,
+    pub pb_driven: u8,
+    /// Levels of the control lines, `None` while they're inputs
+    pub ca2_out: Option<bool>,
+    pub cb1_out: Option<bool>,
+    pub cb2_out: Option<bool>,
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Pins {
+            pa_in: 0xFF,
+            pb_in: 0xFF,
+            ca1: true,
+            ca2_in: true,
+            cb1_in: true,
+            cb2_in: true,
+            pa_out: 0,
+            pa_driven: 0,
+            pb_out: 0,
+            pb_driven: 0,
+            ca2_out: None,
+            cb1_out: None,
+            cb2_out: None,
+        }
+    }
+}
+
+/// W65C22 versatile interface adapter: two 8-bit ports with handshake lines, two 16-bit timers
+/// and a shift register.
+#[allow(clippy::upper_case_acronyms)]
+pub struct VIA {
+    pins: Rc<RefCell<Pins>>,
+
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    /// Port inputs as latched by CA1 and CB1, when latching is enabled in ACR
+    ira_latch: u8,
+    irb_latch: u8,
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+
+    t1: u16,
+    t1_latch: u16,
+    /// The cycle after T1 reaches 0, where it reads $FFFF before being reloaded
+    t1_reload: bool,
+    /// Interrupts at the next time out, one-shot mode only does so once per write to T1C-H
+    t1_armed: bool,
+    /// PB7 as driven by T1
+    pb7: bool,
+
+    t2: u16,
+    /// Only the low byte of the latch is a register, the high byte is written straight to T2
+    t2_latch: u8,
+    t2_armed: bool,
+
+    sr: u8,
+    /// Bits shifted since the shift register was last accessed
+    sr_bits: u8,
+    sr_running: bool,
+    /// Cycles until the next edge of the shift clock, when it's clocked internally
+    sr_clock: u64,
+    /// Shift clock as driven on CB1
+    sr_cb1: bool,
+
+    ca2: bool,
+    cb2: bool,
+    /// CA2 or CB2 are low for a single cycle in pulse output mode
+    ca2_pulse: bool,
+    cb2_pulse: bool,
+
+    /// Input levels at the last look, to find edges
+    last_ca1: bool,
+    last_ca2: bool,
+    last_cb1: bool,
+    last_cb2: bool,
+    last_pb6: bool,
+}
+
+impl Default for VIA {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VIA {
+    pub fn new() -> Self {
+        let mut via = VIA {
+            pins: Rc::new(RefCell::new(Pins::default())),
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            ira_latch: 0,
+            irb_latch: 0,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+            t1: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_reload: false,
+            t1_armed: false,
+            pb7: true,
+            t2: 0xFFFF,
+            t2_latch: 0xFF,
+            t2_armed: false,
+            sr: 0,
+            sr_bits: 0,
+            sr_running: false,
+            sr_clock: 0,
+            sr_cb1: true,
+            ca2: true,
+            cb2: true,
+            ca2_pulse: false,
+            cb2_pulse: false,
+            last_ca1: true,
+            last_ca2: true,
+            last_cb1: true,
+            last_cb2: true,
+            last_pb6: true,
+        };
+        via.drive();
+        via
+    }
+
+    /// The pins, for attaching peripherals to them
+    pub fn pins(&self) -> Rc<RefCell<Pins>> {
+        self.pins.clone()
+    }
+
+    fn t1_continuous(&self) -> bool {
+        self.acr & 0x40 != 0
+    }
+
+    fn t1_drives_pb7(&self) -> bool {
+        self.acr & 0x80 != 0
+    }
+
+    fn t2_counts_pulses(&self) -> bool {
+        self.acr & 0x20 != 0
+    }
+
+    fn sr_mode(&self) -> u8 {
+        (self.acr >> 2) & 0x07
+    }
+
+    fn sr_shifts_out(&self) -> bool {
+        self.sr_mode() & 0x04 != 0
+    }
+
+    /// Half period of the shift clock in cycles, `None` if CB1 clocks it from outside
+    fn sr_half_period(&self) -> Option<u64> {
+        match self.sr_mode() {
+            0b001 | 0b100 | 0b101 => Some(self.t2_latch as u64 + 2),
+            0b010 | 0b110 => Some(1),
+            _ => None,
+        }
+    }
+
+    fn ca2_mode(&self) -> u8 {
+        (self.pcr >> 1) & 0x07
+    }
+
+    fn cb2_mode(&self) -> u8 {
+        (self.pcr >> 5) & 0x07
+    }
+
+    /// Port pins, output bits from the output register and input bits from outside
+    fn port_a(&self) -> u8 {
+        let pins = self.pins.borrow();
+        (self.ora & self.ddra) | (pins.pa_in & !self.ddra)
+    }
+
+    fn port_b(&self) -> u8 {
+        let pins = self.pins.borrow();
+        let mut pb = (self.orb & self.ddrb) | (pins.pb_in & !self.ddrb);
+        if self.t1_drives_pb7() {
+            pb = (pb & 0x7F) | if self.pb7 { 0x80 } else { 0 };
+        }
+        pb
+    }
+
+    /// Updates the outputs in `pins`
+    fn drive(&mut self) {
+        let ca2_out = (self.ca2_mode() >= 4).then_some(self.ca2);
+        let cb1_out = self.sr_half_period().map(|_| self.sr_cb1);
+        let cb2_out = if self.sr_shifts_out() {
+            Some(self.cb2)
+        } else {
+            (self.cb2_mode() >= 4 && self.sr_mode() == 0).then_some(self.cb2)
+        };
+        let mut pb_driven = self.ddrb;
+        let mut pb_out = self.orb & self.ddrb;
+        if self.t1_drives_pb7() {
+            pb_driven |= 0x80;
+            pb_out = (pb_out & 0x7F) | if self.pb7 { 0x80 } else { 0 };
+        }
+
+        let mut pins = self.pins.borrow_mut();
+        pins.pa_out = self.ora & self.ddra;
+        pins.pa_driven = self.ddra;
+        pins.pb_out = pb_out;
+        pins.pb_driven = pb_driven;
+        pins.ca2_out = ca2_out;
+        pins.cb1_out = cb1_out;
+        pins.cb2_out = cb2_out;
+    }
+
+    /// Looks for edges on the inputs
+    fn sample(&mut self) {
+        let pins = *self.pins.borrow();
+
+        if pins.ca1 != self.last_ca1 {
+            self.last_ca1 = pins.ca1;
+            if pins.ca1 == (self.pcr & 0x01 != 0) {
+                self.ifr |= IRQ_CA1;
+                self.ira_latch = self.port_a();
+                if self.ca2_mode() == 0b100 {
+                    self.ca2 = true;
+                }
+            }
+        }
+
+        if pins.ca2_in != self.last_ca2 {
+            self.last_ca2 = pins.ca2_in;
+            let mode = self.ca2_mode();
+            if mode < 4 && pins.ca2_in == (mode & 0b010 != 0) {
+                self.ifr |= IRQ_CA2;
+            }
+        }
+
+        if self.sr_half_period().is_none() && pins.cb1_in != self.last_cb1 {
+            self.last_cb1 = pins.cb1_in;
+            if pins.cb1_in == (self.pcr & 0x10 != 0) {
+                self.ifr |= IRQ_CB1;
+                self.irb_latch = self.port_b();
+                if self.cb2_mode() == 0b100 {
+                    self.cb2 = true;
+                }
+            }
+            // the shift register clocked from outside shifts on the rising edge
+            if pins.cb1_in && self.sr_running {
+                self.shift();
+            }
+        }
+
+        if pins.cb2_in != self.last_cb2 {
+            self.last_cb2 = pins.cb2_in;
+            let mode = self.cb2_mode();
+            if mode < 4 && pins.cb2_in == (mode & 0b010 != 0) {
+                self.ifr |= IRQ_CB2;
+            }
+        }
+
+        let pb6 = pins.pb_in & 0x40 != 0;
+        if pb6 != self.last_pb6 {
+            self.last_pb6 = pb6;
+            if !pb6 && self.t2_counts_pulses() {
+                self.t2 = self.t2.wrapping_sub(1);
+                if self.t2 == 0 && self.t2_armed {
+                    self.ifr |= IRQ_T2;
+                    self.t2_armed = false;
+                }
+            }
+        }
+
+        self.drive();
+    }
+
+    /// Side effects of accessing port A through ORA
+    fn port_a_handshake(&mut self) {
+        self.ifr &= !IRQ_CA1;
+        match self.ca2_mode() {
+            0b000 | 0b010 => self.ifr &= !IRQ_CA2,
+            0b100 => self.ca2 = false,
+            0b101 => {
+                self.ca2 = false;
+                self.ca2_pulse = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Side effects of accessing port B, the handshake only happens on writes
+    fn port_b_handshake(&mut self, write: bool) {
+        self.ifr &= !IRQ_CB1;
+        match self.cb2_mode() {
+            0b000 | 0b010 => self.ifr &= !IRQ_CB2,
+            0b100 if write => self.cb2 = false,
+            0b101 if write => {
+                self.cb2 = false;
+                self.cb2_pulse = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_port_a(&self) -> u8 {
+        if self.acr & 0x01 != 0 { self.ira_latch } else { self.port_a() }
+    }
+
+    fn read_port_b(&self) -> u8 {
+        let inputs = if self.acr & 0x02 != 0 { self.irb_latch } else { self.port_b() };
+        let mut irb = (self.orb & self.ddrb) | (inputs & !self.ddrb);
+        if self.t1_drives_pb7() {
+            irb = (irb & 0x7F) | if self.pb7 { 0x80 } else { 0 };
+        }
+        irb
+    }
+
+    /// Restarts the shift register after it was read or written
+    fn start_shift(&mut self) {
+        self.ifr &= !IRQ_SR;
+        self.sr_bits = 0;
+        self.sr_running = self.sr_mode() != 0;
+        self.sr_cb1 = true;
+        self.sr_clock = self.sr_half_period().unwrap_or(0);
+    }
+
+    fn shift(&mut self) {
+        if self.sr_shifts_out() {
+            self.cb2 = self.sr & 0x80 != 0;
+            self.sr = self.sr.rotate_left(1);
+        } else {
+            self.sr = (self.sr << 1) | self.pins.borrow().cb2_in as u8;
+        }
+        self.sr_bits += 1;
+        if self.sr_bits == 8 {
+            self.sr_bits = 0;
+            // free running mode keeps recirculating the same byte without interrupting
+            if self.sr_mode() != 0b100 {
+                self.sr_running = false;
+                self.ifr |= IRQ_SR;
+            }
+        }
+    }
+
+    fn t1_timeout(&mut self) {
+        if self.t1_armed || self.t1_continuous() {
+            self.ifr |= IRQ_T1;
+            if self.t1_drives_pb7() && !self.t1_continuous() {
+                self.pb7 = true;
+            }
+        }
+        if self.t1_continuous() {
+            self.pb7 = !self.pb7;
+        } else {
+            self.t1_armed = false;
+        }
+    }
+
+    /// T1 counts down to 0, reads $FFFF for a cycle and is reloaded from the latch. In one-shot
+    /// mode it keeps counting after the time out, but doesn't interrupt again.
+    fn run_t1(&mut self, mut cycles: u64) {
+        while cycles > 0 {
+            if self.t1_reload {
+                self.t1_reload = false;
+                self.t1 = self.t1_latch;
+                cycles -= 1;
+                continue;
+            }
+            let to_timeout = self.t1 as u64 + 1;
+            if cycles < to_timeout {
+                self.t1 -= cycles as u16;
+                return;
+            }
+            cycles -= to_timeout;
+            self.t1 = 0xFFFF;
+            self.t1_reload = true;
+            self.t1_timeout();
+        }
+    }
+
+    /// T2 counts down and wraps around, it only interrupts once per write to T2C-H
+    fn run_t2(&mut self, cycles: u64) {
+        if self.t2_counts_pulses() {
+            return;
+        }
+        let to_timeout = self.t2 as u64 + 1;
+        if cycles >= to_timeout {
+            if self.t2_armed {
+                self.ifr |= IRQ_T2;
+                self.t2_armed = false;
+            }
+            self.t2 = 0xFFFF - ((cycles - to_timeout) % 0x10000) as u16;
+        } else {
+            self.t2 -= cycles as u16;
+        }
+    }
+
+    fn run_sr(&mut self, mut cycles: u64) {
+        let Some(half_period) = self.sr_half_period() else { return };
+        while self.sr_running && cycles > 0 {
+            if cycles < self.sr_clock {
+                self.sr_clock -= cycles;
+                return;
+            }
+            cycles -= self.sr_clock;
+            self.sr_clock = half_period;
+            self.sr_cb1 = !self.sr_cb1;
+            if self.sr_cb1 {
+                self.shift();
+            }
+        }
+        if !self.sr_running {
+            self.sr_cb1 = true;
+        }
+    }
+}
+
+/// The 16 registers of the VIA, `offset` is the register
+impl BusDevice for VIA {
+    fn read(&mut self, offset: u32) -> Result<u8, Fault> {
+        self.sample();
+        let value = match offset & 0x0F {
+            ORB => {
+                self.port_b_handshake(false);
+                self.read_port_b()
+            }
+            ORA => {
+                self.port_a_handshake();
+                self.read_port_a()
+            }
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1C_L => {
+                self.ifr &= !IRQ_T1;
+                self.t1 as u8
+            }
+            T1C_H => (self.t1 >> 8) as u8,
+            T1L_L => self.t1_latch as u8,
+            T1L_H => (self.t1_latch >> 8) as u8,
+            T2C_L => {
+                self.ifr &= !IRQ_T2;
+                self.t2 as u8
+            }
+            T2C_H => (self.t2 >> 8) as u8,
+            SR => {
+                let sr = self.sr;
+                self.start_shift();
+                sr
+            }
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.ifr | if self.irq() { 0x80 } else { 0 },
+            IER => self.ier | 0x80,
+            // $F is ORA without the handshake
+            _ => self.read_port_a(),
+        };
+        self.drive();
+        Ok(value)
+    }
+
+    fn write(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        self.sample();
+        match offset & 0x0F {
+            ORB => {
+                self.orb = value;
+                self.port_b_handshake(true);
+            }
+            ORA => {
+                self.ora = value;
+                self.port_a_handshake();
+            }
+            DDRB => self.ddrb = value,
+            DDRA => self.ddra = value,
+            T1C_L | T1L_L => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+            T1C_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.t1 = self.t1_latch;
+                self.t1_reload = false;
+                self.t1_armed = true;
+                self.ifr &= !IRQ_T1;
+                if self.t1_drives_pb7() {
+                    self.pb7 = false;
+                }
+            }
+            T1L_H => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+                self.ifr &= !IRQ_T1;
+            }
+            T2C_L => self.t2_latch = value,
+            T2C_H => {
+                self.t2 = (value as u16) << 8 | self.t2_latch as u16;
+                self.t2_armed = true;
+                self.ifr &= !IRQ_T2;
+            }
+            SR => {
+                self.sr = value;
+                self.start_shift();
+            }
+            ACR => {
+                self.acr = value;
+                if self.sr_mode() == 0 {
+                    self.sr_running = false;
+                }
+            }
+            PCR => {
+                self.pcr = value;
+                match self.ca2_mode() {
+                    0b110 => self.ca2 = false,
+                    0b111 => self.ca2 = true,
+                    _ => {}
+                }
+                match self.cb2_mode() {
+                    0b110 => self.cb2 = false,
+                    0b111 => self.cb2 = true,
+                    _ => {}
+                }
+            }
+            IFR => self.ifr &= !value,
+            IER => {
+                if value & 0x80 != 0 {
+                    self.ier |= value & 0x7F;
+                } else {
+                    self.ier &= !value;
+                }
+            }
+            _ => self.ora = value,
+        }
+        self.drive();
+        Ok(())
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if cycles == 0 {
+            return;
+        }
+        // pulse outputs go back up after a cycle
+        if self.ca2_pulse {
+            self.ca2_pulse = false;
+            self.ca2 = true;
+        }
+        if self.cb2_pulse {
+            self.cb2_pulse = false;
+            self.cb2 = true;
+        }
+        self.run_t1(cycles);
+        self.run_t2(cycles);
+        self.run_sr(cycles);
+        self.sample();
+    }
+
+    /// Clears everything but the timers and the shift register, like the RES pin
+    fn reset(&mut self) {
+        self.ora = 0;
+        self.orb = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.acr = 0;
+        self.pcr = 0;
+        self.ifr = 0;
+        self.ier = 0;
+        self.t1_armed = false;
+        self.t2_armed = false;
+        self.sr_running = false;
+        self.sr_cb1 = true;
+        self.ca2 = true;
+        self.cb2 = true;
+        self.ca2_pulse = false;
+        self.cb2_pulse = false;
+        self.drive();
+    }
+
+    fn next_event(&self) -> u64 {
+        if self.ca2_pulse || self.cb2_pulse {
+            return 1;
+        }
+        let t1 = if self.t1_armed || self.t1_continuous() {
+            if self.t1_reload { self.t1_latch as u64 + 2 } else { self.t1 as u64 + 1 }
+        } else {
+            u64::MAX
+        };
+        let t2 = if self.t2_armed && !self.t2_counts_pulses() {
+            self.t2 as u64 + 1
+        } else {
+            u64::MAX
+        };
+        let sr = match self.sr_half_period() {
+            Some(_) if self.sr_running => self.sr_clock,
+            _ => u64::MAX,
+        };
+        t1.min(t2).min(sr)
+    }
+
+    fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn via() -> (VIA, Rc<RefCell<Pins>>) {
+        let via = VIA::new();
+        let pins = via.pins();
+        (via, pins)
+    }
+
+    fn run(via: &mut VIA, cycles: u64) {
+        for _ in 0..cycles {
+            via.tick(1);
+        }
+    }
+
+    fn t1(via: &mut VIA) -> u16 {
+        u16::from_le_bytes([via.read(T1C_L).unwrap(), via.read(T1C_H).unwrap()])
+    }
+
+    #[test]
+    fn t1_one_shot() {
+        let (mut via, pins) = via();
+        via.write(ACR, 0x80).unwrap();
+        via.write(T1C_L, 0x10).unwrap();
+        via.write(T1C_H, 0x00).unwrap();
+        assert_eq!(pins.borrow().pb_out & 0x80, 0x00);
+        assert_eq!(pins.borrow().pb_driven, 0x80);
+        assert_eq!(via.next_event(), 0x11);
+
+        run(&mut via, 0x10);
+        assert_eq!(via.read(IFR), Ok(0x00));
+        run(&mut via, 1);
+        assert_eq!(via.read(IFR), Ok(IRQ_T1));
+        assert_eq!(pins.borrow().pb_out & 0x80, 0x80);
+        // reads $FFFF for a cycle, then the latch
+        assert_eq!(t1(&mut via), 0xFFFF);
+        assert_eq!(via.read(IFR), Ok(0x00));
+        run(&mut via, 1);
+        assert_eq!(t1(&mut via), 0x0010);
+
+        // it keeps counting, but doesn't time out again until T1C-H is written
+        assert_eq!(via.next_event(), u64::MAX);
+        via.tick(0x30);
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert_eq!(pins.borrow().pb_out & 0x80, 0x80);
+        via.write(T1C_H, 0x00).unwrap();
+        run(&mut via, 0x11);
+        assert_eq!(via.read(IFR), Ok(IRQ_T1));
+    }
+
+    #[test]
+    fn t1_free_run() {
+        let (mut via, pins) = via();
+        via.write(ACR, 0xC0).unwrap();
+        via.write(T1C_L, 0x04).unwrap();
+        via.write(T1C_H, 0x00).unwrap();
+        run(&mut via, 5);
+        assert_eq!(via.read(IFR), Ok(IRQ_T1));
+        assert_eq!(pins.borrow().pb_out & 0x80, 0x80);
+        via.write(IFR, IRQ_T1).unwrap();
+
+        // every latch + 2 cycles from then on, PB7 toggles every time
+        assert_eq!(via.next_event(), 6);
+        for pb7 in [0x00, 0x80, 0x00] {
+            run(&mut via, 5);
+            assert_eq!(via.read(IFR), Ok(0x00));
+            run(&mut via, 1);
+            assert_eq!(via.read(IFR), Ok(IRQ_T1));
+            assert_eq!(pins.borrow().pb_out & 0x80, pb7);
+            via.write(IFR, IRQ_T1).unwrap();
+        }
+
+        // the latch takes over at the next reload
+        via.write(T1L_L, 0x01).unwrap();
+        run(&mut via, 6);
+        assert_eq!(via.read(IFR), Ok(IRQ_T1));
+        assert_eq!(via.next_event(), 3);
+    }
+
+    #[test]
+    fn t2_timer() {
+        let (mut via, _) = via();
+        via.write(T2C_L, 0x05).unwrap();
+        via.write(T2C_H, 0x00).unwrap();
+        assert_eq!(via.next_event(), 6);
+        via.tick(6);
+        assert_eq!(via.read(IFR), Ok(IRQ_T2));
+        assert_eq!(via.read(T2C_H), Ok(0xFF));
+        // reading T2C-L cleared it, and it doesn't time out again
+        assert_eq!(via.read(T2C_L), Ok(0xFF));
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert_eq!(via.next_event(), u64::MAX);
+        via.tick(0x10000);
+        assert_eq!(via.read(IFR), Ok(0x00));
+    }
+
+    #[test]
+    fn t2_pulse_counting() {
+        let (mut via, pins) = via();
+        via.write(ACR, 0x20).unwrap();
+        via.write(T2C_L, 0x03).unwrap();
+        via.write(T2C_H, 0x00).unwrap();
+        // the clock doesn't count
+        assert_eq!(via.next_event(), u64::MAX);
+        via.tick(100);
+        assert_eq!(via.read(T2C_L), Ok(0x03));
+
+        let pulse = |via: &mut VIA| {
+            pins.borrow_mut().pb_in &= !0x40;
+            via.tick(1);
+            pins.borrow_mut().pb_in |= 0x40;
+            via.tick(1);
+        };
+        pulse(&mut via);
+        pulse(&mut via);
+        assert_eq!(via.read(T2C_L), Ok(0x01));
+        assert_eq!(via.read(IFR), Ok(0x00));
+        pulse(&mut via);
+        assert_eq!(via.read(IFR), Ok(IRQ_T2));
+        assert_eq!(via.read(T2C_L), Ok(0x00));
+        // only once per write to T2C-H
+        pulse(&mut via);
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert_eq!(via.read(T2C_H), Ok(0xFF));
+    }
+
+    /// Runs the shift register clocked by φ2 for a byte, returns the bits on CB2 at the rising
+    /// edges of CB1, MSB first
+    fn shift_byte(via: &mut VIA, pins: &Rc<RefCell<Pins>>) -> u8 {
+        let mut byte = 0;
+        for _ in 0..8 {
+            run(via, 1);
+            assert_eq!(pins.borrow().cb1_out, Some(false));
+            run(via, 1);
+            assert_eq!(pins.borrow().cb1_out, Some(true));
+            byte = (byte << 1) | pins.borrow().cb2_out.unwrap() as u8;
+        }
+        byte
+    }
+
+    #[test]
+    fn sr_out_under_phi2() {
+        let (mut via, pins) = via();
+        via.write(ACR, 0b110 << 2).unwrap();
+        via.write(SR, 0xA5).unwrap();
+        assert_eq!(shift_byte(&mut via, &pins), 0xA5);
+        assert_eq!(via.read(IFR), Ok(IRQ_SR));
+        // stopped with CB1 high
+        assert_eq!(via.next_event(), u64::MAX);
+        run(&mut via, 2);
+        assert_eq!(pins.borrow().cb1_out, Some(true));
+        // writing it starts the next byte
+        via.write(SR, 0x3C).unwrap();
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert_eq!(shift_byte(&mut via, &pins), 0x3C);
+    }
+
+    #[test]
+    fn sr_free_running() {
+        let (mut via, pins) = via();
+        via.write(T2C_L, 0x00).unwrap();
+        via.write(ACR, 0b100 << 2).unwrap();
+        via.write(SR, 0x81).unwrap();
+        // the clock is half T2 latch + 2 cycles
+        assert_eq!(via.next_event(), 2);
+        let mut bits = Vec::new();
+        for _ in 0..16 {
+            run(&mut via, 4);
+            bits.push(pins.borrow().cb2_out.unwrap() as u8);
+        }
+        // it recirculates without interrupting
+        assert_eq!(bits, [1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert_eq!(via.read(SR), Ok(0x81));
+    }
+
+    #[test]
+    fn sr_in_under_phi2() {
+        let (mut via, pins) = via();
+        via.write(ACR, 0b010 << 2).unwrap();
+        assert_eq!(pins.borrow().cb2_out, None);
+        via.read(SR).unwrap();
+        for i in (0..8).rev() {
+            pins.borrow_mut().cb2_in = 0x3C & (1 << i) != 0;
+            run(&mut via, 2);
+        }
+        // the data on CB2 sets the CB2 flag as well
+        assert_eq!(via.read(IFR).unwrap() & IRQ_SR, IRQ_SR);
+        assert_eq!(via.read(SR), Ok(0x3C));
+    }
+
+    #[test]
+    fn sr_out_under_cb1() {
+        let (mut via, pins) = via();
+        // CB1 interrupts on the rising edge, so only the shift register sets a flag
+        via.write(PCR, 0x10).unwrap();
+        via.write(ACR, 0b111 << 2).unwrap();
+        assert_eq!(pins.borrow().cb1_out, None);
+        via.write(SR, 0xC3).unwrap();
+        assert_eq!(via.next_event(), u64::MAX);
+        via.tick(100);
+        assert_eq!(pins.borrow().cb2_out, Some(true));
+
+        let mut byte = 0;
+        for _ in 0..8 {
+            pins.borrow_mut().cb1_in = false;
+            via.tick(1);
+            pins.borrow_mut().cb1_in = true;
+            via.tick(1);
+            byte = (byte << 1) | pins.borrow().cb2_out.unwrap() as u8;
+        }
+        assert_eq!(byte, 0xC3);
+        assert_eq!(via.read(IFR), Ok(IRQ_SR | IRQ_CB1));
+    }
+
+    #[test]
+    fn interrupt_enable() {
+        let (mut via, pins) = via();
+        assert_eq!(via.read(IER), Ok(0x80));
+        // bit 7 set sets the other bits written, clear clears them
+        via.write(IER, 0x80 | IRQ_T1 | IRQ_CA1).unwrap();
+        assert_eq!(via.read(IER), Ok(0x80 | IRQ_T1 | IRQ_CA1));
+        via.write(IER, IRQ_T1).unwrap();
+        assert_eq!(via.read(IER), Ok(0x80 | IRQ_CA1));
+        via.write(IER, IRQ_CA1 | IRQ_CB1).unwrap();
+        assert_eq!(via.read(IER), Ok(0x80));
+
+        // a negative edge on CA1 sets its flag, bit 7 of IFR only shows with an enabled flag
+        pins.borrow_mut().ca1 = false;
+        via.tick(1);
+        assert_eq!(via.read(IFR), Ok(IRQ_CA1));
+        assert!(!via.irq());
+        via.write(IER, 0x80 | IRQ_CA1).unwrap();
+        assert_eq!(via.read(IFR), Ok(0x80 | IRQ_CA1));
+        assert!(via.irq());
+
+        // writing 1s to IFR clears those flags, bit 7 doesn't do anything
+        via.write(IFR, 0x80).unwrap();
+        assert_eq!(via.read(IFR), Ok(0x80 | IRQ_CA1));
+        via.write(IFR, IRQ_CA1).unwrap();
+        assert_eq!(via.read(IFR), Ok(0x00));
+        assert!(!via.irq());
+
+        // reading ORA clears CA1 too
+        pins.borrow_mut().ca1 = true;
+        via.tick(1);
+        pins.borrow_mut().ca1 = false;
+        via.tick(1);
+        assert!(via.irq());
+        via.read(ORA).unwrap();
+        assert!(!via.irq());
+    }
+}
@@ -1,3 +1,11 @@
 pub mod cpu;
 pub mod bus;
-pub mod io;
\ No newline at end of file
+pub mod config;
+pub mod device;
+pub mod flash;
+pub mod io;
+pub mod rom;
+pub mod fault;
+
+/// CPU clock in Hz, the 25.175 MHz VGA dot clock divided by 8
+pub const CPU_CLOCK: u64 = 25_175_000 / 8;
@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Size of the flash, 32 KiB in each of banks $00-$0F
+pub const FLASH_SIZE: usize = 16 * FLASH_BANK_SIZE;
+pub const FLASH_BANK_SIZE: usize = 0x8000;
+
+/// A file to load into the flash. What's in it depends on the path:
+///
+/// - a directory holds the bank files ld65 writes with `cate-16_layout.cfg`, `rom-0` to `rom-F`
+/// - `.hex` and `.ihx` files are Intel HEX, with CPU addresses ($8000-$FFFF in banks $00-$0F)
+/// - anything else is a raw binary, starting at $8000 of `bank`. Images shorter than the flash are
+///   padded with $FF, like an erased chip.
+///
+/// For Intel HEX, `bank` is added to the bank of every record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomImage {
+    pub path: PathBuf,
+    pub bank: u8,
+}
+
+impl RomImage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        RomImage { path: path.into(), bank: 0 }
+    }
+
+    /// Whether `load` copies the file as it is to the start of the flash, so offsets into the
+    /// flash are offsets into the file
+    pub fn is_raw_at_start(&self) -> bool {
+        self.bank == 0 && !self.path.is_dir() && !is_hex(&self.path)
+    }
+}
+
+/// Parses "PATH" or "PATH@BANK", with BANK in hex
+impl FromStr for RomImage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('@') {
+            Some((path, bank)) => {
+                let bank = u8::from_str_radix(bank, 16)
+                    .ok()
+                    .filter(|&bank| (bank as usize) < FLASH_SIZE / FLASH_BANK_SIZE)
+                    .ok_or_else(|| format!("bad flash bank \"{}\", it has to be 0-F", bank))?;
+                Ok(RomImage { path: PathBuf::from(path), bank })
+            }
+            None => Ok(RomImage::new(s)),
+        }
+    }
+}
+
+fn is_hex(path: &Path) -> bool {
+    matches!(path.extension().and_then(|ext| ext.to_str()), Some("hex") | Some("ihx"))
+}
+
+/// Builds the flash contents from `images`, where they overlap the one that comes last wins.
+/// Whatever no image covers is $FF.
+pub fn load(images: &[RomImage]) -> Result<Vec<u8>, String> {
+    let mut flash = vec![0xFF; FLASH_SIZE];
+    for image in images {
+        if image.path.is_dir() {
+            load_banks(&image.path, &mut flash)?;
+        } else if is_hex(&image.path) {
+            load_hex(&image.path, image.bank, &mut flash)?;
+        } else {
+            let offset = image.bank as usize * FLASH_BANK_SIZE;
+            load_raw(&image.path, &mut flash[offset..])?;
+        }
+    }
+    Ok(flash)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn load_raw(path: &Path, flash: &mut [u8]) -> Result<(), String> {
+    let data = read(path)?;
+    if data.len() > flash.len() {
+        return Err(format!("{}: {} bytes don't fit in the {} bytes of flash from there",
+            path.display(), data.len(), flash.len()));
+    }
+    flash[..data.len()].copy_from_slice(&data);
+    Ok(())
+}
+
+fn load_banks(dir: &Path, flash: &mut [u8]) -> Result<(), String> {
+    let mut found = false;
+    for (bank, flash) in flash.chunks_mut(FLASH_BANK_SIZE).enumerate() {
+        let path = dir.join(format!("rom-{:X}", bank));
+        if path.exists() {
+            load_raw(&path, flash)?;
+            found = true;
+        }
+    }
+    if !found {
+        return Err(format!("{}: no rom-0 to rom-F bank files in here", dir.display()));
+    }
+    Ok(())
+}
+
+fn load_hex(path: &Path, bank: u8, flash: &mut [u8]) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    // upper bits of the address from the last extended address record
+    let mut base = 0u32;
+    for (number, line) in text.lines().enumerate() {
+        let error = |what: &str| format!("{}:{}: {}", path.display(), number + 1, what);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line.strip_prefix(':')
+            .filter(|hex| hex.len() % 2 == 0)
+            .and_then(|hex| (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>())
+            .ok_or_else(|| error("not an Intel HEX record"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error("record length doesn't match its byte count"));
+        }
+        if record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(error("bad checksum"));
+        }
+        let addr = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => {
+                for (i, &byte) in data.iter().enumerate() {
+                    let addr = base + addr + i as u32;
+                    let (record_bank, addr) = (bank as u32 + (addr >> 16), addr & 0xFFFF);
+                    if record_bank as usize >= FLASH_SIZE / FLASH_BANK_SIZE || addr < 0x8000 {
+                        return Err(error(&format!("{:02X}{:04X} is outside the flash",
+                            record_bank, addr)));
+                    }
+                    flash[record_bank as usize * FLASH_BANK_SIZE + addr as usize - 0x8000] = byte;
+                }
+            }
+            0x01 => return Ok(()),
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // start addresses don't matter, the CPU starts at the reset vector
+            0x03 | 0x05 => {}
+            kind => return Err(error(&format!("unsupported record type {:02X}", kind))),
+        }
+    }
+    Err(format!("{}: no end of file record, is it cut short?", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Intel HEX record with a good checksum
+    fn record(kind: u8, addr: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+        bytes.extend_from_slice(data);
+        let checksum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte));
+        bytes.push(checksum);
+        bytes.iter().fold(":".to_string(), |line, byte| line + &format!("{:02X}", byte))
+    }
+
+    fn eof() -> String {
+        record(0x01, 0, &[])
+    }
+
+    /// Writes `records` to a file named `name` in a fresh directory under the system temp dir
+    fn hex_file(name: &str, records: &[String]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cate16-rom-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.hex", name));
+        fs::write(&path, records.join("\n")).unwrap();
+        path
+    }
+
+    /// Loads `records` as the only image, errors name the file `name`
+    fn load_hex_file(name: &str, bank: u8, records: &[String]) -> Result<Vec<u8>, String> {
+        let path = hex_file(name, records);
+        let flash = load(&[RomImage { path: path.clone(), bank }]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        flash.map_err(|e| e.replace(&path.display().to_string(), name))
+    }
+
+    #[test]
+    fn hex_data() {
+        let flash = load_hex_file("data", 0, &[
+            record(0x00, 0x8000, &[1, 2, 3, 4]),
+            record(0x00, 0xFFFC, &[0x00, 0x80]),
+            eof(),
+        ]).unwrap();
+        assert_eq!(flash[..5], [1, 2, 3, 4, 0xFF]);
+        assert_eq!(flash[0x7FFC..0x8000], [0x00, 0x80, 0xFF, 0xFF]);
+        // everything else is erased
+        assert_eq!(flash.iter().filter(|&&byte| byte != 0xFF).count(), 6);
+
+        // records after the end of file are ignored
+        let flash = load_hex_file("after", 0, &[eof(), record(0x00, 0x8000, &[1])]).unwrap();
+        assert_eq!(flash[0], 0xFF);
+    }
+
+    #[test]
+    fn hex_extended_addresses() {
+        // type 04 sets bits 31-16 of the address, the bank
+        let flash = load_hex_file("linear", 0, &[
+            record(0x04, 0, &[0x00, 0x03]),
+            record(0x00, 0x9000, &[0x11]),
+            eof(),
+        ]).unwrap();
+        assert_eq!(flash[3 * FLASH_BANK_SIZE + 0x1000], 0x11);
+
+        // type 02 is a segment, shifted left by 4, and `bank` moves everything up
+        let flash = load_hex_file("segment", 2, &[
+            record(0x02, 0, &[0x10, 0x00]),
+            record(0x00, 0x8000, &[0x22]),
+            eof(),
+        ]).unwrap();
+        assert_eq!(flash[3 * FLASH_BANK_SIZE], 0x22);
+    }
+
+    #[test]
+    fn hex_errors() {
+        let mut bad = record(0x00, 0x8000, &[1, 2, 3, 4]);
+        bad.replace_range(bad.len() - 2.., "00");
+        assert_eq!(load_hex_file("checksum", 0, &[bad, eof()]),
+            Err("checksum:1: bad checksum".to_string()));
+        assert_eq!(load_hex_file("low", 0, &[record(0x00, 0x7FFF, &[0x11]), eof()]),
+            Err("low:1: 007FFF is outside the flash".to_string()));
+        assert_eq!(load_hex_file("high", 0, &[
+                record(0x04, 0, &[0x00, 0x10]),
+                record(0x00, 0x8000, &[0x11]),
+                eof(),
+            ]),
+            Err("high:2: 108000 is outside the flash".to_string()));
+        // in the flash on its own, but not once `bank` moves it up
+        assert_eq!(load_hex_file("moved", 1, &[
+                record(0x04, 0, &[0x00, 0x0F]),
+                record(0x00, 0x8000, &[0x11]),
+                eof(),
+            ]),
+            Err("moved:2: 108000 is outside the flash".to_string()));
+        assert_eq!(load_hex_file("truncated", 0, &[record(0x00, 0x8000, &[1, 2, 3, 4])]),
+            Err("truncated: no end of file record, is it cut short?".to_string()));
+        assert_eq!(load_hex_file("length", 0, &[":0580000001020304F1".to_string(), eof()]),
+            Err("length:1: record length doesn't match its byte count".to_string()));
+        assert_eq!(load_hex_file("type", 0, &[record(0x06, 0, &[]), eof()]),
+            Err("type:1: unsupported record type 06".to_string()));
+    }
+
+    #[test]
+    fn images_overlap() {
+        let first = hex_file("first", &[record(0x00, 0x8000, &[0x11, 0x22]), eof()]);
+        let second = hex_file("second", &[record(0x00, 0x8001, &[0x33]), eof()]);
+        let flash = load(&[RomImage::new(&first), RomImage::new(&second)]).unwrap();
+        for path in [first, second] {
+            fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        }
+        assert_eq!(flash[..3], [0x11, 0x33, 0xFF]);
+    }
+}
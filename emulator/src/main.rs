@@ -1,17 +1,146 @@
-mod terminal;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-mod machine;
-
-use machine::bus::*;
-use machine::cpu::*;
-use machine::io::*;
+use emulator::machine::CPU_CLOCK;
+use emulator::machine::bus::*;
+use emulator::machine::config::Config;
+use emulator::machine::cpu::*;
+use emulator::machine::cpu::semihost::Semihost;
+use emulator::machine::io::*;
 
 fn main() {
-    let mut cpu = W65C816::new(Bus::new("../rom/boot_rom".to_string(), IO::new()));
+    // exit only once the machine is dropped, so the terminal gets restored
+    let code = run();
+    process::exit(code);
+}
 
-    loop {
-        if cpu.instruction() != RunStatus::Running {
-            break;
+fn run() -> i32 {
+    // --lenient: report faults but keep running, like the real hardware would
+    let lenient = env::args().any(|arg| arg == "--lenient");
+    let trace = env::args().any(|arg| arg == "--trace");
+    // --io-map: list the devices in the IO page before starting
+    let io_map = env::args().any(|arg| arg == "--io-map");
+    // --semihosting[=DIR]: WDM calls the host, with file access inside DIR
+    let semihosting = env::args().find_map(|arg| match arg.as_str() {
+        "--semihosting" => Some(None),
+        _ => arg.strip_prefix("--semihosting=").map(|dir| Some(PathBuf::from(dir))),
+    });
+
+    let mut config = Config {
+        // --write-back-flash: programs and erases of the flash change the ROM file
+        write_back_flash: env::args().any(|arg| arg == "--write-back-flash"),
+        ..Config::default()
+    };
+    // --expansion=open-bus|trap|ram:SIZE: what banks $40-$FF do
+    let expansion = env::args().find_map(|arg| arg.strip_prefix("--expansion=").map(String::from));
+    if let Some(policy) = expansion {
+        match policy.parse() {
+            Ok(expansion) => config.expansion = expansion,
+            Err(e) => {
+                eprintln!("--expansion: {}", e);
+                return 2;
+            }
         }
     }
+
+    // --wait-states=REGION:N,...: cycles every access to a region stalls the CPU for
+    let wait_states = env::args()
+        .find_map(|arg| arg.strip_prefix("--wait-states=").map(String::from));
+    if let Some(wait_states) = wait_states {
+        match wait_states.parse() {
+            Ok(wait_states) => config.wait_states = wait_states,
+            Err(e) => {
+                eprintln!("--wait-states: {}", e);
+                return 2;
+            }
+        }
+    }
+
+    // --rom=PATH[@BANK]: images to load into the flash instead of rom/boot_rom, can be repeated
+    let roms = env::args().filter_map(|arg| arg.strip_prefix("--rom=").map(str::parse))
+        .collect::<Result<Vec<_>, _>>();
+    match roms {
+        Ok(roms) if roms.is_empty() => {}
+        Ok(roms) => config.roms = roms,
+        Err(e) => {
+            eprintln!("--rom: {}", e);
+            return 2;
+        }
+    }
+
+    // --nvram=PATH@START-END: battery backed RAM kept in PATH, can be repeated
+    match env::args().filter_map(|arg| arg.strip_prefix("--nvram=").map(str::parse)).collect() {
+        Ok(nvram) => config.nvram = nvram,
+        Err(e) => {
+            eprintln!("--nvram: {}", e);
+            return 2;
+        }
+    }
+
+    // --rtc=host|YYYY-MM-DD[THH:MM:SS]: the RTC follows host time or starts at a pinned time
+    let rtc = env::args().find_map(|arg| arg.strip_prefix("--rtc=").map(String::from));
+    if let Some(rtc) = rtc {
+        match rtc.parse() {
+            Ok(rtc) => config.rtc = rtc,
+            Err(e) => {
+                eprintln!("--rtc: {}", e);
+                return 2;
+            }
+        }
+    }
+    // --rtc-nvram=PATH: keep the NVRAM of the RTC in PATH
+    config.rtc_nvram = env::args()
+        .find_map(|arg| arg.strip_prefix("--rtc-nvram=").map(PathBuf::from));
+
+    let bus = match IO::cate16(&config).and_then(|io| Bus::cate16(&config, io)) {
+        Ok(bus) => bus,
+        Err(e) => {
+            eprintln!("can't build the machine: {}", e);
+            return 1;
+        }
+    };
+    if io_map {
+        for (registers, name) in bus.io_map() {
+            eprintln!("7F{:02X}-7F{:02X} {}", registers.start(), registers.end(), name);
+        }
+    }
+    let mut cpu = W65C816::new(bus);
+    cpu.set_trace(trace);
+    if let Some(sandbox) = semihosting {
+        cpu.enable_semihosting(Semihost::new(sandbox));
+    }
+    let code = loop {
+        let start = cpu.cycles();
+        match cpu.instruction() {
+            Ok(RunStatus::Running) => {}
+            // the CPU skipped ahead to the next event, take as long as that would have taken
+            Ok(RunStatus::Waiting) => thread::sleep(waiting_time(cpu.cycles() - start)),
+            Ok(RunStatus::Stopped) => break 0,
+            Ok(RunStatus::Exited(code)) => break code as i32,
+            Err(fault) => {
+                eprintln!("fault: {}", fault);
+                if !lenient {
+                    break 1;
+                }
+            }
+        }
+    };
+    if let Err(e) = cpu.bus_mut().flush() {
+        eprintln!("can't save battery backed RAM: {}", e);
+        return 1;
+    }
+    code
+}
+
+/// Host time to sleep for `cycles` spent in WAI. If no cycles were skipped, sleeps for a short
+/// while before looking again.
+fn waiting_time(cycles: u64) -> Duration {
+    if cycles == 0 {
+        Duration::from_micros(100)
+    } else {
+        Duration::from_nanos((cycles as u128 * 1_000_000_000 / CPU_CLOCK as u128) as u64)
+    }
 }
@@ -16,12 +16,12 @@ impl Terminal {
         let stdin = 0;
 
         let termios = Termios::from_fd(stdin).unwrap();
-        let mut new_termios = termios.clone();
+        let mut new_termios = termios;
 
         new_termios.c_cc[VMIN] = 0;
 
         new_termios.c_lflag &= !(ICANON | ECHO);
-        tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
+        tcsetattr(stdin, TCSANOW, &new_termios).unwrap();
         let stdout = io::stdout();
         let reader = io::stdin();
 
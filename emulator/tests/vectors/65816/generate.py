@@ -0,0 +1,864 @@
+#!/usr/bin/env python3
+"""Generates the W65C816 single-step test vectors in this directory.
+
+Usage: generate.py [--count N] [--seed S] [OUT_DIR]
+
+These vectors only supplement the SingleStepTests/65816 ones, which were recorded from a real chip
+and are the reference: this is a model of the W65C816S written from WDC's datasheet by the authors
+of the emulator, so a misreading of the datasheet that both share goes unnoticed.
+
+Every instruction is spelled out as the bus cycles of its addressing mode in table 5-7 ("Cycle by
+Cycle" listing), in that order, with the cycles the notes there add (DL != 0, 16-bit data, index
+page crossings, taken branches, native mode interrupts). Where the datasheet leaves things open the
+model follows what's known of the real chip:
+
+- in emulation mode a direct page with DL = 0 wraps around within its page for the addressing
+  modes the 6502 already had, [d] and [d],y don't wrap
+- in emulation mode the stack wraps around within page 1, except for the instructions that are new
+  to the 65816 (PEA, PEI, PER, PHD, PLD, PLB, JSL, RTL and JSR (a,x)), which only put SH back to
+  1 when they're done
+- decimal mode works on invalid BCD digits the way the chip does, digit by digit with the
+  adjustment of each digit carried into the next
+
+Memory is filled lazily: the first read of an address makes up a random value for it, which goes
+into the initial RAM of the test. Cycles use the notation of SingleStepTests, [address, value,
+pins], see `Cpu.status`. Internal operation cycles (IO in the datasheet) have neither VDA nor VPA
+set, and no address or value since the model doesn't track what's on the bus during them.
+
+The output is deterministic for a given seed and count.
+"""
+
+import argparse
+import json
+import os
+import random
+
+N, V, M, X, D, I, Z, C = 0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01
+
+
+class Bus:
+    """16 MiB of memory that makes up the contents of every address on its first read"""
+
+    def __init__(self, rng):
+        self.rng = rng
+        self.mem = {}
+        self.initial = {}
+        self.cycles = []
+
+    def poke(self, addr, value):
+        """Sets up memory before the instruction runs"""
+        self.mem[addr] = value
+        self.initial[addr] = value
+
+    def read(self, addr, status):
+        if addr not in self.mem:
+            self.poke(addr, self.rng.randrange(256))
+        value = self.mem[addr]
+        self.cycles.append([addr, value, status])
+        return value
+
+    def write(self, addr, value, status):
+        self.mem[addr] = value
+        self.cycles.append([addr, value, status])
+
+    def io(self, status):
+        self.cycles.append([None, None, status])
+
+
+def long(bank, addr):
+    return (bank << 16) | addr
+
+
+def add24(addr, n):
+    return (addr + n) & 0xFFFFFF
+
+
+class Cpu:
+    def __init__(self, bus, state):
+        self.bus = bus
+        self.locked = False
+        for name in ("a", "x", "y", "s", "d", "dbr", "pbr", "pc", "p", "e"):
+            setattr(self, name, state[name])
+
+    def state(self):
+        return {name: getattr(self, name)
+                for name in ("pc", "s", "p", "a", "x", "y", "dbr", "d", "pbr", "e")}
+
+    # flags and widths
+
+    def flag(self, f):
+        return self.p & f != 0
+
+    def set_flag(self, f, on):
+        self.p = self.p | f if on else self.p & ~f & 0xFF
+
+    def m16(self):
+        return not self.flag(M)
+
+    def x16(self):
+        return not self.flag(X)
+
+    def set_p(self, p):
+        self.p = p
+        if self.e:
+            self.p |= M | X
+        if self.flag(X):
+            self.x &= 0xFF
+            self.y &= 0xFF
+
+    def set_nz(self, value, wide):
+        sign = 0x8000 if wide else 0x80
+        mask = 0xFFFF if wide else 0xFF
+        self.set_flag(Z, value & mask == 0)
+        self.set_flag(N, value & sign != 0)
+
+    # bus cycles
+
+    def status(self, kind, valid="", vector=False):
+        """The pins of a bus cycle: VDA (d), VPA (p), VPB low (v), read or write (r/w), E (e),
+        M (m), X (x) and MLB low (l)"""
+        return "".join([
+            "d" if "d" in valid else "-",
+            "p" if "p" in valid else "-",
+            "v" if vector else "-",
+            kind,
+            "e" if self.e else "-",
+            "m" if self.flag(M) else "-",
+            "x" if self.flag(X) else "-",
+            "l" if self.locked else "-",
+        ])
+
+    def fetch(self, valid="p"):
+        value = self.bus.read(long(self.pbr, self.pc), self.status("r", valid))
+        self.pc = (self.pc + 1) & 0xFFFF
+        return value
+
+    def fetch16(self):
+        lo = self.fetch()
+        return lo | self.fetch() << 8
+
+    def read(self, addr, vector=False):
+        return self.bus.read(addr, self.status("r", "d", vector))
+
+    def write(self, addr, value):
+        self.bus.write(addr, value & 0xFF, self.status("w", "d"))
+
+    def io(self):
+        self.bus.io(self.status("r"))
+
+    def push(self, value, new=False):
+        self.write(self.s, value)
+        if self.e and not new:
+            self.s = 0x100 | ((self.s - 1) & 0xFF)
+        else:
+            self.s = (self.s - 1) & 0xFFFF
+
+    def pull(self, new=False):
+        if self.e and not new:
+            self.s = 0x100 | ((self.s + 1) & 0xFF)
+        else:
+            self.s = (self.s + 1) & 0xFFFF
+        return self.read(self.s)
+
+    def fix_stack(self):
+        """SH goes back to 1 after the new instructions in emulation mode"""
+        if self.e:
+            self.s = 0x100 | (self.s & 0xFF)
+
+    # direct page
+
+    def dl_cycle(self):
+        # note 2: an extra cycle when the direct page isn't page aligned
+        if self.d & 0xFF:
+            self.io()
+
+    def direct(self, offset):
+        """Bank 0 address of a direct page offset, index included. Wraps within the page in
+        emulation mode when DL = 0."""
+        if self.e and self.d & 0xFF == 0:
+            return self.d | (offset & 0xFF)
+        return (self.d + offset) & 0xFFFF
+
+    def direct_pointer(self, offset):
+        lo = self.read(self.direct(offset))
+        return lo | self.read(self.direct(offset + 1)) << 8
+
+    def long_pointer(self, addr):
+        """24-bit pointer at `addr` in bank 0, never wraps within a page"""
+        lo = self.read(addr)
+        hi = self.read((addr + 1) & 0xFFFF)
+        return long(self.read((addr + 2) & 0xFFFF), hi << 8 | lo)
+
+    # effective addresses, as (address of the low byte, address of the high byte). The operand
+    # fetches and IO cycles up to the data cycles happen here.
+
+    def ea(self, mode, kind):
+        """`kind` is "r" for reads, "w" for writes and "m" for read-modify-write"""
+        if mode == "abs":                                       # 1a
+            addr = long(self.dbr, self.fetch16())
+            return addr, add24(addr, 1)
+        if mode in ("abs,x", "abs,y"):                          # 6a, 7
+            base = long(self.dbr, self.fetch16())
+            index = self.x if mode == "abs,x" else self.y
+            addr = add24(base, index)
+            # note 3: reads take an extra cycle for 16-bit indexes or when crossing a page
+            if kind != "r" or self.x16() or base >> 8 != addr >> 8:
+                self.io()
+            return addr, add24(addr, 1)
+        if mode == "long":                                      # 4a
+            aa = self.fetch16()
+            addr = long(self.fetch(), aa)
+            return addr, add24(addr, 1)
+        if mode == "long,x":                                    # 5
+            aa = self.fetch16()
+            addr = add24(long(self.fetch(), aa), self.x)
+            return addr, add24(addr, 1)
+        if mode == "dp":                                        # 10a
+            offset = self.fetch()
+            self.dl_cycle()
+            return self.direct(offset), self.direct(offset + 1)
+        if mode in ("dp,x", "dp,y"):                            # 16a, 17
+            offset = self.fetch()
+            self.dl_cycle()
+            self.io()
+            offset += self.x if mode == "dp,x" else self.y
+            return self.direct(offset), self.direct(offset + 1)
+        if mode == "(dp,x)":                                    # 11
+            offset = self.fetch()
+            self.dl_cycle()
+            self.io()
+            addr = long(self.dbr, self.direct_pointer(offset + self.x))
+            return addr, add24(addr, 1)
+        if mode == "(dp)":                                      # 12
+            offset = self.fetch()
+            self.dl_cycle()
+            addr = long(self.dbr, self.direct_pointer(offset))
+            return addr, add24(addr, 1)
+        if mode == "(dp),y":                                    # 14
+            offset = self.fetch()
+            self.dl_cycle()
+            base = long(self.dbr, self.direct_pointer(offset))
+            addr = add24(base, self.y)
+            if kind != "r" or self.x16() or base >> 8 != addr >> 8:
+                self.io()
+            return addr, add24(addr, 1)
+        if mode == "[dp]":                                      # 13
+            offset = self.fetch()
+            self.dl_cycle()
+            addr = self.long_pointer((self.d + offset) & 0xFFFF)
+            return addr, add24(addr, 1)
+        if mode == "[dp],y":                                    # 15
+            offset = self.fetch()
+            self.dl_cycle()
+            addr = add24(self.long_pointer((self.d + offset) & 0xFFFF), self.y)
+            return addr, add24(addr, 1)
+        if mode == "sr":                                        # 23
+            offset = self.fetch()
+            self.io()
+            addr = (self.s + offset) & 0xFFFF
+            return addr, (addr + 1) & 0xFFFF
+        if mode == "(sr),y":                                    # 24
+            offset = self.fetch()
+            self.io()
+            ptr = (self.s + offset) & 0xFFFF
+            lo = self.read(ptr)
+            hi = self.read((ptr + 1) & 0xFFFF)
+            self.io()
+            addr = add24(long(self.dbr, hi << 8 | lo), self.y)
+            return addr, add24(addr, 1)
+        raise ValueError(mode)
+
+    def load(self, mode, wide):
+        if mode == "imm":                                       # 18
+            return self.fetch16() if wide else self.fetch()
+        lo_addr, hi_addr = self.ea(mode, "r")
+        value = self.read(lo_addr)
+        if wide:
+            value |= self.read(hi_addr) << 8
+        return value
+
+    def store(self, mode, value, wide):
+        lo_addr, hi_addr = self.ea(mode, "w")
+        self.write(lo_addr, value)
+        if wide:
+            self.write(hi_addr, value >> 8)
+
+    def modify(self, mode, op):
+        """Read-modify-write: data low, (data high), IO, (data high), data low"""
+        wide = self.m16()
+        if mode == "acc":                                       # 8
+            self.io()
+            value = self.a if wide else self.a & 0xFF
+            result = op(value, wide)
+            self.a = result if wide else (self.a & 0xFF00) | result
+            return
+        lo_addr, hi_addr = self.ea(mode, "m")
+        self.locked = True
+        value = self.read(lo_addr)
+        if wide:
+            value |= self.read(hi_addr) << 8
+        self.io()
+        result = op(value, wide)
+        if wide:
+            self.write(hi_addr, result >> 8)
+        self.write(lo_addr, result)
+        self.locked = False
+
+    # operations
+
+    def acc(self):
+        return self.a if self.m16() else self.a & 0xFF
+
+    def set_acc(self, value):
+        if self.m16():
+            self.a = value & 0xFFFF
+        else:
+            self.a = (self.a & 0xFF00) | (value & 0xFF)
+        self.set_nz(value, self.m16())
+
+    def adc(self, data):
+        wide = self.m16()
+        a = self.acc()
+        carry = int(self.flag(C))
+        if not self.flag(D):
+            result = a + data + carry
+        else:
+            result = 0
+            for digit in range(4 if wide else 2):
+                shift = 4 * digit
+                result = (a & 0xF << shift) + (data & 0xF << shift) + (carry << shift) + \
+                    (result & (1 << shift) - 1)
+                top = digit == (3 if wide else 1)
+                if top:
+                    break
+                if result > 0x9 << shift | (1 << shift) - 1:
+                    result += 0x6 << shift
+                carry = int(result > (0x10 << shift) - 1)
+        sign = 0x8000 if wide else 0x80
+        self.set_flag(V, ~(a ^ data) & (a ^ result) & sign)
+        top = 0x9FFF if wide else 0x9F
+        if self.flag(D) and result > top:
+            result += 0x6000 if wide else 0x60
+        self.set_flag(C, result > (0xFFFF if wide else 0xFF))
+        self.set_acc(result)
+
+    def sbc(self, data):
+        wide = self.m16()
+        data ^= 0xFFFF if wide else 0xFF
+        a = self.acc()
+        carry = int(self.flag(C))
+        if not self.flag(D):
+            result = a + data + carry
+        else:
+            result = 0
+            for digit in range(4 if wide else 2):
+                shift = 4 * digit
+                result = (a & 0xF << shift) + (data & 0xF << shift) + (carry << shift) + \
+                    (result & (1 << shift) - 1)
+                if digit == (3 if wide else 1):
+                    break
+                if result <= (0x10 << shift) - 1:
+                    result -= 0x6 << shift
+                carry = int(result > (0x10 << shift) - 1)
+        sign = 0x8000 if wide else 0x80
+        self.set_flag(V, ~(a ^ data) & (a ^ result) & sign)
+        mask = 0xFFFF if wide else 0xFF
+        if self.flag(D) and result <= mask:
+            result -= 0x6000 if wide else 0x60
+        self.set_flag(C, result > mask)
+        self.set_acc(result)
+
+    def compare(self, reg, data, wide):
+        mask = 0xFFFF if wide else 0xFF
+        reg &= mask
+        self.set_flag(C, reg >= data)
+        self.set_nz((reg - data) & mask, wide)
+
+    def bit(self, data, immediate):
+        wide = self.m16()
+        self.set_flag(Z, self.acc() & data == 0)
+        if not immediate:
+            self.set_flag(N, data & (0x8000 if wide else 0x80))
+            self.set_flag(V, data & (0x4000 if wide else 0x40))
+
+    def shift(self, name):
+        def op(value, wide):
+            sign = 0x8000 if wide else 0x80
+            mask = 0xFFFF if wide else 0xFF
+            carry = int(self.flag(C))
+            if name == "asl":
+                self.set_flag(C, value & sign)
+                result = value << 1 & mask
+            elif name == "lsr":
+                self.set_flag(C, value & 1)
+                result = value >> 1
+            elif name == "rol":
+                self.set_flag(C, value & sign)
+                result = (value << 1 | carry) & mask
+            else:
+                self.set_flag(C, value & 1)
+                result = value >> 1 | (sign if carry else 0)
+            self.set_nz(result, wide)
+            return result
+        return op
+
+    def step(self, delta):
+        def op(value, wide):
+            result = (value + delta) & (0xFFFF if wide else 0xFF)
+            self.set_nz(result, wide)
+            return result
+        return op
+
+    def test_bits(self, set_bits):
+        def op(value, wide):
+            a = self.acc()
+            self.set_flag(Z, a & value == 0)
+            return value | a if set_bits else value & ~a & (0xFFFF if wide else 0xFF)
+        return op
+
+    def set_index(self, name, value):
+        if self.x16():
+            value &= 0xFFFF
+            self.set_nz(value, True)
+        else:
+            value &= 0xFF
+            self.set_nz(value, False)
+        setattr(self, name, value)
+
+    def branch(self, taken):                                    # 20
+        offset = self.fetch()
+        if not taken:
+            return
+        self.io()
+        target = (self.pc + (offset ^ 0x80) - 0x80) & 0xFFFF
+        # note 5: crossing a page in emulation mode costs another cycle
+        if self.e and target >> 8 != self.pc >> 8:
+            self.io()
+        self.pc = target
+
+    def interrupt(self, vector):                                # 22i
+        self.fetch()    # signature byte
+        if not self.e:
+            self.push(self.pbr)
+        self.push(self.pc >> 8)
+        self.push(self.pc)
+        self.push(self.p)
+        self.set_flag(I, True)
+        self.set_flag(D, False)
+        self.pbr = 0
+        lo = self.read(vector, vector=True)
+        self.pc = lo | self.read(vector + 1, vector=True) << 8
+
+    def transfer(self, value, dest):
+        """TAX, TXA and friends, the width is that of the destination"""
+        if dest in ("x", "y"):
+            self.set_index(dest, value)
+        else:
+            self.set_acc(value)
+
+    def execute(self):
+        op = self.fetch("dp")
+        name, mode = OPCODES[op]
+        mw = self.m16()
+        xw = self.x16()
+
+        if name in ("adc", "and", "cmp", "eor", "lda", "ora", "sbc", "bit"):
+            data = self.load(mode, mw)
+            if name == "adc":
+                self.adc(data)
+            elif name == "sbc":
+                self.sbc(data)
+            elif name == "and":
+                self.set_acc(self.acc() & data)
+            elif name == "eor":
+                self.set_acc(self.acc() ^ data)
+            elif name == "ora":
+                self.set_acc(self.acc() | data)
+            elif name == "lda":
+                self.set_acc(data)
+            elif name == "cmp":
+                self.compare(self.a, data, mw)
+            else:
+                self.bit(data, mode == "imm")
+        elif name in ("ldx", "ldy"):
+            self.set_index(name[2], self.load(mode, xw))
+        elif name in ("cpx", "cpy"):
+            self.compare(getattr(self, name[2]), self.load(mode, xw), xw)
+        elif name == "sta":
+            self.store(mode, self.a, mw)
+        elif name == "stz":
+            self.store(mode, 0, mw)
+        elif name in ("stx", "sty"):
+            self.store(mode, getattr(self, name[2]), xw)
+        elif name in ("asl", "lsr", "rol", "ror"):
+            self.modify(mode, self.shift(name))
+        elif name in ("inc", "dec"):
+            self.modify(mode, self.step(1 if name == "inc" else -1))
+        elif name in ("tsb", "trb"):
+            self.modify(mode, self.test_bits(name == "tsb"))
+
+        elif name in ("inx", "iny", "dex", "dey"):              # 19a
+            self.io()
+            reg = name[2]
+            self.set_index(reg, getattr(self, reg) + (1 if name[0] == "i" else -1))
+        elif name in ("clc", "cld", "cli", "clv", "sec", "sed", "sei"):
+            self.io()
+            self.set_flag({"c": C, "d": D, "i": I, "v": V}[name[2]], name[0] == "s")
+        elif name == "nop":
+            self.io()
+        elif name in ("tax", "tay", "txa", "tya", "txy", "tyx"):
+            self.io()
+            self.transfer(getattr(self, name[1]), name[2])
+        elif name == "tsx":
+            self.io()
+            self.set_index("x", self.s)
+        elif name == "txs":
+            self.io()
+            self.s = 0x100 | (self.x & 0xFF) if self.e else self.x
+        elif name == "tcs":
+            self.io()
+            self.s = 0x100 | (self.a & 0xFF) if self.e else self.a
+        elif name == "tsc":
+            self.io()
+            self.a = self.s
+            self.set_nz(self.a, True)
+        elif name == "tcd":
+            self.io()
+            self.d = self.a
+            self.set_nz(self.d, True)
+        elif name == "tdc":
+            self.io()
+            self.a = self.d
+            self.set_nz(self.a, True)
+        elif name == "xce":
+            self.io()
+            carry = self.flag(C)
+            self.set_flag(C, self.e)
+            self.e = int(carry)
+            if self.e:
+                self.s = 0x100 | (self.s & 0xFF)
+                self.set_p(self.p)
+        elif name == "xba":                                     # 19b
+            self.io()
+            self.io()
+            self.a = (self.a >> 8) | (self.a & 0xFF) << 8
+            self.set_nz(self.a & 0xFF, False)
+        elif name in ("wai", "stp"):                            # 19c
+            self.io()
+            self.io()
+        elif name in ("rep", "sep"):
+            mask = self.fetch()
+            self.io()
+            self.set_p(self.p & ~mask & 0xFF if name == "rep" else self.p | mask)
+        elif name == "wdm":
+            self.fetch()
+
+        elif name == "bra":
+            self.branch(True)
+        elif name in BRANCHES:
+            flag, taken_if = BRANCHES[name]
+            self.branch(self.flag(flag) == taken_if)
+        elif name == "brl":                                     # 21
+            offset = self.fetch16()
+            self.io()
+            self.pc = (self.pc + offset) & 0xFFFF
+
+        elif name == "jmp" and mode == "abs":                   # 1b
+            self.pc = self.fetch16()
+        elif name == "jmp" and mode == "long":                  # 4b
+            pc = self.fetch16()
+            self.pbr = self.fetch()
+            self.pc = pc
+        elif name == "jmp" and mode == "(abs)":                 # 3a
+            ptr = self.fetch16()
+            lo = self.read(ptr)
+            self.pc = lo | self.read((ptr + 1) & 0xFFFF) << 8
+        elif name == "jmp" and mode == "[abs]":                 # 3b
+            ptr = self.fetch16()
+            target = self.long_pointer(ptr)
+            self.pbr, self.pc = target >> 16, target & 0xFFFF
+        elif name == "jmp" and mode == "(abs,x)":               # 2a
+            base = self.fetch16()
+            self.io()
+            ptr = (base + self.x) & 0xFFFF
+            lo = self.read(long(self.pbr, ptr))
+            self.pc = lo | self.read(long(self.pbr, (ptr + 1) & 0xFFFF)) << 8
+        elif name == "jsr" and mode == "abs":                   # 1c
+            target = self.fetch16()
+            self.io()
+            ret = (self.pc - 1) & 0xFFFF
+            self.push(ret >> 8)
+            self.push(ret)
+            self.pc = target
+        elif name == "jsr" and mode == "(abs,x)":               # 2b
+            lo = self.fetch()
+            ret = self.pc
+            self.push(ret >> 8, new=True)
+            self.push(ret, new=True)
+            base = lo | self.fetch() << 8
+            self.io()
+            ptr = (base + self.x) & 0xFFFF
+            lo = self.read(long(self.pbr, ptr))
+            self.pc = lo | self.read(long(self.pbr, (ptr + 1) & 0xFFFF)) << 8
+            self.fix_stack()
+        elif name == "jsl":                                     # 4c
+            target = self.fetch16()
+            self.push(self.pbr, new=True)
+            self.io()
+            bank = self.fetch()
+            ret = (self.pc - 1) & 0xFFFF
+            self.push(ret >> 8, new=True)
+            self.push(ret, new=True)
+            self.pbr, self.pc = bank, target
+            self.fix_stack()
+        elif name == "rts":                                     # 22g
+            self.io()
+            self.io()
+            lo = self.pull()
+            self.pc = ((lo | self.pull() << 8) + 1) & 0xFFFF
+            self.io()
+        elif name == "rtl":                                     # 22h
+            self.io()
+            self.io()
+            lo = self.pull(new=True)
+            pc = lo | self.pull(new=True) << 8
+            self.pbr = self.pull(new=True)
+            self.pc = (pc + 1) & 0xFFFF
+            self.fix_stack()
+        elif name == "rti":                                     # 22f
+            self.io()
+            self.io()
+            self.set_p(self.pull())
+            lo = self.pull()
+            self.pc = lo | self.pull() << 8
+            if not self.e:
+                self.pbr = self.pull()
+        elif name == "brk":
+            self.interrupt(0xFFFE if self.e else 0xFFE6)
+        elif name == "cop":
+            self.interrupt(0xFFF4 if self.e else 0xFFE4)
+
+        elif name in ("pha", "phx", "phy", "php", "phb", "phk", "phd"):     # 22b
+            self.io()
+            if name == "phd":
+                self.push(self.d >> 8, new=True)
+                self.push(self.d, new=True)
+                self.fix_stack()
+            elif name in ("php", "phb", "phk"):
+                self.push({"php": self.p, "phb": self.dbr, "phk": self.pbr}[name])
+            else:
+                value = getattr(self, name[2])
+                if (mw if name == "pha" else xw):
+                    self.push(value >> 8)
+                self.push(value)
+        elif name in ("pla", "plx", "ply", "plp", "plb", "pld"):            # 22a
+            self.io()
+            self.io()
+            if name == "pld":
+                lo = self.pull(new=True)
+                self.d = lo | self.pull(new=True) << 8
+                self.set_nz(self.d, True)
+                self.fix_stack()
+            elif name == "plb":
+                self.dbr = self.pull(new=True)
+                self.set_nz(self.dbr, False)
+                self.fix_stack()
+            elif name == "plp":
+                self.set_p(self.pull())
+            elif name == "pla":
+                value = self.pull()
+                if mw:
+                    value |= self.pull() << 8
+                self.set_acc(value)
+            else:
+                value = self.pull()
+                if xw:
+                    value |= self.pull() << 8
+                self.set_index(name[2], value)
+        elif name == "pea":                                     # 22c
+            value = self.fetch16()
+            self.push(value >> 8, new=True)
+            self.push(value, new=True)
+            self.fix_stack()
+        elif name == "pei":                                     # 22d
+            offset = self.fetch()
+            self.dl_cycle()
+            value = self.direct_pointer(offset)
+            self.push(value >> 8, new=True)
+            self.push(value, new=True)
+            self.fix_stack()
+        elif name == "per":                                     # 22e
+            offset = self.fetch16()
+            self.io()
+            value = (self.pc + offset) & 0xFFFF
+            self.push(value >> 8, new=True)
+            self.push(value, new=True)
+            self.fix_stack()
+
+        elif name in ("mvn", "mvp"):                            # 9a
+            dest = self.fetch()
+            src = self.fetch()
+            self.dbr = dest
+            value = self.read(long(src, self.x))
+            self.write(long(dest, self.y), value)
+            self.io()
+            self.io()
+            step = 1 if name == "mvn" else -1
+            mask = 0xFFFF if xw else 0xFF
+            self.x = (self.x + step) & mask
+            self.y = (self.y + step) & mask
+            self.a = (self.a - 1) & 0xFFFF
+            if self.a != 0xFFFF:
+                self.pc = (self.pc - 3) & 0xFFFF
+        else:
+            raise ValueError(f"{op:02x} {name} {mode}")
+
+
+BRANCHES = {
+    "bpl": (N, False), "bmi": (N, True), "bvc": (V, False), "bvs": (V, True),
+    "bcc": (C, False), "bcs": (C, True), "bne": (Z, False), "beq": (Z, True),
+}
+
+# The opcode matrix of the datasheet, table 5-4
+OPCODES = [
+    ("brk", "imm"), ("ora", "(dp,x)"), ("cop", "imm"), ("ora", "sr"),
+    ("tsb", "dp"), ("ora", "dp"), ("asl", "dp"), ("ora", "[dp]"),
+    ("php", "imp"), ("ora", "imm"), ("asl", "acc"), ("phd", "imp"),
+    ("tsb", "abs"), ("ora", "abs"), ("asl", "abs"), ("ora", "long"),
+    ("bpl", "rel"), ("ora", "(dp),y"), ("ora", "(dp)"), ("ora", "(sr),y"),
+    ("trb", "dp"), ("ora", "dp,x"), ("asl", "dp,x"), ("ora", "[dp],y"),
+    ("clc", "imp"), ("ora", "abs,y"), ("inc", "acc"), ("tcs", "imp"),
+    ("trb", "abs"), ("ora", "abs,x"), ("asl", "abs,x"), ("ora", "long,x"),
+    ("jsr", "abs"), ("and", "(dp,x)"), ("jsl", "long"), ("and", "sr"),
+    ("bit", "dp"), ("and", "dp"), ("rol", "dp"), ("and", "[dp]"),
+    ("plp", "imp"), ("and", "imm"), ("rol", "acc"), ("pld", "imp"),
+    ("bit", "abs"), ("and", "abs"), ("rol", "abs"), ("and", "long"),
+    ("bmi", "rel"), ("and", "(dp),y"), ("and", "(dp)"), ("and", "(sr),y"),
+    ("bit", "dp,x"), ("and", "dp,x"), ("rol", "dp,x"), ("and", "[dp],y"),
+    ("sec", "imp"), ("and", "abs,y"), ("dec", "acc"), ("tsc", "imp"),
+    ("bit", "abs,x"), ("and", "abs,x"), ("rol", "abs,x"), ("and", "long,x"),
+    ("rti", "imp"), ("eor", "(dp,x)"), ("wdm", "imm"), ("eor", "sr"),
+    ("mvp", "move"), ("eor", "dp"), ("lsr", "dp"), ("eor", "[dp]"),
+    ("pha", "imp"), ("eor", "imm"), ("lsr", "acc"), ("phk", "imp"),
+    ("jmp", "abs"), ("eor", "abs"), ("lsr", "abs"), ("eor", "long"),
+    ("bvc", "rel"), ("eor", "(dp),y"), ("eor", "(dp)"), ("eor", "(sr),y"),
+    ("mvn", "move"), ("eor", "dp,x"), ("lsr", "dp,x"), ("eor", "[dp],y"),
+    ("cli", "imp"), ("eor", "abs,y"), ("phy", "imp"), ("tcd", "imp"),
+    ("jmp", "long"), ("eor", "abs,x"), ("lsr", "abs,x"), ("eor", "long,x"),
+    ("rts", "imp"), ("adc", "(dp,x)"), ("per", "rel16"), ("adc", "sr"),
+    ("stz", "dp"), ("adc", "dp"), ("ror", "dp"), ("adc", "[dp]"),
+    ("pla", "imp"), ("adc", "imm"), ("ror", "acc"), ("rtl", "imp"),
+    ("jmp", "(abs)"), ("adc", "abs"), ("ror", "abs"), ("adc", "long"),
+    ("bvs", "rel"), ("adc", "(dp),y"), ("adc", "(dp)"), ("adc", "(sr),y"),
+    ("stz", "dp,x"), ("adc", "dp,x"), ("ror", "dp,x"), ("adc", "[dp],y"),
+    ("sei", "imp"), ("adc", "abs,y"), ("ply", "imp"), ("tdc", "imp"),
+    ("jmp", "(abs,x)"), ("adc", "abs,x"), ("ror", "abs,x"), ("adc", "long,x"),
+    ("bra", "rel"), ("sta", "(dp,x)"), ("brl", "rel16"), ("sta", "sr"),
+    ("sty", "dp"), ("sta", "dp"), ("stx", "dp"), ("sta", "[dp]"),
+    ("dey", "imp"), ("bit", "imm"), ("txa", "imp"), ("phb", "imp"),
+    ("sty", "abs"), ("sta", "abs"), ("stx", "abs"), ("sta", "long"),
+    ("bcc", "rel"), ("sta", "(dp),y"), ("sta", "(dp)"), ("sta", "(sr),y"),
+    ("sty", "dp,x"), ("sta", "dp,x"), ("stx", "dp,y"), ("sta", "[dp],y"),
+    ("tya", "imp"), ("sta", "abs,y"), ("txs", "imp"), ("txy", "imp"),
+    ("stz", "abs"), ("sta", "abs,x"), ("stz", "abs,x"), ("sta", "long,x"),
+    ("ldy", "imm"), ("lda", "(dp,x)"), ("ldx", "imm"), ("lda", "sr"),
+    ("ldy", "dp"), ("lda", "dp"), ("ldx", "dp"), ("lda", "[dp]"),
+    ("tay", "imp"), ("lda", "imm"), ("tax", "imp"), ("plb", "imp"),
+    ("ldy", "abs"), ("lda", "abs"), ("ldx", "abs"), ("lda", "long"),
+    ("bcs", "rel"), ("lda", "(dp),y"), ("lda", "(dp)"), ("lda", "(sr),y"),
+    ("ldy", "dp,x"), ("lda", "dp,x"), ("ldx", "dp,y"), ("lda", "[dp],y"),
+    ("clv", "imp"), ("lda", "abs,y"), ("tsx", "imp"), ("tyx", "imp"),
+    ("ldy", "abs,x"), ("lda", "abs,x"), ("ldx", "abs,y"), ("lda", "long,x"),
+    ("cpy", "imm"), ("cmp", "(dp,x)"), ("rep", "imm"), ("cmp", "sr"),
+    ("cpy", "dp"), ("cmp", "dp"), ("dec", "dp"), ("cmp", "[dp]"),
+    ("iny", "imp"), ("cmp", "imm"), ("dex", "imp"), ("wai", "imp"),
+    ("cpy", "abs"), ("cmp", "abs"), ("dec", "abs"), ("cmp", "long"),
+    ("bne", "rel"), ("cmp", "(dp),y"), ("cmp", "(dp)"), ("cmp", "(sr),y"),
+    ("pei", "(dp)"), ("cmp", "dp,x"), ("dec", "dp,x"), ("cmp", "[dp],y"),
+    ("cld", "imp"), ("cmp", "abs,y"), ("phx", "imp"), ("stp", "imp"),
+    ("jmp", "[abs]"), ("cmp", "abs,x"), ("dec", "abs,x"), ("cmp", "long,x"),
+    ("cpx", "imm"), ("sbc", "(dp,x)"), ("sep", "imm"), ("sbc", "sr"),
+    ("cpx", "dp"), ("sbc", "dp"), ("inc", "dp"), ("sbc", "[dp]"),
+    ("inx", "imp"), ("sbc", "imm"), ("nop", "imp"), ("xba", "imp"),
+    ("cpx", "abs"), ("sbc", "abs"), ("inc", "abs"), ("sbc", "long"),
+    ("beq", "rel"), ("sbc", "(dp),y"), ("sbc", "(dp)"), ("sbc", "(sr),y"),
+    ("pea", "abs"), ("sbc", "dp,x"), ("inc", "dp,x"), ("sbc", "[dp],y"),
+    ("sed", "imp"), ("sbc", "abs,y"), ("plx", "imp"), ("xce", "imp"),
+    ("jsr", "(abs,x)"), ("sbc", "abs,x"), ("inc", "abs,x"), ("sbc", "long,x"),
+]
+
+
+def random_state(rng, emulation):
+    """Registers to start a test from, biased towards the cases that change how the
+    instruction runs"""
+    p = rng.randrange(256)
+    a = rng.randrange(0x10000)
+    x = rng.randrange(0x10000)
+    y = rng.randrange(0x10000)
+    s = rng.randrange(0x10000)
+    # a page aligned direct page half of the time, it wraps in emulation mode
+    d = rng.randrange(0x10000)
+    if rng.random() < 0.5:
+        d &= 0xFF00
+    if emulation:
+        p |= M | X
+        s = 0x100 | (s & 0xFF)
+    if p & X:
+        x &= 0xFF
+        y &= 0xFF
+    return {
+        "pc": rng.randrange(0x10000),
+        "s": s,
+        "p": p,
+        "a": a,
+        "x": x,
+        "y": y,
+        "dbr": rng.randrange(256),
+        "d": d,
+        "pbr": rng.randrange(256),
+        "e": int(emulation),
+    }
+
+
+def generate(opcode, emulation, count, seed):
+    mode = "e" if emulation else "n"
+    rng = random.Random(f"{seed}-{opcode:02x}-{mode}")
+    tests = []
+    for number in range(count):
+        initial = random_state(rng, emulation)
+        bus = Bus(rng)
+        bus.poke(long(initial["pbr"], initial["pc"]), opcode)
+        cpu = Cpu(bus, initial)
+        cpu.execute()
+        final = cpu.state()
+        initial["ram"] = sorted([addr, value] for addr, value in bus.initial.items())
+        final["ram"] = sorted([addr, bus.mem[addr]] for addr in bus.mem)
+        tests.append({
+            "name": f"{opcode:02x} {mode} {number + 1}",
+            "initial": initial,
+            "final": final,
+            "cycles": bus.cycles,
+        })
+    return tests
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split("\n")[0])
+    parser.add_argument("--count", type=int, default=10, help="tests per opcode and mode")
+    parser.add_argument("--seed", default="cate-16")
+    parser.add_argument("out", nargs="?", default=os.path.dirname(os.path.abspath(__file__)))
+    args = parser.parse_args()
+
+    os.makedirs(args.out, exist_ok=True)
+    for opcode in range(256):
+        for emulation in (False, True):
+            tests = generate(opcode, emulation, args.count, args.seed)
+            path = os.path.join(args.out, f"{opcode:02x}.{'e' if emulation else 'n'}.json")
+            with open(path, "w") as f:
+                f.write("[\n")
+                f.write(",\n".join(json.dumps(test, separators=(",", ":")) for test in tests))
+                f.write("\n]\n")
+
+
+if __name__ == "__main__":
+    main()